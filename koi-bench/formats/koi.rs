@@ -1,4 +1,4 @@
-use koi::{file::FileHeader, types::Compression};
+use koi::encoder::{block::CompressionLevel, Encoder};
 use std::io::Result;
 
 use super::ImageFormat;
@@ -8,22 +8,10 @@ pub struct Koi<const C: usize> {}
 
 impl<const C: usize> ImageFormat for Koi<C> {
     fn encode(&mut self, data: &[u8], dimensions: (u32, u32)) -> Result<Vec<u8>> {
-        let header = FileHeader::new(
-            1,
-            None,
-            dimensions.0 as u64,
-            dimensions.1 as u64,
-            (C as u8).try_into().expect("Koi: Invalid channel count"),
-            Compression::Lz4,
-            None,
-            None,
-        );
-
-        let data = koi::encoder::block::encode_to_vec::<C>(
-            data,
-            header,
-            koi::encoder::block::CompressionLevel::Lz4Hc(4),
-        )?;
+        let channels = (C as u8).try_into().expect("Koi: Invalid channel count");
+        let data = Encoder::new(dimensions.0 as u64, dimensions.1 as u64, channels)
+            .level(CompressionLevel::Lz4Hc(4))
+            .encode_to_vec::<C>(data)?;
         Ok(data)
     }
 
@@ -38,22 +26,10 @@ pub struct KoiFast<const C: usize> {}
 
 impl<const C: usize> ImageFormat for KoiFast<C> {
     fn encode(&mut self, data: &[u8], dimensions: (u32, u32)) -> Result<Vec<u8>> {
-        let header = FileHeader::new(
-            1,
-            None,
-            dimensions.0 as u64,
-            dimensions.1 as u64,
-            (C as u8).try_into().expect("Koi: Invalid channel count"),
-            Compression::Lz4,
-            None,
-            None,
-        );
-
-        let data = koi::encoder::block::encode_to_vec::<C>(
-            data,
-            header,
-            koi::encoder::block::CompressionLevel::Lz4Flex,
-        )?;
+        let channels = (C as u8).try_into().expect("Koi: Invalid channel count");
+        let data = Encoder::new(dimensions.0 as u64, dimensions.1 as u64, channels)
+            .level(CompressionLevel::Lz4Flex)
+            .encode_to_vec::<C>(data)?;
 
         Ok(data)
     }
@@ -11,6 +11,7 @@ pub struct FormatResult {
 }
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct Test {
     pub name: String,
     pub input_size: usize,
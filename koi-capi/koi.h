@@ -0,0 +1,183 @@
+/* C ABI for koi, generated from koi-capi/lib.rs with cbindgen, don't edit */
+
+#ifndef KOI_H
+#define KOI_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Stores the pixels uncompressed
+ */
+#define KOI_COMPRESSION_NONE 0
+
+/**
+ * LZ4 compression with lz4_flex (fast, the level is ignored)
+ */
+#define KOI_COMPRESSION_LZ4_FLEX 1
+
+/**
+ * LZ4 compression with the C library, the level is its acceleration
+ */
+#define KOI_COMPRESSION_LZ4 2
+
+/**
+ * LZ4 HC compression with the C library, the level is its compression level
+ */
+#define KOI_COMPRESSION_LZ4_HC 3
+
+/**
+ * Result of every function (`KOI_STATUS_OK` on success)
+ */
+typedef enum KoiStatus {
+  KOI_STATUS_OK = 0,
+  /**
+   * A pointer is null, a size doesn't match or a parameter is out of range
+   */
+  KOI_STATUS_INVALID_ARGUMENT = 1,
+  /**
+   * The data isn't a koi file or its header is invalid
+   */
+  KOI_STATUS_INVALID_HEADER = 2,
+  /**
+   * The file uses a version or feature this library can't decode to the requested channels
+   */
+  KOI_STATUS_UNSUPPORTED = 3,
+  /**
+   * The pixel data is truncated or corrupt
+   */
+  KOI_STATUS_CORRUPT_DATA = 4,
+  /**
+   * The output buffer is smaller than `KoiInfo::data_size`
+   */
+  KOI_STATUS_BUFFER_TOO_SMALL = 5,
+  /**
+   * The image exceeds the default decoding limits
+   */
+  KOI_STATUS_LIMIT_EXCEEDED = 6,
+  /**
+   * Unexpected internal error
+   */
+  KOI_STATUS_INTERNAL = 7,
+} KoiStatus;
+
+/**
+ * What the header tells about an image (see `koi_probe`)
+ */
+typedef struct KoiInfo {
+  uint64_t width;
+  uint64_t height;
+  /**
+   * Number of channels the image was stored with
+   */
+  uint32_t channels;
+  /**
+   * Bits per sample, 8 or 16 (raw sensor data)
+   */
+  uint32_t bit_depth;
+  /**
+   * 0 for uncompressed, 1 for LZ4
+   */
+  uint32_t compression;
+  /**
+   * 0 for the stream layout, 1 for the block layout
+   */
+  uint32_t layout;
+  /**
+   * EXIF orientation (1-8), the pixels are stored unrotated
+   */
+  uint32_t orientation;
+  bool has_exif;
+  /**
+   * The pixel data starts at this offset
+   */
+  size_t header_size;
+  /**
+   * Size of the decoded pixels with the stored channels, 0 if it doesn't fit into size_t
+   */
+  size_t data_size;
+} KoiInfo;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * A static description of a status code, "unknown status" for values that aren't a `KoiStatus`
+ */
+const char *koi_status_string(uint32_t status);
+
+/**
+ * Copies the message of the last error on this thread into `buf` (null terminated, truncated to
+ * `len` bytes), returns the length of the whole message without the terminator
+ *
+ * # Safety
+ *
+ * `buf` has to be valid for writes of `len` bytes or null
+ */
+size_t koi_last_error_message(char *buf, size_t len);
+
+/**
+ * Reads the header of an image, `data` only has to contain the header
+ *
+ * # Safety
+ *
+ * `data` has to be valid for reads of `len` bytes, `info` has to be valid for writes
+ */
+KoiStatus koi_probe(const uint8_t *data, size_t len, struct KoiInfo *info);
+
+/**
+ * Decodes an image in either layout into `out` with `channels` channels (1-4 for gray, gray +
+ * alpha, RGB or RGBA, or 0 for the channels it was stored with), images stored with fewer
+ * channels are expanded and images with another color model (e.g. CMYK) or more than 4
+ * channels fail with `KOI_STATUS_UNSUPPORTED`. The number of bytes written is stored in `written`
+ * unless it's null.
+ *
+ * # Safety
+ *
+ * `data` has to be valid for reads of `len` bytes, `out` for writes of `out_len` bytes and
+ * `written` for writes or null
+ */
+KoiStatus koi_decode_into(const uint8_t *data,
+                          size_t len,
+                          uint8_t *out,
+                          size_t out_len,
+                          uint32_t channels,
+                          size_t *written);
+
+/**
+ * Encodes `channels` (1-4) interleaved channels with 8 bits per sample in the block layout. On
+ * success `out` points to the encoded file of `out_len` bytes, which has to be released with
+ * `koi_free`.
+ *
+ * # Safety
+ *
+ * `pixels` has to be valid for reads of `len` bytes, `out` and `out_len` for writes
+ */
+KoiStatus koi_encode(const uint8_t *pixels,
+                     size_t len,
+                     uint64_t width,
+                     uint64_t height,
+                     uint32_t channels,
+                     uint32_t compression,
+                     int32_t level,
+                     uint8_t **out,
+                     size_t *out_len);
+
+/**
+ * Releases a buffer returned by `koi_encode`, null is ignored
+ *
+ * # Safety
+ *
+ * `data` and `len` have to be the values returned by `koi_encode`, and the buffer can only be
+ * released once
+ */
+void koi_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* KOI_H */
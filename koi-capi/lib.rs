@@ -0,0 +1,384 @@
+//! C ABI for koi (see `koi.h`, generated from this file with `cbindgen --config cbindgen.toml
+//! --output koi.h`, tests/header.rs checks that it's up to date)
+//!
+//! Every function returns a `KoiStatus`. On failure, `koi_last_error_message` returns a detailed
+//! message for the calling thread. Buffers returned by `koi_encode` are owned by the caller and
+//! have to be released with `koi_free`.
+
+use std::{
+    cell::RefCell,
+    ffi::c_char,
+    panic::{self, UnwindSafe},
+    ptr, slice,
+};
+
+use koi::{
+    decoder::{block, DecodeOptions},
+    encoder::{block::CompressionLevel, Encoder},
+    file::Layout,
+    types::Channels,
+    KoiDecodeError, KoiEncodeError,
+};
+
+/// Result of every function (`KOI_STATUS_OK` on success)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KoiStatus {
+    Ok = 0,
+    /// A pointer is null, a size doesn't match or a parameter is out of range
+    InvalidArgument = 1,
+    /// The data isn't a koi file or its header is invalid
+    InvalidHeader = 2,
+    /// The file uses a version or feature this library can't decode to the requested channels
+    Unsupported = 3,
+    /// The pixel data is truncated or corrupt
+    CorruptData = 4,
+    /// The output buffer is smaller than `KoiInfo::data_size`
+    BufferTooSmall = 5,
+    /// The image exceeds the default decoding limits
+    LimitExceeded = 6,
+    /// Unexpected internal error
+    Internal = 7,
+}
+
+/// Stores the pixels uncompressed
+pub const KOI_COMPRESSION_NONE: u32 = 0;
+/// LZ4 compression with lz4_flex (fast, the level is ignored)
+pub const KOI_COMPRESSION_LZ4_FLEX: u32 = 1;
+/// LZ4 compression with the C library, the level is its acceleration
+pub const KOI_COMPRESSION_LZ4: u32 = 2;
+/// LZ4 HC compression with the C library, the level is its compression level
+pub const KOI_COMPRESSION_LZ4_HC: u32 = 3;
+
+/// What the header tells about an image (see `koi_probe`)
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KoiInfo {
+    pub width: u64,
+    pub height: u64,
+    /// Number of channels the image was stored with
+    pub channels: u32,
+    /// Bits per sample, 8 or 16 (raw sensor data)
+    pub bit_depth: u32,
+    /// 0 for uncompressed, 1 for LZ4
+    pub compression: u32,
+    /// 0 for the stream layout, 1 for the block layout
+    pub layout: u32,
+    /// EXIF orientation (1-8), the pixels are stored unrotated
+    pub orientation: u32,
+    pub has_exif: bool,
+    /// The pixel data starts at this offset
+    pub header_size: usize,
+    /// Size of the decoded pixels with the stored channels, 0 if it doesn't fit into size_t
+    pub data_size: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+fn fail(status: KoiStatus, message: impl ToString) -> KoiStatus {
+    LAST_ERROR.with(|last| *last.borrow_mut() = message.to_string());
+    status
+}
+
+fn decode_status(err: KoiDecodeError) -> KoiStatus {
+    let status = match err {
+        KoiDecodeError::InvalidFileHeader(_) => KoiStatus::InvalidHeader,
+        KoiDecodeError::UnsupportedVersion(_)
+        | KoiDecodeError::UnsupportedLayout { .. }
+        | KoiDecodeError::UnsupportedExpansion { .. } => KoiStatus::Unsupported,
+        KoiDecodeError::ChannelMismatch { .. } | KoiDecodeError::InvalidStride { .. } => {
+            KoiStatus::InvalidArgument
+        }
+        KoiDecodeError::OutputTooSmall { .. } => KoiStatus::BufferTooSmall,
+        KoiDecodeError::LimitExceeded { .. } => KoiStatus::LimitExceeded,
+        KoiDecodeError::Io(_) => KoiStatus::Internal,
+        _ => KoiStatus::CorruptData,
+    };
+    fail(status, err)
+}
+
+fn encode_status(err: KoiEncodeError) -> KoiStatus {
+    let status = match err {
+        KoiEncodeError::UnsupportedVersion(_) => KoiStatus::Unsupported,
+        KoiEncodeError::Io(_) | KoiEncodeError::Bson(_) => KoiStatus::Internal,
+        _ => KoiStatus::InvalidArgument,
+    };
+    fail(status, err)
+}
+
+// panics must not unwind into C
+fn guard(f: impl FnOnce() -> KoiStatus + UnwindSafe) -> KoiStatus {
+    panic::catch_unwind(f).unwrap_or_else(|_| fail(KoiStatus::Internal, "panic"))
+}
+
+// a slice from a pointer and length passed from C, empty slices may be null
+unsafe fn input<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match (data.is_null(), len) {
+        (true, 0) => Some(&[]),
+        (true, _) => None,
+        (false, _) => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// A static description of a status code, "unknown status" for values that aren't a `KoiStatus`
+#[no_mangle]
+pub extern "C" fn koi_status_string(status: u32) -> *const c_char {
+    // the values of KoiStatus, C can pass any integer so it isn't converted to the enum
+    let message: &'static [u8] = match status {
+        0 => b"ok\0",
+        1 => b"invalid argument\0",
+        2 => b"invalid header\0",
+        3 => b"unsupported image\0",
+        4 => b"corrupt data\0",
+        5 => b"buffer too small\0",
+        6 => b"limit exceeded\0",
+        7 => b"internal error\0",
+        _ => b"unknown status\0",
+    };
+    message.as_ptr().cast()
+}
+
+/// Copies the message of the last error on this thread into `buf` (null terminated, truncated to
+/// `len` bytes), returns the length of the whole message without the terminator
+///
+/// # Safety
+///
+/// `buf` has to be valid for writes of `len` bytes or null
+#[no_mangle]
+pub unsafe extern "C" fn koi_last_error_message(buf: *mut c_char, len: usize) -> usize {
+    LAST_ERROR.with(|last| {
+        let message = last.borrow();
+        if !buf.is_null() && len > 0 {
+            let n = message.len().min(len - 1);
+            ptr::copy_nonoverlapping(message.as_ptr(), buf.cast(), n);
+            *buf.add(n) = 0;
+        }
+        message.len()
+    })
+}
+
+/// Reads the header of an image, `data` only has to contain the header
+///
+/// # Safety
+///
+/// `data` has to be valid for reads of `len` bytes, `info` has to be valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn koi_probe(data: *const u8, len: usize, info: *mut KoiInfo) -> KoiStatus {
+    guard(|| {
+        let (Some(data), false) = (input(data, len), info.is_null()) else {
+            return fail(KoiStatus::InvalidArgument, "null pointer");
+        };
+
+        let probed = match koi::probe(data) {
+            Ok(probed) => probed,
+            Err(err) => return decode_status(err),
+        };
+        *info = KoiInfo {
+            width: probed.width,
+            height: probed.height,
+            channels: probed.channels.count() as u32,
+            bit_depth: probed.bit_depth as u32,
+            compression: probed.compression as u32,
+            layout: match probed.layout {
+                Layout::Stream => 0,
+                Layout::Block => 1,
+            },
+            orientation: probed.header.orientation as u32,
+            has_exif: probed.has_exif,
+            header_size: probed.header_size,
+            data_size: probed.data_size().unwrap_or(0),
+        };
+        KoiStatus::Ok
+    })
+}
+
+/// Decodes an image in either layout into `out` with `channels` channels (1-4 for gray, gray +
+/// alpha, RGB or RGBA, or 0 for the channels it was stored with), images stored with fewer
+/// channels are expanded and images with another color model (e.g. CMYK) or more than 4
+/// channels fail with `KOI_STATUS_UNSUPPORTED`. The number of bytes written is stored in `written`
+/// unless it's null.
+///
+/// # Safety
+///
+/// `data` has to be valid for reads of `len` bytes, `out` for writes of `out_len` bytes and
+/// `written` for writes or null
+#[no_mangle]
+pub unsafe extern "C" fn koi_decode_into(
+    data: *const u8,
+    len: usize,
+    out: *mut u8,
+    out_len: usize,
+    channels: u32,
+    written: *mut usize,
+) -> KoiStatus {
+    guard(|| {
+        let Some(data) = input(data, len) else {
+            return fail(KoiStatus::InvalidArgument, "null pointer");
+        };
+        if out.is_null() && out_len > 0 {
+            return fail(KoiStatus::InvalidArgument, "null pointer");
+        }
+        let out = match out_len {
+            0 => &mut [],
+            _ => slice::from_raw_parts_mut(out, out_len),
+        };
+
+        let info = match koi::probe(data) {
+            Ok(info) => info,
+            Err(err) => return decode_status(err),
+        };
+        let (channels, expand) = match channels {
+            0 if info.channels.count() > 4 => {
+                return fail(
+                    KoiStatus::Unsupported,
+                    format!("{:?} can't be decoded, only 1-4 channels", info.channels),
+                )
+            }
+            0 => (info.channels.count(), false),
+            1..=4 => (channels as usize, true),
+            _ => return fail(KoiStatus::InvalidArgument, "channels have to be 0-4"),
+        };
+
+        let len = match channels {
+            1 => decode_as::<1>(data, out, &info, expand),
+            2 => decode_as::<2>(data, out, &info, expand),
+            3 => decode_as::<3>(data, out, &info, expand),
+            _ => decode_as::<4>(data, out, &info, expand),
+        };
+        match len {
+            Ok(len) => {
+                if !written.is_null() {
+                    *written = len;
+                }
+                KoiStatus::Ok
+            }
+            Err(status) => status,
+        }
+    })
+}
+
+fn too_small(size: usize, required: usize) -> KoiStatus {
+    decode_status(KoiDecodeError::OutputTooSmall { size, required })
+}
+
+// block images with their own channels are decoded in place, others are decoded to a vector first
+// - `expand` expands images to the color model of C channels (see `DecodeOptions`)
+fn decode_as<const C: usize>(
+    data: &[u8],
+    out: &mut [u8],
+    info: &koi::decoder::ImageInfo,
+    expand: bool,
+) -> Result<usize, KoiStatus> {
+    // checked before anything is decoded, so a small buffer doesn't cost a whole decode
+    let required = info
+        .header
+        .pixel_count()
+        .and_then(|pixels| pixels.checked_mul(C * info.header.bytes_per_sample()))
+        .unwrap_or(usize::MAX);
+    if out.len() < required {
+        return Err(too_small(out.len(), required));
+    }
+
+    let own_channels = match expand {
+        true => Channels::try_from(C as u8) == Ok(info.channels),
+        false => info.channels.count() == C,
+    };
+    if info.layout == Layout::Block && own_channels {
+        return match block::decode::<C>(data, out) {
+            Ok((len, _)) => Ok(len),
+            Err(err) => Err(decode_status(err)),
+        };
+    }
+
+    let options = DecodeOptions {
+        expand_channels: expand,
+        ..Default::default()
+    };
+    let image = koi::decode_to_vec_with_options::<C>(data, &options).map_err(decode_status)?;
+    out[..image.data.len()].copy_from_slice(&image.data);
+    Ok(image.data.len())
+}
+
+/// Encodes `channels` (1-4) interleaved channels with 8 bits per sample in the block layout. On
+/// success `out` points to the encoded file of `out_len` bytes, which has to be released with
+/// `koi_free`.
+///
+/// # Safety
+///
+/// `pixels` has to be valid for reads of `len` bytes, `out` and `out_len` for writes
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn koi_encode(
+    pixels: *const u8,
+    len: usize,
+    width: u64,
+    height: u64,
+    channels: u32,
+    compression: u32,
+    level: i32,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> KoiStatus {
+    guard(|| {
+        let (Some(pixels), false, false) = (input(pixels, len), out.is_null(), out_len.is_null())
+        else {
+            return fail(KoiStatus::InvalidArgument, "null pointer");
+        };
+
+        let level = match compression {
+            KOI_COMPRESSION_NONE => CompressionLevel::None,
+            KOI_COMPRESSION_LZ4_FLEX => CompressionLevel::Lz4Flex,
+            KOI_COMPRESSION_LZ4 => CompressionLevel::Lz4(level),
+            KOI_COMPRESSION_LZ4_HC => CompressionLevel::Lz4Hc(level),
+            _ => return fail(KoiStatus::InvalidArgument, "invalid compression"),
+        };
+        let Some(channels) = u8::try_from(channels)
+            .ok()
+            .and_then(|c| Channels::try_from(c).ok())
+            .filter(|c| c.count() <= 4)
+        else {
+            return fail(KoiStatus::InvalidArgument, "channels have to be 1-4");
+        };
+
+        let size = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(channels.count() as u64));
+        if size != Some(pixels.len() as u64) {
+            return fail(
+                KoiStatus::InvalidArgument,
+                "len has to be width * height * channels",
+            );
+        }
+
+        let encoder = Encoder::new(width, height, channels).level(level);
+        let file = match channels.count() {
+            1 => encoder.encode_to_vec::<1>(pixels),
+            2 => encoder.encode_to_vec::<2>(pixels),
+            3 => encoder.encode_to_vec::<3>(pixels),
+            _ => encoder.encode_to_vec::<4>(pixels),
+        };
+        let file = match file {
+            Ok(file) => file.into_boxed_slice(),
+            Err(err) => return encode_status(err),
+        };
+
+        *out_len = file.len();
+        *out = Box::into_raw(file).cast();
+        KoiStatus::Ok
+    })
+}
+
+/// Releases a buffer returned by `koi_encode`, null is ignored
+///
+/// # Safety
+///
+/// `data` and `len` have to be the values returned by `koi_encode`, and the buffer can only be
+/// released once
+#[no_mangle]
+pub unsafe extern "C" fn koi_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
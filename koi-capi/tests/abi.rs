@@ -0,0 +1,103 @@
+// Compiles tests/c/abi.c against koi.h and the static library, and runs it.
+
+use std::{env, path::PathBuf, process::Command};
+
+#[test]
+fn c_program() {
+    // the static library is built into the deps directory next to the test itself
+    let exe = env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let lib = deps.join("libkoi_capi.a");
+    assert!(lib.exists(), "{} is missing", lib.display());
+
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let program = deps.join("koi-capi-abi");
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args(["-std=c99", "-Wall", "-Wextra", "-Werror", "-I"])
+        .arg(&dir)
+        .arg(dir.join("tests/c/abi.c"))
+        .arg(&lib)
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success(), "compiling tests/c/abi.c failed");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+// files with more channels than the C API supports can't be created with koi_encode
+#[test]
+fn more_than_four_channels_are_unsupported() {
+    use koi::{encoder::Encoder, types::Channels};
+    use koi_capi::{koi_decode_into, koi_last_error_message, KoiStatus};
+
+    let files = [
+        Encoder::new(4, 3, Channels::Cmyka).encode_to_vec::<5>(&[3; 4 * 3 * 5]),
+        Encoder::new(4, 3, Channels::Bands(7)).encode_to_vec::<7>(&[3; 4 * 3 * 7]),
+    ];
+    for file in files {
+        let file = file.unwrap();
+        let mut out = vec![0; 4 * 3 * 7];
+        let mut written = 0;
+        let status = unsafe {
+            koi_decode_into(
+                file.as_ptr(),
+                file.len(),
+                out.as_mut_ptr(),
+                out.len(),
+                0,
+                &mut written,
+            )
+        };
+        assert_eq!(status, KoiStatus::Unsupported);
+        assert_eq!(written, 0);
+
+        let mut message = [0; 128];
+        let len = unsafe { koi_last_error_message(message.as_mut_ptr(), message.len()) };
+        assert!(len > 0);
+    }
+}
+
+// the size is checked before decoding, even for images that have to be decoded to a vector first
+#[test]
+fn small_buffers_are_rejected_before_decoding() {
+    use koi::{encoder::Encoder, file::Layout, types::Channels};
+    use koi_capi::{koi_decode_into, KoiStatus};
+
+    let data = [9; 4 * 3 * 3];
+    let stream = Encoder::new(4, 3, Channels::Rgb)
+        .layout(Layout::Stream)
+        .encode_to_vec::<3>(&data)
+        .unwrap();
+    let block = Encoder::new(4, 3, Channels::Rgb)
+        .encode_to_vec::<3>(&data)
+        .unwrap();
+
+    for (file, channels, required) in [(&stream, 3, 36), (&stream, 4, 48), (&block, 4, 48)] {
+        // corrupt pixel data would fail to decode, so only the size check can report an error
+        let mut file = file.clone();
+        let len = file.len();
+        file[len - 12..].fill(0xff);
+
+        for size in [0, required - 1] {
+            let mut out = vec![0; size];
+            let status = unsafe {
+                koi_decode_into(
+                    file.as_ptr(),
+                    file.len(),
+                    out.as_mut_ptr(),
+                    out.len(),
+                    channels,
+                    std::ptr::null_mut(),
+                )
+            };
+            assert_eq!(status, KoiStatus::BufferTooSmall, "{channels} {size}");
+        }
+    }
+}
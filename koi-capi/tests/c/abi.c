@@ -0,0 +1,72 @@
+// Exercises the C ABI through koi.h, exits with 1 after the first failed check
+#include <stdio.h>
+#include <string.h>
+
+#include "koi.h"
+
+#define CHECK(cond)                                                     \
+  do {                                                                  \
+    if (!(cond)) {                                                      \
+      char message[256];                                                \
+      koi_last_error_message(message, sizeof(message));                 \
+      fprintf(stderr, "%s:%d: %s (%s)\n", __FILE__, __LINE__, #cond, message); \
+      return 1;                                                         \
+    }                                                                   \
+  } while (0)
+
+enum { WIDTH = 13, HEIGHT = 7 };
+
+int main(void) {
+  uint8_t pixels[WIDTH * HEIGHT * 3];
+  for (size_t i = 0; i < sizeof(pixels); i++) pixels[i] = (uint8_t)(i * 7 / 3);
+
+  uint8_t *file = NULL;
+  size_t file_len = 0;
+  CHECK(koi_encode(pixels, sizeof(pixels), WIDTH, HEIGHT, 3, KOI_COMPRESSION_LZ4_HC, 9, &file,
+                   &file_len) == KOI_STATUS_OK);
+  CHECK(file != NULL && file_len > 4 && memcmp(file, "KOI ", 4) == 0);
+
+  KoiInfo info;
+  CHECK(koi_probe(file, file_len, &info) == KOI_STATUS_OK);
+  CHECK(info.width == WIDTH && info.height == HEIGHT);
+  CHECK(info.channels == 3 && info.bit_depth == 8);
+  CHECK(info.compression == 1 && info.layout == 1 && info.orientation == 1 && !info.has_exif);
+  CHECK(info.data_size == sizeof(pixels));
+  CHECK(koi_probe(file, info.header_size, &info) == KOI_STATUS_OK);
+
+  // with the stored channels
+  uint8_t decoded[WIDTH * HEIGHT * 4];
+  size_t written = 0;
+  CHECK(koi_decode_into(file, file_len, decoded, sizeof(decoded), 0, &written) == KOI_STATUS_OK);
+  CHECK(written == sizeof(pixels) && memcmp(decoded, pixels, written) == 0);
+
+  // expanded to RGBA
+  CHECK(koi_decode_into(file, file_len, decoded, sizeof(decoded), 4, &written) == KOI_STATUS_OK);
+  CHECK(written == sizeof(decoded));
+  for (size_t i = 0; i < WIDTH * HEIGHT; i++) {
+    CHECK(memcmp(&decoded[i * 4], &pixels[i * 3], 3) == 0 && decoded[i * 4 + 3] == 255);
+  }
+
+  // errors
+  CHECK(koi_decode_into(file, file_len, decoded, 10, 3, NULL) == KOI_STATUS_BUFFER_TOO_SMALL);
+  CHECK(koi_decode_into(file, file_len, decoded, sizeof(decoded), 5, NULL) ==
+        KOI_STATUS_INVALID_ARGUMENT);
+  CHECK(koi_decode_into(file, file_len, decoded, sizeof(decoded), 2, NULL) ==
+        KOI_STATUS_UNSUPPORTED);
+  const uint8_t garbage[] = "not an image";
+  CHECK(koi_probe(garbage, sizeof(garbage), &info) == KOI_STATUS_INVALID_HEADER);
+  CHECK(koi_last_error_message(NULL, 0) > 0);
+  CHECK(koi_probe(NULL, 4, &info) == KOI_STATUS_INVALID_ARGUMENT);
+  CHECK(koi_decode_into(file, file_len - 10, decoded, sizeof(decoded), 3, NULL) ==
+        KOI_STATUS_CORRUPT_DATA);
+  CHECK(koi_encode(pixels, sizeof(pixels) - 1, WIDTH, HEIGHT, 3, KOI_COMPRESSION_NONE, 0, &file,
+                   &file_len) == KOI_STATUS_INVALID_ARGUMENT);
+  CHECK(strcmp(koi_status_string(KOI_STATUS_CORRUPT_DATA), "corrupt data") == 0);
+  CHECK(strcmp(koi_status_string(KOI_STATUS_INTERNAL), "internal error") == 0);
+  CHECK(strcmp(koi_status_string(8), "unknown status") == 0);
+  CHECK(strcmp(koi_status_string(0xffffffff), "unknown status") == 0);
+
+  koi_free(file, file_len);
+  koi_free(NULL, 0);
+  return 0;
+}
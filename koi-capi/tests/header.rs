@@ -0,0 +1,31 @@
+// Regenerates koi.h with cbindgen and checks that the committed header matches lib.rs. Skipped
+// when cbindgen isn't installed (`cargo install cbindgen`), except in CI.
+
+use std::{env, fs, io::ErrorKind, path::PathBuf, process::Command};
+
+#[test]
+fn header_is_up_to_date() {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let output = Command::new("cbindgen")
+        .args(["--config", "cbindgen.toml", "--quiet"])
+        .current_dir(&dir)
+        .output();
+    let output = match output {
+        Err(err) if err.kind() == ErrorKind::NotFound && env::var_os("CI").is_none() => {
+            eprintln!("cbindgen isn't installed, koi.h isn't checked");
+            return;
+        }
+        output => output.expect("running cbindgen failed"),
+    };
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let committed = fs::read_to_string(dir.join("koi.h")).unwrap();
+    assert!(
+        String::from_utf8_lossy(&output.stdout) == committed,
+        "koi.h is out of date, regenerate it with `cbindgen --config cbindgen.toml --output koi.h`"
+    );
+}
@@ -1,4 +1,4 @@
-use koi::{decode, encode, file::FileHeader, types::Compression};
+use koi::{decode, encoder::Encoder, file::Layout};
 use std::{fs::File, io::BufReader};
 
 fn read_png(path: &str) -> (Vec<u8>, (u32, u32)) {
@@ -22,18 +22,10 @@ pub fn run() {
     let (test_image, (width, height)) = read_png(FILE);
     let mut out = File::create("test.koi").expect("Failed to create file");
 
-    let header = FileHeader::new(
-        0,
-        None,
-        width as u64,
-        height as u64,
-        (C as u8).try_into().unwrap(),
-        Compression::Lz4,
-        None,
-        None,
-    );
-
-    encode::<_, _, C>(header, &test_image[..], &mut out).expect("Failed to encode");
+    Encoder::new(width as u64, height as u64, (C as u8).try_into().unwrap())
+        .layout(Layout::Stream)
+        .encode_to_writer::<_, C>(&test_image, &mut out)
+        .expect("Failed to encode");
 
     let encoded_file = BufReader::new(File::open("test.koi").expect("Failed to open file"));
     let mut decoded_file = Vec::with_capacity((width * height * (C as u32)) as usize);
@@ -0,0 +1,269 @@
+// Python bindings (built with maturin, see pyproject.toml)
+//
+//     import koi
+//     pixels = koi.decode(data)                   # numpy array of shape (height, width, channels)
+//     data = koi.encode(pixels, level=9)          # block layout compressed with LZ4 HC
+//     header = koi.probe(data)                    # dimensions and metadata without the pixels
+//
+// Decoding and encoding release the GIL, so they can run in parallel in a thread pool.
+
+use koi::{
+    decoder::{DecodeOptions, ImageInfo},
+    encoder::{block::CompressionLevel, Encoder},
+    file::Layout,
+    types::{Channels, Compression, Orientation},
+    KoiDecodeError, KoiEncodeError,
+};
+use numpy::{PyArray1, PyArray3, PyArrayMethods, PyReadonlyArrayDyn, PyUntypedArrayMethods};
+use pyo3::{create_exception, exceptions::PyValueError, prelude::*, types::PyBytes};
+
+create_exception!(
+    koi,
+    KoiError,
+    PyValueError,
+    "Raised for invalid or unsupported koi files"
+);
+
+fn decode_error(err: KoiDecodeError) -> PyErr {
+    KoiError::new_err(err.to_string())
+}
+
+fn encode_error(err: KoiEncodeError) -> PyErr {
+    KoiError::new_err(err.to_string())
+}
+
+// the header of an image (see `koi.probe`)
+#[pyclass(frozen, module = "koi")]
+struct Header {
+    info: ImageInfo,
+}
+
+#[pymethods]
+impl Header {
+    #[getter]
+    fn width(&self) -> u64 {
+        self.info.width
+    }
+
+    #[getter]
+    fn height(&self) -> u64 {
+        self.info.height
+    }
+
+    // number of channels the image was stored with
+    #[getter]
+    fn channels(&self) -> usize {
+        self.info.channels.count()
+    }
+
+    #[getter]
+    fn bit_depth(&self) -> u8 {
+        self.info.bit_depth
+    }
+
+    // "none" or "lz4"
+    #[getter]
+    fn compression(&self) -> &'static str {
+        match self.info.compression {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    // "stream" or "block"
+    #[getter]
+    fn layout(&self) -> &'static str {
+        match self.info.layout {
+            Layout::Stream => "stream",
+            Layout::Block => "block",
+        }
+    }
+
+    #[getter]
+    fn color_space(&self) -> u32 {
+        self.info.header.color_space
+    }
+
+    // EXIF orientation (1-8), applied by `decode(..., apply_orientation=True)`
+    #[getter]
+    fn orientation(&self) -> u8 {
+        self.info.header.orientation as u8
+    }
+
+    #[getter]
+    fn exif<'py>(&self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        let exif = self.info.header.exif.as_deref()?;
+        Some(PyBytes::new_bound(py, exif))
+    }
+
+    // (horizontal, vertical)
+    #[getter]
+    fn dpi(&self) -> Option<(f64, f64)> {
+        self.info.header.dpi()
+    }
+
+    #[getter]
+    fn pixels_per_meter(&self) -> Option<(u32, u32)> {
+        self.info.header.pixels_per_meter
+    }
+
+    // (numerator, denominator)
+    #[getter]
+    fn pixel_aspect_ratio(&self) -> Option<(u32, u32)> {
+        self.info.header.pixel_aspect_ratio
+    }
+
+    // the pixel data starts at this offset
+    #[getter]
+    fn header_size(&self) -> usize {
+        self.info.header_size
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Header(width={}, height={}, channels={}, layout='{}')",
+            self.info.width,
+            self.info.height,
+            self.info.channels.count(),
+            self.layout()
+        )
+    }
+}
+
+// reads only the header of an image
+#[pyfunction]
+fn probe(data: &[u8]) -> PyResult<Header> {
+    let info = koi::probe(data).map_err(decode_error)?;
+    Ok(Header { info })
+}
+
+// decodes an image in either layout to a uint8 array of shape (height, width, channels), with the
+// channels it was stored with or expanded to `channels` (1-4 for gray, gray + alpha, RGB or RGBA,
+// images with another color model like CMYK raise KoiError)
+#[pyfunction]
+#[pyo3(signature = (data, channels=None, apply_orientation=false))]
+fn decode<'py>(
+    py: Python<'py>,
+    data: &[u8],
+    channels: Option<usize>,
+    apply_orientation: bool,
+) -> PyResult<Bound<'py, PyArray3<u8>>> {
+    let info = koi::probe(data).map_err(decode_error)?;
+    if info.bit_depth != 8 {
+        return Err(KoiError::new_err(format!(
+            "images with {} bits per sample aren't supported",
+            info.bit_depth
+        )));
+    }
+
+    if channels.is_some_and(|channels| !(1..=4).contains(&channels)) {
+        return Err(PyValueError::new_err("channels have to be 1-4"));
+    }
+
+    let options = DecodeOptions {
+        apply_orientation,
+        expand_channels: channels.is_some(),
+        ..Default::default()
+    };
+    let (header, pixels) = py
+        .allow_threads(|| match channels {
+            None => koi::decode_dynamic_with_options(data, &options)
+                .map(|image| (image.header, image.data)),
+            Some(1) => decode_as::<1>(data, &options),
+            Some(2) => decode_as::<2>(data, &options),
+            Some(3) => decode_as::<3>(data, &options),
+            Some(_) => decode_as::<4>(data, &options),
+        })
+        .map_err(decode_error)?;
+
+    // the orientation may have swapped the dimensions
+    let shape = [
+        header.height as usize,
+        header.width as usize,
+        header.channels.count(),
+    ];
+    PyArray1::from_vec_bound(py, pixels).reshape(shape)
+}
+
+fn decode_as<const C: usize>(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<(koi::file::FileHeader, Vec<u8>), KoiDecodeError> {
+    let image = koi::decode_to_vec_with_options::<C>(data, options)?;
+    Ok((image.header, image.data))
+}
+
+// encodes a uint8 array of shape (height, width) or (height, width, channels) with 1-4 channels in
+// the block layout
+// - level: None compresses with lz4_flex, 0 stores the pixels uncompressed and 1-12 compress with
+//   LZ4 HC at that level (higher is smaller and slower)
+// - orientation: EXIF orientation (1-8) stored in the header, the pixels are stored as they are
+#[pyfunction]
+#[pyo3(signature = (array, level=None, exif=None, orientation=1))]
+fn encode<'py>(
+    py: Python<'py>,
+    array: PyReadonlyArrayDyn<'py, u8>,
+    level: Option<i32>,
+    exif: Option<Vec<u8>>,
+    orientation: u8,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let (height, width, channels) = match *array.shape() {
+        [height, width] => (height, width, 1),
+        [height, width, channels] => (height, width, channels),
+        _ => {
+            return Err(PyValueError::new_err(
+                "expected an array with 2 or 3 dimensions",
+            ))
+        }
+    };
+    let channels = match channels {
+        1 => Channels::Gray,
+        2 => Channels::GrayAlpha,
+        3 => Channels::Rgb,
+        4 => Channels::Rgba,
+        _ => return Err(PyValueError::new_err("expected 1-4 channels")),
+    };
+    let level = match level {
+        None => CompressionLevel::Lz4Flex,
+        Some(0) => CompressionLevel::None,
+        Some(level @ 1..=12) => CompressionLevel::Lz4Hc(level),
+        Some(_) => return Err(PyValueError::new_err("level has to be 0-12")),
+    };
+    let orientation = Orientation::try_from(orientation)
+        .map_err(|_| PyValueError::new_err("orientation has to be 1-8"))?;
+
+    let mut encoder = Encoder::new(width as u64, height as u64, channels)
+        .level(level)
+        .orientation(orientation);
+    if let Some(exif) = exif {
+        encoder = encoder.exif(exif);
+    }
+
+    // other threads may write to the array once the GIL is released, so the pixels are copied
+    // (slices of larger arrays, e.g. crops, aren't contiguous and are copied pixel by pixel)
+    let pixels: Vec<u8> = match array.as_slice() {
+        Ok(pixels) => pixels.to_vec(),
+        Err(_) => array.as_array().iter().copied().collect(),
+    };
+
+    let file = py
+        .allow_threads(|| match channels.count() {
+            1 => encoder.encode_to_vec::<1>(&pixels),
+            2 => encoder.encode_to_vec::<2>(&pixels),
+            3 => encoder.encode_to_vec::<3>(&pixels),
+            _ => encoder.encode_to_vec::<4>(&pixels),
+        })
+        .map_err(encode_error)?;
+    Ok(PyBytes::new_bound(py, &file))
+}
+
+#[pymodule]
+#[pyo3(name = "koi")]
+fn koi_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("KoiError", m.py().get_type_bound::<KoiError>())?;
+    m.add_class::<Header>()?;
+    m.add_function(wrap_pyfunction!(probe, m)?)?;
+    m.add_function(wrap_pyfunction!(decode, m)?)?;
+    m.add_function(wrap_pyfunction!(encode, m)?)?;
+    Ok(())
+}
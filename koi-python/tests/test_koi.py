@@ -0,0 +1,70 @@
+# Tests for the Python bindings, run with `maturin develop && pytest tests`
+
+import numpy as np
+import pytest
+
+import koi
+
+
+def pixels(height, width, channels):
+    return (np.arange(height * width * channels) * 7 // 3).astype(np.uint8).reshape(height, width, channels)
+
+
+@pytest.mark.parametrize("channels", [1, 2, 3, 4])
+@pytest.mark.parametrize("level", [None, 0, 1, 9])
+def test_roundtrip(channels, level):
+    image = pixels(17, 31, channels)
+    data = koi.encode(image, level=level)
+    assert isinstance(data, bytes)
+
+    decoded = koi.decode(data)
+    assert decoded.dtype == np.uint8
+    assert decoded.shape == (17, 31, channels)
+    assert np.array_equal(decoded, image)
+
+
+def test_gray_arrays_and_crops():
+    image = pixels(40, 50, 3)
+    crop = image[5:25, 10:40]
+    assert not crop.flags["C_CONTIGUOUS"]
+    assert np.array_equal(koi.decode(koi.encode(crop)), crop)
+
+    gray = image[:, :, 0].copy()
+    decoded = koi.decode(koi.encode(gray))
+    assert decoded.shape == (40, 50, 1)
+    assert np.array_equal(decoded[:, :, 0], gray)
+
+
+def test_expands_channels():
+    gray = pixels(5, 7, 1)
+    rgba = koi.decode(koi.encode(gray), channels=4)
+    assert rgba.shape == (5, 7, 4)
+    assert np.array_equal(rgba[:, :, 2], gray[:, :, 0])
+    assert (rgba[:, :, 3] == 255).all()
+
+
+def test_header_and_metadata():
+    data = koi.encode(pixels(5, 7, 3), level=0, exif=b"Exif\0\0II", orientation=6)
+    header = koi.probe(data)
+    assert (header.width, header.height, header.channels) == (7, 5, 3)
+    assert header.bit_depth == 8
+    assert header.compression == "none"
+    assert header.layout == "block"
+    assert header.orientation == 6
+    assert header.exif == b"Exif\0\0II"
+    assert header.dpi is None
+    assert koi.probe(data[: header.header_size]).width == 7
+
+    # rotated 90 degrees clockwise for display
+    assert koi.decode(data, apply_orientation=True).shape == (7, 5, 3)
+
+
+def test_errors():
+    with pytest.raises(koi.KoiError):
+        koi.decode(b"not an image")
+    with pytest.raises(ValueError):
+        koi.encode(np.zeros((2, 2, 5), dtype=np.uint8))
+    with pytest.raises(ValueError):
+        koi.encode(np.zeros((2, 2, 3), dtype=np.uint8), level=13)
+    with pytest.raises(TypeError):
+        koi.encode(np.zeros((2, 2, 3), dtype=np.float32))
@@ -0,0 +1,201 @@
+// WebAssembly bindings for showing koi files in the browser (built with `wasm-pack`)
+//
+// `decode` returns RGBA pixels in the layout of `ImageData`, images with fewer channels are
+// expanded and the header's orientation is applied (CMYK and other bands are rejected):
+//
+//     const image = decode(bytes);
+//     context.putImageData(new ImageData(image.data, image.width, image.height), 0, 0);
+//
+// `probe` only reads the header, e.g. to show the dimensions before decoding the pixels.
+
+use koi::{
+    decoder::{DecodeOptions, ImageInfo},
+    file::Layout,
+    types::{Channels, Compression},
+    KoiDecodeError,
+};
+use thiserror::Error;
+use wasm_bindgen::{prelude::*, Clamped};
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error(transparent)]
+    Koi(#[from] KoiDecodeError),
+
+    // only gray and RGB with or without alpha have a meaning as RGBA, not e.g. CMYK
+    #[error("{0:?} images can't be decoded to RGBA")]
+    UnsupportedChannels(Channels),
+
+    // ImageData only holds 8 bits per sample
+    #[error("Images with {0} bits per sample can't be decoded to RGBA")]
+    UnsupportedBitDepth(u8),
+
+    #[error("Image too large: {width}x{height}")]
+    TooLarge { width: u64, height: u64 },
+}
+
+// pixels decoded to RGBA with 8 bits per sample
+#[wasm_bindgen]
+pub struct Image {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Image {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // copied into a new Uint8ClampedArray on every access
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Clamped<Vec<u8>> {
+        Clamped(self.data.clone())
+    }
+}
+
+impl Image {
+    pub fn pixels(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+// what the header tells about an image (see `koi::probe`)
+#[wasm_bindgen]
+pub struct Info {
+    info: ImageInfo,
+    width: u32,
+    height: u32,
+}
+
+#[wasm_bindgen]
+impl Info {
+    #[wasm_bindgen(getter)]
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // number of channels the image was stored with, `decode` always returns 4
+    #[wasm_bindgen(getter)]
+    pub fn channels(&self) -> u32 {
+        self.info.channels.count() as u32
+    }
+
+    #[wasm_bindgen(getter, js_name = bitDepth)]
+    pub fn bit_depth(&self) -> u8 {
+        self.info.bit_depth
+    }
+
+    // "none" or "lz4"
+    #[wasm_bindgen(getter)]
+    pub fn compression(&self) -> String {
+        match self.info.compression {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+        }
+        .to_string()
+    }
+
+    // "stream" or "block"
+    #[wasm_bindgen(getter)]
+    pub fn layout(&self) -> String {
+        match self.info.layout {
+            Layout::Stream => "stream",
+            Layout::Block => "block",
+        }
+        .to_string()
+    }
+
+    // EXIF orientation (1-8), `decode` has already applied it
+    #[wasm_bindgen(getter)]
+    pub fn orientation(&self) -> u8 {
+        self.info.header.orientation as u8
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn exif(&self) -> Option<Vec<u8>> {
+        self.info.header.exif.clone()
+    }
+
+    // the pixel data starts at this offset
+    #[wasm_bindgen(getter, js_name = headerSize)]
+    pub fn header_size(&self) -> usize {
+        self.info.header_size
+    }
+}
+
+impl Info {
+    pub fn image_info(&self) -> &ImageInfo {
+        &self.info
+    }
+}
+
+fn dimensions(width: u64, height: u64) -> Result<(u32, u32), DecodeError> {
+    match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(DecodeError::TooLarge { width, height }),
+    }
+}
+
+// decodes an image in either layout to RGBA
+pub fn decode_rgba(data: &[u8]) -> Result<Image, DecodeError> {
+    let info = koi::probe(data)?;
+    if !matches!(
+        info.channels,
+        Channels::Gray | Channels::GrayAlpha | Channels::Rgb | Channels::Rgba
+    ) {
+        return Err(DecodeError::UnsupportedChannels(info.channels));
+    }
+    if info.bit_depth != 8 {
+        return Err(DecodeError::UnsupportedBitDepth(info.bit_depth));
+    }
+    dimensions(info.width, info.height)?;
+
+    let options = DecodeOptions {
+        apply_orientation: true,
+        expand_channels: true,
+        ..Default::default()
+    };
+    let image = koi::decode_to_vec_with_options::<4>(data, &options)?;
+
+    // the orientation may have swapped the dimensions
+    let (width, height) = dimensions(image.header.width, image.header.height)?;
+    Ok(Image {
+        width,
+        height,
+        data: image.data,
+    })
+}
+
+// reads only the header of an image
+pub fn probe_header(data: &[u8]) -> Result<Info, DecodeError> {
+    let info = koi::probe(data)?;
+    let (width, height) = dimensions(info.width, info.height)?;
+    Ok(Info {
+        info,
+        width,
+        height,
+    })
+}
+
+#[wasm_bindgen]
+pub fn decode(data: &[u8]) -> Result<Image, JsError> {
+    Ok(decode_rgba(data)?)
+}
+
+#[wasm_bindgen]
+pub fn probe(data: &[u8]) -> Result<Info, JsError> {
+    Ok(probe_header(data)?)
+}
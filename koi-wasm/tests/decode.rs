@@ -0,0 +1,109 @@
+// The decoding logic behind the bindings, runs natively and with `cargo test --target wasm32-wasip1`
+// in wasmtime.
+
+use koi::{
+    encoder::{block::CompressionLevel, Encoder},
+    file::Layout,
+    types::{CfaPattern, Channels, Orientation},
+};
+use koi_wasm::{decode_rgba, probe_header, DecodeError};
+
+const WIDTH: u64 = 7;
+const HEIGHT: u64 = 5;
+
+fn pixels(channels: usize) -> Vec<u8> {
+    (0..WIDTH as usize * HEIGHT as usize * channels)
+        .map(|i| (i * 13 / 3) as u8)
+        .collect()
+}
+
+#[test]
+fn decodes_to_rgba() {
+    let data = pixels(4);
+    for layout in [Layout::Block, Layout::Stream] {
+        let file = Encoder::new(WIDTH, HEIGHT, Channels::Rgba)
+            .layout(layout)
+            .encode_to_vec::<4>(&data)
+            .unwrap();
+
+        let image = decode_rgba(&file).unwrap();
+        assert_eq!(
+            (image.width(), image.height()),
+            (WIDTH as u32, HEIGHT as u32)
+        );
+        assert_eq!(image.pixels(), data);
+    }
+}
+
+#[test]
+fn expands_and_orients() {
+    let gray = pixels(1);
+    let file = Encoder::new(WIDTH, HEIGHT, Channels::Gray)
+        .level(CompressionLevel::Lz4Hc(9))
+        .orientation(Orientation::Rotate90)
+        .encode_to_vec::<1>(&gray)
+        .unwrap();
+
+    let image = decode_rgba(&file).unwrap();
+    assert_eq!(
+        (image.width(), image.height()),
+        (HEIGHT as u32, WIDTH as u32)
+    );
+
+    // the top left pixel after rotating clockwise is the bottom left pixel of the stored image
+    let v = gray[(HEIGHT as usize - 1) * WIDTH as usize];
+    assert_eq!(image.pixels()[..4], [v, v, v, 255]);
+}
+
+#[test]
+fn probes_the_header() {
+    let file = Encoder::new(WIDTH, HEIGHT, Channels::Rgb)
+        .exif(b"Exif\0\0II".to_vec())
+        .encode_to_vec::<3>(&pixels(3))
+        .unwrap();
+
+    let info = probe_header(&file).unwrap();
+    assert_eq!((info.width(), info.height()), (WIDTH as u32, HEIGHT as u32));
+    assert_eq!(info.channels(), 3);
+    assert_eq!(info.bit_depth(), 8);
+    assert_eq!(info.compression(), "lz4");
+    assert_eq!(info.layout(), "block");
+    assert_eq!(info.exif().as_deref(), Some(&b"Exif\0\0II"[..]));
+    assert_eq!(info.header_size(), info.image_info().header_size);
+}
+
+#[test]
+fn rejects_unsupported_images() {
+    assert!(matches!(
+        decode_rgba(b"not an image"),
+        Err(DecodeError::Koi(_))
+    ));
+
+    let cfa = Encoder::new(WIDTH, HEIGHT, Channels::Gray)
+        .cfa(CfaPattern::Rggb, 16)
+        .encode_to_vec::<1>(&pixels(2))
+        .unwrap();
+    assert!(matches!(
+        decode_rgba(&cfa),
+        Err(DecodeError::UnsupportedBitDepth(16))
+    ));
+
+    // 4 channels that aren't RGBA aren't passed through as if they were
+    for channels in [Channels::Cmyk, Channels::Bands(4)] {
+        let file = Encoder::new(WIDTH, HEIGHT, channels)
+            .encode_to_vec::<4>(&pixels(4))
+            .unwrap();
+        match decode_rgba(&file) {
+            Err(DecodeError::UnsupportedChannels(c)) => assert_eq!(c, channels),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    let file = Encoder::new(WIDTH, HEIGHT, Channels::Cmyka)
+        .encode_to_vec::<5>(&pixels(5))
+        .unwrap();
+    assert!(matches!(
+        decode_rgba(&file),
+        Err(DecodeError::UnsupportedChannels(Channels::Cmyka))
+    ));
+}
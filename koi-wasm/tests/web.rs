@@ -0,0 +1,31 @@
+// The JavaScript API, runs in a headless browser with `wasm-pack test --headless --firefox` (or
+// `--chrome`), no Node.js required.
+#![cfg(target_arch = "wasm32")]
+
+use koi::{encoder::Encoder, types::Channels};
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+#[wasm_bindgen_test]
+fn decode_returns_image_data() {
+    let data: Vec<u8> = (0..3 * 2 * 3).map(|i| i as u8 * 10).collect();
+    let file = Encoder::new(3, 2, Channels::Rgb)
+        .encode_to_vec::<3>(&data)
+        .unwrap();
+
+    let image = koi_wasm::decode(&file).unwrap();
+    assert_eq!((image.width(), image.height()), (3, 2));
+
+    let rgba: Vec<u8> = data
+        .chunks(3)
+        .flat_map(|px| [px[0], px[1], px[2], 255])
+        .collect();
+    assert_eq!(image.data().0, rgba);
+
+    let info = koi_wasm::probe(&file).unwrap();
+    assert_eq!(info.channels(), 3);
+    assert_eq!(info.layout(), "block");
+
+    assert!(koi_wasm::decode(b"not an image").is_err());
+}
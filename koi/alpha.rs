@@ -0,0 +1,168 @@
+// Separate alpha planes (AlphaMode::Plane)
+//
+// Every chunk first contains the ops for the color channels (all channels except the last one),
+// followed by the alpha plane of the chunk, which starts with its type:
+// - ALPHA_PLANE_BINARY: all pixels are either fully transparent or fully opaque, stored as the
+//   lengths of alternating runs of opaque and transparent pixels (starting with an opaque run,
+//   which may be empty), each encoded as a LEB128 varint
+// - ALPHA_PLANE_DIFF: one byte per pixel containing the (wrapping) difference to the previous alpha value
+
+use alloc::string::ToString;
+
+use crate::{
+    ops::{decode_px, encode_px},
+    types::Pixel,
+    util::{read_varint, write_varint, Writer},
+    KoiDecodeError,
+};
+
+pub(crate) const ALPHA_PLANE_BINARY: u8 = 0;
+pub(crate) const ALPHA_PLANE_DIFF: u8 = 1;
+
+// encodes a chunk of pixels with C channels, the last channel being alpha
+pub(crate) fn encode_chunk<W: Writer, const C: usize>(
+    chunk: &[u8],
+    prev_pixel: &mut Pixel<C>,
+    buf: W,
+) -> W {
+    match C {
+        2 => encode_chunk_impl::<W, C, 1>(chunk, prev_pixel, buf),
+        4 => encode_chunk_impl::<W, C, 3>(chunk, prev_pixel, buf),
+        5 => encode_chunk_impl::<W, C, 4>(chunk, prev_pixel, buf),
+        _ => unreachable!("alpha planes require 2, 4 or 5 channels"),
+    }
+}
+
+// decodes a chunk of pixels with C channels, the last channel being alpha, `offset` is the
+// position of the chunk in the uncompressed ops of the image (for errors)
+pub(crate) fn decode_chunk<const C: usize>(
+    data: &[u8],
+    out: &mut [u8],
+    prev_pixel: &mut Pixel<C>,
+    offset: usize,
+) -> Result<(), KoiDecodeError> {
+    match C {
+        2 => decode_chunk_impl::<C, 1>(data, out, prev_pixel, offset),
+        4 => decode_chunk_impl::<C, 3>(data, out, prev_pixel, offset),
+        5 => decode_chunk_impl::<C, 4>(data, out, prev_pixel, offset),
+        _ => Err(KoiDecodeError::InvalidFileHeader(
+            "alpha planes require 2, 4 or 5 channels".to_string(),
+        )),
+    }
+}
+
+// CC is the number of color channels (C - 1)
+fn encode_chunk_impl<W: Writer, const C: usize, const CC: usize>(
+    chunk: &[u8],
+    prev_pixel: &mut Pixel<C>,
+    mut buf: W,
+) -> W {
+    let mut prev_color = Pixel::<CC>::from(&prev_pixel.data[..CC]);
+    for px in chunk.chunks_exact(C) {
+        let color = Pixel::<CC>::from(&px[..CC]);
+        buf = encode_px::<W, CC>(color, prev_color, buf);
+        prev_color = color;
+    }
+
+    buf = encode_plane::<W, C>(chunk, prev_pixel.data[C - 1], buf);
+
+    if let Some(last) = chunk.chunks_exact(C).last() {
+        *prev_pixel = Pixel::from(last);
+    }
+    buf
+}
+
+fn decode_chunk_impl<const C: usize, const CC: usize>(
+    chunk: &[u8],
+    out: &mut [u8],
+    prev_pixel: &mut Pixel<C>,
+    offset: usize,
+) -> Result<(), KoiDecodeError> {
+    let mut data = chunk;
+    let mut prev_color = Pixel::<CC>::from(&prev_pixel.data[..CC]);
+    for px in out.chunks_exact_mut(C) {
+        let color: Pixel<CC>;
+        // alpha planes only exist since version 2
+        (data, color) = decode_px::<CC>(data, prev_color, false)
+            .map_err(|e| e.at(offset + chunk.len() - data.len()))?;
+        px[..CC].copy_from_slice(&color.data);
+        prev_color = color;
+    }
+
+    decode_plane::<C>(data, out, prev_pixel.data[C - 1])?;
+
+    if let Some(last) = out.chunks_exact(C).last() {
+        *prev_pixel = Pixel::from(last);
+    }
+    Ok(())
+}
+
+fn encode_plane<W: Writer, const C: usize>(chunk: &[u8], prev_alpha: u8, mut buf: W) -> W {
+    let alpha = chunk.iter().skip(C - 1).step_by(C).copied();
+
+    if !alpha.clone().all(|a| a == 0 || a == 255) {
+        buf = buf.write_one(ALPHA_PLANE_DIFF);
+
+        let mut prev_alpha = prev_alpha;
+        for a in alpha {
+            buf = buf.write_one(a.wrapping_sub(prev_alpha));
+            prev_alpha = a;
+        }
+        return buf;
+    }
+
+    buf = buf.write_one(ALPHA_PLANE_BINARY);
+
+    let mut opaque = true;
+    let mut run = 0;
+    for a in alpha {
+        if (a == 255) != opaque {
+            buf = write_varint(run, buf);
+            opaque = !opaque;
+            run = 0;
+        }
+        run += 1;
+    }
+    write_varint(run, buf)
+}
+
+fn decode_plane<const C: usize>(
+    data: &[u8],
+    out: &mut [u8],
+    prev_alpha: u8,
+) -> Result<(), KoiDecodeError> {
+    let mut pixels = out.chunks_exact_mut(C);
+
+    match data.split_first() {
+        Some((&ALPHA_PLANE_DIFF, diffs)) => {
+            if diffs.len() < pixels.len() {
+                return Err(KoiDecodeError::InvalidAlphaPlane);
+            }
+
+            let mut prev_alpha = prev_alpha;
+            for (px, diff) in pixels.zip(diffs) {
+                prev_alpha = prev_alpha.wrapping_add(*diff);
+                px[C - 1] = prev_alpha;
+            }
+        }
+        Some((&ALPHA_PLANE_BINARY, mut runs)) => {
+            let mut opaque = true;
+            while pixels.len() > 0 {
+                let run;
+                (run, runs) = read_varint(runs).ok_or(KoiDecodeError::InvalidAlphaPlane)?;
+                if run > pixels.len() {
+                    return Err(KoiDecodeError::InvalidAlphaPlane);
+                }
+
+                let alpha = if opaque { 255 } else { 0 };
+                for px in pixels.by_ref().take(run) {
+                    px[C - 1] = alpha;
+                }
+                opaque = !opaque;
+            }
+        }
+        _ => return Err(KoiDecodeError::InvalidAlphaPlane),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+// Raw Bayer/CFA sensor data (FileHeader::cfa_pattern)
+//
+// Single-channel mosaics with 8 or 16 bits per sample (16-bit samples are little endian). Instead
+// of pixel ops, every sample is stored as the difference to a prediction from its already encoded
+// same-color neighbors (see `Cfa::predict`), mapped to 0, -1, 1, -2, 2, ... (zigzag):
+// - 8 bits: one byte per sample
+// - 16 bits: one LEB128 varint per sample (1 to 3 bytes)
+//
+// Predictions use samples of the previous two rows, so chunks have to be decoded in order and
+// streaming codecs keep the last `history()` samples of the previous chunks around.
+
+use core::ops::Range;
+
+use crate::{
+    file::FileHeader,
+    types::CfaPattern,
+    util::{read_varint, write_varint, Writer},
+    KoiDecodeError,
+};
+
+pub(crate) struct Cfa {
+    pattern: CfaPattern,
+    width: usize,
+    bit_depth: u8,
+}
+
+impl Cfa {
+    pub(crate) fn new(header: &FileHeader) -> Option<Self> {
+        Some(Self {
+            pattern: header.cfa_pattern?,
+            width: header.width as usize,
+            bit_depth: header.bit_depth,
+        })
+    }
+
+    // number of samples before the current one that predictions may use
+    pub(crate) fn history(&self) -> usize {
+        2 * self.width + 2
+    }
+
+    #[inline]
+    fn mask(&self) -> u32 {
+        (1 << self.bit_depth) - 1
+    }
+
+    #[inline]
+    fn sample(&self, data: &[u8], i: usize) -> u32 {
+        match self.bit_depth {
+            16 => u16::from_le_bytes([data[i * 2], data[i * 2 + 1]]) as u32,
+            _ => data[i] as u32,
+        }
+    }
+
+    #[inline]
+    fn set_sample(&self, data: &mut [u8], i: usize, value: u32) {
+        match self.bit_depth {
+            16 => data[i * 2..i * 2 + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+            _ => data[i] = value as u8,
+        }
+    }
+
+    // predicts sample i from its same-color neighbors in the current and the previous two rows,
+    // `data` starts at sample `offset`
+    #[inline]
+    fn predict(&self, data: &[u8], offset: usize, i: usize) -> u32 {
+        let (x, y) = (i % self.width, i / self.width);
+        let at = |x: usize, y: usize| self.sample(data, y * self.width + x - offset);
+
+        // green samples have closer same-color neighbors on the diagonals of the previous row
+        if y >= 1 && self.pattern.is_green(x, y) {
+            match (x >= 1, x + 1 < self.width) {
+                (true, true) => return (at(x - 1, y - 1) + at(x + 1, y - 1)).div_ceil(2),
+                (true, false) => return at(x - 1, y - 1),
+                (false, true) => return at(x + 1, y - 1),
+                (false, false) => {}
+            }
+        }
+
+        match (x >= 2, y >= 2) {
+            (true, true) => median_edge(at(x - 2, y), at(x, y - 2), at(x - 2, y - 2)),
+            (true, false) => at(x - 2, y),
+            (false, true) => at(x, y - 2),
+            (false, false) => 0,
+        }
+    }
+
+    // encodes the samples in `pixels`, `data` starts at sample `offset` and contains at least
+    // `history()` samples before the first one (or all of them)
+    pub(crate) fn encode_chunk<W: Writer>(
+        &self,
+        data: &[u8],
+        offset: usize,
+        pixels: Range<usize>,
+        mut buf: W,
+    ) -> W {
+        let mask = self.mask();
+        for i in pixels {
+            let sample = self.sample(data, i - offset);
+            let residual = sample.wrapping_sub(self.predict(data, offset, i)) & mask;
+            let zigzag = if residual <= mask / 2 {
+                residual * 2
+            } else {
+                (mask - residual) * 2 + 1
+            };
+
+            buf = match self.bit_depth {
+                16 => write_varint(zigzag as usize, buf),
+                _ => buf.write_one(zigzag as u8),
+            };
+        }
+        buf
+    }
+
+    // decodes the samples in `pixels` into `out`, which starts at sample `offset` (see encode_chunk)
+    pub(crate) fn decode_chunk(
+        &self,
+        mut data: &[u8],
+        out: &mut [u8],
+        offset: usize,
+        pixels: Range<usize>,
+    ) -> Result<(), KoiDecodeError> {
+        if (pixels.end - offset) * (self.bit_depth as usize / 8) > out.len() {
+            return Err(KoiDecodeError::InvalidChunkLength);
+        }
+
+        let mask = self.mask();
+        for i in pixels {
+            let zigzag = match self.bit_depth {
+                16 => {
+                    let zigzag;
+                    (zigzag, data) = read_varint(data).ok_or(KoiDecodeError::InvalidCfaData)?;
+                    zigzag as u32
+                }
+                _ => {
+                    let (&zigzag, rest) =
+                        data.split_first().ok_or(KoiDecodeError::InvalidCfaData)?;
+                    data = rest;
+                    zigzag as u32
+                }
+            };
+
+            if zigzag > mask {
+                return Err(KoiDecodeError::InvalidCfaData);
+            }
+
+            let residual = if zigzag % 2 == 0 {
+                zigzag / 2
+            } else {
+                mask - zigzag / 2
+            };
+            let value = self.predict(out, offset, i).wrapping_add(residual) & mask;
+            self.set_sample(out, i - offset, value);
+        }
+
+        Ok(())
+    }
+}
+
+// median edge detector (LOCO-I), picks the left or upper neighbor at edges and interpolates otherwise
+#[inline]
+fn median_edge(left: u32, up: u32, up_left: u32) -> u32 {
+    if up_left >= left.max(up) {
+        left.min(up)
+    } else if up_left <= left.min(up) {
+        left.max(up)
+    } else {
+        left + up - up_left
+    }
+}
@@ -0,0 +1,173 @@
+// Channel reduction (EncodeOptions::reduce_channels) and expansion
+//
+// Gray, gray + alpha, RGB and RGBA images can be stored with fewer channels if their alpha channel
+// is always opaque or their color is always gray. Decoding them with `DecodeOptions::expand_channels`
+// and more channels than they were stored with expands them again: gray is copied into r, g and b,
+// and missing alpha is opaque.
+
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+
+use crate::{
+    file::FileHeader,
+    types::{AlphaMode, Channels},
+    KoiDecodeError,
+};
+
+fn is_color(channels: Channels) -> bool {
+    matches!(
+        channels,
+        Channels::Gray | Channels::GrayAlpha | Channels::Rgb | Channels::Rgba
+    )
+}
+
+// stores the pixels with the fewest channels that keep them lossless, returns the reduced pixels
+// and updates the header, None if the image can't be reduced
+pub(crate) fn reduce(data: &[u8], header: &mut FileHeader) -> Option<Vec<u8>> {
+    let channels = header.channels;
+    if !is_color(channels) || header.bit_depth != 8 || header.cfa_pattern.is_some() {
+        return None;
+    }
+
+    let count = channels.count();
+    if Some(data.len()) != header.pixel_count()?.checked_mul(count) {
+        return None;
+    }
+
+    let mut pixels = data.chunks_exact(count);
+    let opaque = !channels.has_alpha() || pixels.clone().all(|px| px[count - 1] == 255);
+    let gray = count < 3 || pixels.all(|px| px[0] == px[1] && px[1] == px[2]);
+
+    let reduced = match (gray, opaque) {
+        (true, true) => Channels::Gray,
+        (true, false) => Channels::GrayAlpha,
+        (false, true) => Channels::Rgb,
+        (false, false) => Channels::Rgba,
+    };
+    if reduced == channels {
+        return None;
+    }
+
+    let out = to_channels(data, channels, reduced);
+    header.channels = reduced;
+    if !reduced.has_alpha() {
+        header.alpha_mode = AlphaMode::Interleaved;
+    }
+    Some(out)
+}
+
+// the channels pixels stored with `from` are expanded to when they're decoded with C channels,
+// None if they're decoded as they are
+// - without `expand`, C is only a channel count, which the decoders compare with the stored one
+// - with `expand`, C selects gray, gray + alpha, RGB or RGBA (or C bands), which the stored
+//   channels have to be or expand to
+pub(crate) fn expanded<const C: usize>(
+    from: Channels,
+    expand: bool,
+) -> Result<Option<Channels>, KoiDecodeError> {
+    if !expand {
+        return Ok(None);
+    }
+
+    let Ok(to) = Channels::try_from(C as u8) else {
+        return Err(KoiDecodeError::ChannelMismatch {
+            image: from.count(),
+            requested: C,
+        });
+    };
+    if from == to {
+        return Ok(None);
+    }
+
+    let expands = is_color(from)
+        && is_color(to)
+        && from.count() < to.count()
+        && (!from.has_alpha() || to.has_alpha());
+    match expands {
+        true => Ok(Some(to)),
+        false => Err(KoiDecodeError::UnsupportedExpansion { from, to }),
+    }
+}
+
+// appends `data` converted from one set of color channels to another to `out`
+fn convert(data: &[u8], from: Channels, to: Channels, out: &mut Vec<u8>) {
+    for px in data.chunks_exact(from.count()) {
+        let (rgb, a) = match px {
+            [v] => ([*v; 3], 255),
+            [v, a] => ([*v; 3], *a),
+            [r, g, b] => ([*r, *g, *b], 255),
+            [r, g, b, a] => ([*r, *g, *b], *a),
+            _ => unreachable!("only color channels are converted"),
+        };
+
+        match to {
+            Channels::Gray => out.push(rgb[0]),
+            Channels::GrayAlpha => out.extend_from_slice(&[rgb[0], a]),
+            Channels::Rgb => out.extend_from_slice(&rgb),
+            _ => out.extend_from_slice(&[rgb[0], rgb[1], rgb[2], a]),
+        }
+    }
+}
+
+// writer that expands the pixels written to it before passing them on
+#[cfg(feature = "std")]
+pub(crate) struct Expand<W: Write> {
+    inner: W,
+    from: Channels,
+    to: Channels,
+    partial: Vec<u8>, // pixels that haven't been written completely yet
+    buf: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Expand<W> {
+    pub(crate) fn new(inner: W, from: Channels, to: Channels) -> Self {
+        Self {
+            inner,
+            from,
+            to,
+            partial: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Write for Expand<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let size = self.from.count();
+        self.buf.clear();
+
+        // complete the pixel left over from the previous write first
+        let mut rest = data;
+        if !self.partial.is_empty() {
+            let missing = (size - self.partial.len()).min(rest.len());
+            self.partial.extend_from_slice(&rest[..missing]);
+            rest = &rest[missing..];
+
+            if self.partial.len() < size {
+                return Ok(data.len());
+            }
+            convert(&self.partial, self.from, self.to, &mut self.buf);
+            self.partial.clear();
+        }
+
+        let whole = rest.len() / size * size;
+        convert(&rest[..whole], self.from, self.to, &mut self.buf);
+        self.partial.extend_from_slice(&rest[whole..]);
+
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn to_channels(data: &[u8], from: Channels, to: Channels) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / from.count() * to.count());
+    convert(data, from, to, &mut out);
+    out
+}
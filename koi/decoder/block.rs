@@ -1,7 +1,17 @@
+use alloc::{format, string::ToString, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::io::Read;
+
 use crate::{
-    file::FileHeader,
+    alpha,
+    cfa::Cfa,
+    decoder::DecodeLimits,
+    file::{FileHeader, Layout},
+    image_too_large,
+    layout::{write_px, Frame, Rows},
+    ops::decode_px,
     types::*,
-    util::{cold, unlikely, Buffer, BufferMut, Writer},
+    util::{unlikely, Buffer, BufferMut, Writer},
     KoiDecodeError,
 };
 
@@ -11,116 +21,530 @@ pub struct Image {
 }
 
 pub fn decode_to_vec<const C: usize>(data: &[u8]) -> Result<Image, KoiDecodeError> {
-    let data = Buffer::new(data);
-    let (data, header) = FileHeader::read_buf(data)?;
+    decode_to_vec_with_limits::<C>(data, &DecodeLimits::default())
+}
 
-    if header.version != 1 {
+// like `decode_to_vec`, with limits other than the defaults (e.g. to allow larger images)
+pub fn decode_to_vec_with_limits<const C: usize>(
+    data: &[u8],
+    limits: &DecodeLimits,
+) -> Result<Image, KoiDecodeError> {
+    let (header_len, header) = FileHeader::read_bytes_with_limits(data, limits)?;
+    let data = &data[header_len..];
+
+    if header.layout()? != Layout::Block {
         return Err(KoiDecodeError::UnsupportedVersion(header.version as u8));
     }
 
+    limits.check_header(&header)?;
+
+    header.data_size().ok_or_else(image_too_large)?;
+    limits.check_alloc(header.min_output_size())?;
+
     let mut out = vec![0; header.min_output_size()];
-    let len = decode_impl::<C>(&data, &mut out, header.clone())?;
+    let len = decode_impl::<C>(data, &mut out, header.clone(), &BufferFormat::default())?;
     out.truncate(len);
 
     Ok(Image { header, data: out })
 }
 
-pub fn min_output_size<const C: usize>(data: &[u8]) -> usize {
-    FileHeader::read_bytes(data).unwrap().1.min_output_size()
+pub fn min_output_size<const C: usize>(data: &[u8]) -> Result<usize, KoiDecodeError> {
+    Ok(FileHeader::read_bytes(data)?.1.min_output_size())
 }
 
 pub fn decode<const C: usize>(
     data: &[u8],
     out: &mut [u8],
+) -> Result<(usize, FileHeader), KoiDecodeError> {
+    decode_with_format::<C>(data, out, &BufferFormat::default())
+}
+
+// decodes into a buffer with the given format, returns the number of bytes written
+pub fn decode_with_format<const C: usize>(
+    data: &[u8],
+    out: &mut [u8],
+    format: &BufferFormat,
 ) -> Result<(usize, FileHeader), KoiDecodeError> {
     let data = Buffer::new(data);
     let (data, header) = FileHeader::read_buf(data)?;
 
-    if header.version != 1 {
+    if header.layout()? != Layout::Block {
         return Err(KoiDecodeError::UnsupportedVersion(header.version as u8));
     }
 
-    let len = decode_impl::<C>(&data, out, header.clone())?;
+    let len = decode_impl::<C>(&data, out, header.clone(), format)?;
     Ok((len, header))
 }
 
-fn decode_impl<const C: usize>(
+pub(crate) fn decode_impl<const C: usize>(
     data: &[u8],
     out: &mut [u8],
     header: FileHeader,
+    format: &BufferFormat,
 ) -> Result<usize, KoiDecodeError> {
-    let mut data = Buffer::new(data);
+    let mut decoder = ChunkDecoder::<C>::new(&header)?;
+    if !format.layout.supports(C) {
+        return Err(KoiDecodeError::UnsupportedLayout {
+            layout: format.layout,
+            channels: C,
+        });
+    }
 
-    let out_buf_cap = out.len();
-    let mut out_buf = BufferMut::new(out);
+    let frame = Frame::new(format, &header, C);
+    let packed = format.is_packed(frame.row_size());
+    if unlikely(!packed && frame.row_stride < frame.row_size()) {
+        return Err(KoiDecodeError::InvalidStride {
+            stride: frame.row_stride,
+            row_size: frame.row_size(),
+        });
+    }
 
-    let mut prev_pixel = Pixel::<C>::default();
-    let mut out_chunk = [0; (MAX_CHUNK_SIZE * 2)];
+    // checked before the first chunk, so a small buffer isn't mistaken for corrupt data
+    let required = frame.buffer_size().ok_or_else(image_too_large)?;
+    if unlikely(out.len() < required) {
+        return Err(KoiDecodeError::OutputTooSmall {
+            size: out.len(),
+            required,
+        });
+    }
+
+    let bytes_per_pixel = decoder.bytes_per_pixel();
+    let mut data = Buffer::new(data);
+    let mut out_chunk = [0; MAX_OPS_CHUNK_SIZE];
+    let mut pos = 0;
+
+    let expected = header.pixel_count().ok_or_else(image_too_large)?;
+    let mut decoded = 0;
 
     loop {
-        if data.is_empty() {
+        let chunk;
+        (data, chunk) = read_chunk(data, &mut out_chunk, &header)?;
+        let Some((pixels, ops)) = chunk else {
             break;
+        };
+
+        let first = decoded;
+        decoded += pixels as usize;
+        if unlikely(decoded > expected) {
+            return Err(pixel_count_mismatch(expected, decoded));
         }
 
-        let len: u32;
-        let pixels: u32;
-        (len, data) = data.read_u32_le();
-        (pixels, data) = data.read_u32_le();
+        if !packed {
+            decoder.decode_chunk_as(ops, out, frame.rows(first..decoded), format.layout)?;
+            continue;
+        }
 
-        if len == 0 {
-            break;
+        // within `out`, the pixel count has been checked against the header
+        let end = pos + pixels as usize * bytes_per_pixel;
+        decoder.decode_chunk(ops, &mut out[..end], 0, pos)?;
+        pos = end;
+    }
+
+    if unlikely(decoded != expected) {
+        return Err(pixel_count_mismatch(expected, decoded));
+    }
+
+    Ok(required)
+}
+
+fn pixel_count_mismatch(expected: usize, actual: usize) -> KoiDecodeError {
+    KoiDecodeError::PixelCountMismatch {
+        expected: expected as u64,
+        actual: actual as u64,
+    }
+}
+
+// BlockDecoder decodes an image in the block layout chunk by chunk while it is read from, only
+// keeping a single chunk in memory
+// - R is the reader the encoded image is read from
+// - C is the number of channels in the image
+#[cfg(feature = "std")]
+pub struct BlockDecoder<R: Read, const C: usize> {
+    reader: R,
+    header: FileHeader,
+    decoder: ChunkDecoder<C>,
+
+    compressed: Vec<u8>,
+    ops: Vec<u8>,
+
+    window: Vec<u8>, // pixels of the current chunk, preceded by the history needed for CFA data
+    window_offset: usize, // index of the first pixel in window
+    pos: usize,      // position of the first pixel in window that hasn't been read yet
+    remaining: usize, // number of pixels still expected
+    finished: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, const C: usize> BlockDecoder<R, C> {
+    // reads the header and prepares decoding the pixels
+    pub fn new(reader: R) -> Result<Self, KoiDecodeError> {
+        Self::with_limits(reader, &DecodeLimits::default())
+    }
+
+    // only the current chunk is kept in memory, so the allocation limit doesn't apply
+    pub fn with_limits(mut reader: R, limits: &DecodeLimits) -> Result<Self, KoiDecodeError> {
+        let header = FileHeader::read_with_limits(&mut reader, limits)?;
+        limits.check_header(&header)?;
+        Self::with_header(reader, header)
+    }
+
+    // continues after a header that has already been read from `reader` (and checked)
+    pub(crate) fn with_header(reader: R, header: FileHeader) -> Result<Self, KoiDecodeError> {
+        if header.layout()? != Layout::Block {
+            return Err(KoiDecodeError::UnsupportedVersion(header.version as u8));
         }
 
-        if unlikely(len as usize > (MAX_CHUNK_SIZE * 2)) {
-            panic!("chunk too big: {}", len);
+        let remaining = header.pixel_count().ok_or_else(image_too_large)?;
+
+        Ok(Self {
+            reader,
+            decoder: ChunkDecoder::new(&header)?,
+            header,
+
+            compressed: Vec::new(),
+            ops: vec![0; MAX_OPS_CHUNK_SIZE],
+
+            window: Vec::new(),
+            window_offset: 0,
+            pos: 0,
+            remaining,
+            finished: false,
+        })
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    // decodes the next chunk into window, returns false after the last chunk
+    fn next_chunk(&mut self) -> Result<bool, KoiDecodeError> {
+        if self.finished {
+            return Ok(false);
+        }
+
+        let chunk_header = read_chunk_header(&mut self.reader)?;
+        let (len, pixels) = match chunk_header {
+            Some((len, pixels)) if len != 0 => (len, pixels),
+            _ => {
+                self.finished = true;
+                if unlikely(self.remaining != 0) {
+                    let expected = self.header.pixel_count().unwrap_or(usize::MAX);
+                    return Err(pixel_count_mismatch(expected, expected - self.remaining));
+                }
+                return Ok(false);
+            }
+        };
+
+        if unlikely(len as usize > MAX_COMPRESSED_CHUNK_SIZE) {
+            return Err(KoiDecodeError::InvalidChunkLength);
         }
+        if unlikely(pixels as usize > self.remaining) {
+            let expected = self.header.pixel_count().unwrap_or(usize::MAX);
+            let actual = expected - self.remaining + pixels as usize;
+            return Err(pixel_count_mismatch(expected, actual));
+        }
+        check_chunk_pixels(&self.header, pixels)?;
+
+        self.compressed.resize(len as usize, 0);
+        self.reader
+            .read_exact(&mut self.compressed)
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => KoiDecodeError::TruncatedChunk,
+                _ => e.into(),
+            })?;
+        let ops_len = decompress(&self.compressed, &mut self.ops, self.header.compression)?;
 
-        let decompress_size =
-            decompress(&data[..len as usize], &mut out_chunk, header.compression)?;
-        data = data.advance(len as usize);
+        // every pixel takes at least one byte, which bounds the size of the decoded chunk
+        if unlikely(pixels as usize > ops_len) {
+            return Err(KoiDecodeError::InvalidChunkLength);
+        }
 
-        let mut out_chunk_buf = &mut out_chunk[..decompress_size];
+        // only keep the pixels that are needed to decode the next chunk
+        let bytes_per_pixel = self.decoder.bytes_per_pixel();
+        let keep = match &self.decoder.cfa {
+            Some(cfa) => (cfa.history() * bytes_per_pixel).min(self.window.len()),
+            None => 0,
+        };
+        let drop = self.window.len() - keep;
+        self.window.drain(..drop);
+        self.window_offset += drop / bytes_per_pixel;
+
+        let start = self.window.len();
+        self.window
+            .resize(start + pixels as usize * bytes_per_pixel, 0);
+        self.decoder.decode_chunk(
+            &self.ops[..ops_len],
+            &mut self.window,
+            self.window_offset,
+            start,
+        )?;
+
+        self.pos = start;
+        self.remaining -= pixels as usize;
+        Ok(true)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, const C: usize> Read for BlockDecoder<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.window.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.window.len() - self.pos);
+        buf[..len].copy_from_slice(&self.window[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// decoding state carried from one chunk to the next
+struct ChunkDecoder<const C: usize> {
+    prev_pixel: Pixel<C>,
+    reset_alpha: bool, // ops of files before version 2
+    alpha_plane: bool,
+    cfa: Option<Cfa>,
+    bytes_per_sample: usize,
+    ops_offset: usize, // decompressed ops of all previous chunks (for errors)
+
+    // alpha planes and CFA data are decoded here before they're copied into another layout, for
+    // CFA data preceded by the history of the previous chunks
+    scratch: Vec<u8>,
+    scratch_offset: usize, // index of the first pixel in scratch
+}
+
+impl<const C: usize> ChunkDecoder<C> {
+    fn new(header: &FileHeader) -> Result<Self, KoiDecodeError> {
+        if header.channels.count() != C {
+            return Err(KoiDecodeError::ChannelMismatch {
+                image: header.channels.count(),
+                requested: C,
+            });
+        }
+
+        let cfa = Cfa::new(header);
+        if cfa.is_some() && (C != 1 || header.channels != Channels::Gray) {
+            return Err(KoiDecodeError::InvalidFileHeader(format!(
+                "CFA data requires a single channel, got {:?}",
+                header.channels
+            )));
+        }
+
+        if header.bit_depth != 8 && cfa.is_none() {
+            return Err(KoiDecodeError::InvalidFileHeader(format!(
+                "{} bits per sample are only supported for CFA data",
+                header.bit_depth
+            )));
+        }
+
+        Ok(Self {
+            prev_pixel: Pixel::default(),
+            reset_alpha: header.ops_reset_alpha(),
+            alpha_plane: header.alpha_mode == AlphaMode::Plane,
+            cfa,
+            bytes_per_sample: header.bytes_per_sample(),
+            ops_offset: 0,
+            scratch: Vec::new(),
+            scratch_offset: 0,
+        })
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        C * self.bytes_per_sample
+    }
+
+    // decodes the ops of a chunk into `out[start..]`
+    // - `out` starts at pixel `offset`, `out[..start]` contains the history needed for CFA data
+    fn decode_chunk(
+        &mut self,
+        ops: &[u8],
+        out: &mut [u8],
+        offset: usize,
+        start: usize,
+    ) -> Result<(), KoiDecodeError> {
+        let result = self.decode_chunk_ops(ops, out, offset, start);
+        self.ops_offset += ops.len();
+        result
+    }
+
+    // decodes the ops of a chunk into the pixels in `rows` of a buffer in another format (see
+    // BufferFormat)
+    fn decode_chunk_as(
+        &mut self,
+        ops: &[u8],
+        out: &mut [u8],
+        rows: Rows,
+        layout: PixelLayout,
+    ) -> Result<(), KoiDecodeError> {
+        let order = layout.order();
+        let bytes_per_pixel = layout.bytes_per_pixel(C);
+
+        // predictions need the previous rows, so only they're kept instead of the whole image
+        if let Some(cfa) = &self.cfa {
+            let bytes_per_pixel = self.bytes_per_pixel();
+            let mut window = core::mem::take(&mut self.scratch);
+            let keep = (cfa.history() * bytes_per_pixel).min(window.len());
+            let dropped = window.len() - keep;
+            window.drain(..dropped);
+            self.scratch_offset += dropped / bytes_per_pixel;
+
+            let start = window.len();
+            window.resize(start + rows.pixels() * bytes_per_pixel, 0);
+            let result = self.decode_chunk(ops, &mut window, self.scratch_offset, start);
+
+            let mut pos = start;
+            for row in rows {
+                let len = row.len();
+                out[row].copy_from_slice(&window[pos..pos + len]);
+                pos += len;
+            }
+            self.scratch = window;
+            return result;
+        }
+
+        if self.alpha_plane {
+            let mut chunk = core::mem::take(&mut self.scratch);
+            chunk.resize(rows.pixels() * C, 0);
+            let result = self.decode_chunk(ops, &mut chunk, 0, 0);
+
+            let mut pixels = chunk.chunks_exact(C);
+            for row in rows {
+                // the destination comes first, zip would drop a pixel at the end of every row
+                for (dst, px) in out[row].chunks_exact_mut(bytes_per_pixel).zip(&mut pixels) {
+                    write_px::<C>(px, dst, order);
+                }
+            }
+            self.scratch = chunk;
+            return result;
+        }
+
+        let chunk_ops = ops;
+        let mut ops = ops;
+        for row in rows {
+            for dst in out[row].chunks_exact_mut(bytes_per_pixel) {
+                let px: Pixel<C>;
+                (ops, px) = decode_px::<C>(ops, self.prev_pixel, self.reset_alpha)
+                    .map_err(|e| e.at(self.ops_offset + chunk_ops.len() - ops.len()))?;
+
+                self.prev_pixel = px;
+                write_px::<C>(&px.data, dst, order);
+            }
+        }
+
+        self.ops_offset += chunk_ops.len();
+        Ok(())
+    }
+
+    fn decode_chunk_ops(
+        &mut self,
+        chunk_ops: &[u8],
+        out: &mut [u8],
+        offset: usize,
+        start: usize,
+    ) -> Result<(), KoiDecodeError> {
+        if let Some(cfa) = &self.cfa {
+            let first = offset + start / self.bytes_per_pixel();
+            let pixels = (out.len() - start) / self.bytes_per_pixel();
+            return cfa.decode_chunk(chunk_ops, out, offset, first..first + pixels);
+        }
+
+        let out = &mut out[start..];
+        if self.alpha_plane {
+            return alpha::decode_chunk::<C>(chunk_ops, out, &mut self.prev_pixel, self.ops_offset);
+        }
+
+        let pixels = out.len() / C;
+        let mut out_buf = BufferMut::new(out);
+        let mut ops = chunk_ops;
 
         // iterate pixels times
         for _ in 0..pixels {
             let px: Pixel<C>;
-            (out_chunk_buf, px) = decode_px::<C>(out_chunk_buf, prev_pixel);
+            (ops, px) = decode_px::<C>(ops, self.prev_pixel, self.reset_alpha)
+                .map_err(|e| e.at(self.ops_offset + chunk_ops.len() - ops.len()))?;
 
-            prev_pixel = px;
+            self.prev_pixel = px;
             out_buf = out_buf.write_many(&px.data);
         }
-    }
 
-    Ok(out_buf_cap - out_buf.len())
+        Ok(())
+    }
 }
 
-#[allow(clippy::all)] // clippy is making the code slower
-fn decode_px<'a, const C: usize>(
-    data: &'a mut [u8],
-    prev_pixel: Pixel<C>,
-) -> (&'a mut [u8], Pixel<C>) {
-    match data {
-        [OP_SAME, rest @ ..] => (rest, prev_pixel),
-        [OP_GRAY, v, rest @ ..] => (rest, Pixel::<C>::from_grayscale(*v)),
-        [OP_GRAY_ALPHA, v, a, rest @ ..] => (rest, Pixel::<C>::from([*v, *v, *v, *a])),
-        [OP_RGB, r, g, b, rest @ ..] => (rest, Pixel::<C>::from([*r, *g, *b, 255])),
-        [OP_RGBA, r, g, b, a, rest @ ..] => (rest, Pixel::<C>::from([*r, *g, *b, *a])),
+// pixel count and decompressed ops of a chunk
+type Chunk<'a> = (u32, &'a [u8]);
 
-        [b1 @ OP_DIFF..=OP_DIFF_END, rest @ ..] => (rest, prev_pixel.apply_diff(*b1)),
-        [b1 @ OP_LUMA..=OP_LUMA_END, b2, rest @ ..] => (rest, prev_pixel.apply_luma(*b1, *b2)),
-        [b1 @ OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END, rest @ ..] => {
-            (rest, prev_pixel.apply_alpha_diff(*b1))
-        }
+// reads and decompresses the next chunk, returns None after the last chunk
+fn read_chunk<'a, 'b>(
+    mut data: Buffer<'a>,
+    out_chunk: &'b mut [u8],
+    header: &FileHeader,
+) -> Result<(Buffer<'a>, Option<Chunk<'b>>), KoiDecodeError> {
+    if data.is_empty() {
+        return Ok((data, None));
+    }
+
+    let len: u32;
+    let pixels: u32;
+    (len, data) = data.read_u32_le().ok_or(KoiDecodeError::TruncatedChunk)?;
+    (pixels, data) = data.read_u32_le().ok_or(KoiDecodeError::TruncatedChunk)?;
+
+    if len == 0 {
+        return Ok((data, None));
+    }
+
+    if unlikely(len as usize > MAX_COMPRESSED_CHUNK_SIZE) {
+        return Err(KoiDecodeError::InvalidChunkLength);
+    }
+    if unlikely(len as usize > data.len()) {
+        return Err(KoiDecodeError::TruncatedChunk);
+    }
+    check_chunk_pixels(header, pixels)?;
+
+    let decompress_size = decompress(&data[..len as usize], out_chunk, header.compression)?;
+    data = data.advance(len as usize);
+
+    Ok((data, Some((pixels, &out_chunk[..decompress_size]))))
+}
 
-        [opcode, ..] => {
-            cold();
-            panic!("Invalid opcode {}", opcode);
+// reads the compressed length and pixel count of the next chunk, None at the end of the input
+#[cfg(feature = "std")]
+fn read_chunk_header<R: Read>(reader: &mut R) -> Result<Option<(u32, u32)>, KoiDecodeError> {
+    let mut bytes = [0; 8];
+    let mut read = 0;
+    while read < bytes.len() {
+        match reader.read(&mut bytes[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
         }
-        _ => {
-            cold();
-            panic!("Invalid opcode");
+    }
+
+    match read {
+        0 => Ok(None),
+        8 => Ok(Some((
+            u32::from_le_bytes(bytes[..4].try_into().expect("4 bytes")),
+            u32::from_le_bytes(bytes[4..].try_into().expect("4 bytes")),
+        ))),
+        _ => Err(KoiDecodeError::TruncatedChunk),
+    }
+}
+
+// row-aligned chunks always contain whole rows, at most `rows_per_chunk` of them
+fn check_chunk_pixels(header: &FileHeader, pixels: u32) -> Result<(), KoiDecodeError> {
+    if let Some(rows) = header.rows_per_chunk {
+        let row_pixels = header.width.max(1);
+        let pixels = pixels as u64;
+        if unlikely(!pixels.is_multiple_of(row_pixels) || pixels > rows as u64 * row_pixels) {
+            return Err(KoiDecodeError::InvalidChunkLength);
         }
     }
+
+    Ok(())
 }
 
 #[allow(clippy::all)] // clippy is making the code slower
@@ -131,21 +555,23 @@ fn decompress(
 ) -> Result<usize, KoiDecodeError> {
     match compression {
         Compression::None => {
+            if unlikely(data.len() > out.len()) {
+                return Err(KoiDecodeError::InvalidChunkLength);
+            }
             out[..data.len()].copy_from_slice(data);
             Ok(data.len())
         }
+        // lzzz is slightly faster than lz4_flex, but not portable
+        #[cfg(feature = "lzzzz")]
         Compression::Lz4 => {
-            // let len = lz4_flex::block::decompress_into(&data, &mut out).map_err(|e| {
-            //     println!("error: {}", e);
-            //     KoiDecodeError::Decompress(e.to_string())
-            // })?;
-            // Ok(len)
-
-            // lzzz is slightly faster than lz4_flex, but not portable
-            let len = lzzzz::lz4::decompress(&data, &mut out).map_err(|e| {
-                println!("error: {}", e);
-                KoiDecodeError::Decompress(e.to_string())
-            })?;
+            let len = lzzzz::lz4::decompress(&data, &mut out)
+                .map_err(|e| KoiDecodeError::Decompress(e.to_string()))?;
+            Ok(len)
+        }
+        #[cfg(not(feature = "lzzzz"))]
+        Compression::Lz4 => {
+            let len = lz4_flex::block::decompress_into(&data, &mut out)
+                .map_err(|e| KoiDecodeError::Decompress(e.to_string()))?;
             Ok(len)
         }
     }
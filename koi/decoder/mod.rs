@@ -1,4 +1,165 @@
+#[cfg(feature = "std")]
 mod reader;
+#[cfg(feature = "std")]
 mod stream;
+#[cfg(feature = "std")]
 pub use stream::*;
 pub mod block;
+
+use alloc::vec::Vec;
+
+use crate::{
+    file::{FileHeader, Layout},
+    types::{BufferFormat, Channels, Compression},
+    KoiDecodeError,
+};
+
+// an image decoded with the channels it was stored with (see `koi::decode_dynamic`), every pixel
+// takes `bytes_per_pixel()` bytes of `data`
+pub struct DecodedImage {
+    pub header: FileHeader,
+    pub data: Vec<u8>,
+}
+
+impl DecodedImage {
+    pub fn channels(&self) -> Channels {
+        self.header.channels
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.header.channels.count() * self.header.bytes_per_sample()
+    }
+}
+
+// what `koi::probe` found out about an image from its header alone
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub width: u64,
+    pub height: u64,
+    pub channels: Channels,
+    pub bit_depth: u8,
+    pub compression: Compression,
+    pub layout: Layout,
+    pub has_exif: bool,
+    pub header_size: usize, // the pixel data starts at this offset
+    pub header: FileHeader,
+}
+
+impl ImageInfo {
+    // exact size of the decoded pixels in bytes, i.e. of the buffer `decoder::block::decode` needs
+    // and of the pixels returned by `koi::decode_to_vec` with the image's own channels, None if it
+    // doesn't fit into usize
+    pub fn data_size(&self) -> Option<usize> {
+        self.header.data_size()
+    }
+
+    // exact size of the buffer `decoder::block::decode_with_format` needs for `format`
+    pub fn output_size(&self, format: &BufferFormat) -> Option<usize> {
+        if !format.layout.supports(self.channels.count()) {
+            return None;
+        }
+        format.buffer_size(&self.header)
+    }
+}
+
+// options for `koi::decode_to_vec_with_options` and `koi::decode_with_options`
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    // rotates or flips the decoded pixels according to the header's orientation, the returned
+    // header then describes the transformed image
+    pub apply_orientation: bool,
+
+    // decodes gray, gray + alpha and RGB images requested with more channels as the color model of
+    // the requested channel count (e.g. RGBA for 4 channels), and rejects images with another
+    // color model (e.g. CMYK for 4 channels), see `channels.rs`
+    pub expand_channels: bool,
+
+    pub limits: DecodeLimits,
+}
+
+// resource limits for untrusted input, checked before anything is allocated based on the header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_width: u64,
+    pub max_height: u64,
+    pub max_pixels: u64,
+    pub max_header_size: usize,   // size of the BSON encoded header
+    pub max_metadata_size: usize, // size of the exif data
+    pub max_alloc: usize,         // size of the buffer allocated for the decoded pixels
+}
+
+// the limit exceeded by an image (see KoiDecodeError::LimitExceeded)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Width,
+    Height,
+    Pixels,
+    HeaderSize,
+    MetadataSize,
+    Alloc,
+}
+
+impl core::fmt::Display for Limit {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Limit::Width => "width",
+            Limit::Height => "height",
+            Limit::Pixels => "pixel count",
+            Limit::HeaderSize => "header size",
+            Limit::MetadataSize => "metadata size",
+            Limit::Alloc => "allocation size",
+        })
+    }
+}
+
+impl Default for DecodeLimits {
+    // generous limits that still keep headers from requesting arbitrary amounts of memory
+    fn default() -> Self {
+        Self {
+            max_width: 1 << 20,
+            max_height: 1 << 20,
+            max_pixels: 1 << 34,
+            max_header_size: 16 << 20,
+            max_metadata_size: 16 << 20,
+            max_alloc: 4 << 30,
+        }
+    }
+}
+
+impl DecodeLimits {
+    // no limits at all, only for trusted input
+    pub fn none() -> Self {
+        Self {
+            max_width: u64::MAX,
+            max_height: u64::MAX,
+            max_pixels: u64::MAX,
+            max_header_size: usize::MAX,
+            max_metadata_size: usize::MAX,
+            max_alloc: usize::MAX,
+        }
+    }
+
+    pub(crate) fn check(limit: Limit, value: u64, max: u64) -> Result<(), KoiDecodeError> {
+        if value > max {
+            return Err(KoiDecodeError::LimitExceeded { limit, value, max });
+        }
+
+        Ok(())
+    }
+
+    // checks the dimensions of an image
+    pub(crate) fn check_header(&self, header: &FileHeader) -> Result<(), KoiDecodeError> {
+        Self::check(Limit::Width, header.width, self.max_width)?;
+        Self::check(Limit::Height, header.height, self.max_height)?;
+        Self::check(
+            Limit::Pixels,
+            header.width.saturating_mul(header.height),
+            self.max_pixels,
+        )
+    }
+
+    // checks the size of a buffer before allocating it
+    pub(crate) fn check_alloc(&self, size: usize) -> Result<(), KoiDecodeError> {
+        Self::check(Limit::Alloc, size as u64, self.max_alloc as u64)
+    }
+}
@@ -3,15 +3,29 @@ use std::io::{self, BufReader, Read, Write};
 
 use super::reader::Reader;
 use crate::{
+    ops::{decode_px, OpError, MAX_OP_LEN},
     types::*,
-    util::{cold, likely, unlikely},
+    util::likely,
+    KoiDecodeError,
 };
 
+// size of the internal buffer used to read ops from the underlying reader
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// PixelDecoder is a stream decoder that decodes pixels one by one
+// - Reader is a wrapper around the underlying reader that can be either a lz4 decoder or a regular reader
+// - C is the number of channels in the image
 pub struct PixelDecoder<R: Read, const C: usize> {
     read_decoder: Reader<R>,
     last_px: Pixel<C>,
+    reset_alpha: bool,   // ops of files before version 2
     pixels_in: usize,    // pixels decoded so far
     pixels_count: usize, // total number of pixels in the image
+
+    buffer: Vec<u8>,   // ops read from read_decoder but not decoded yet
+    buffer_pos: usize, // position of the next op in buffer
+    consumed: usize,   // ops dropped from the front of buffer so far
+    end_checked: bool, // whether the end of image marker has been read
 }
 
 impl<R: Read, const C: usize> PixelDecoder<R, C> {
@@ -19,8 +33,14 @@ impl<R: Read, const C: usize> PixelDecoder<R, C> {
         Self {
             read_decoder: data,
             last_px: Pixel::default(),
+            reset_alpha: false,
             pixels_in: 0,
             pixels_count,
+
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            buffer_pos: 0,
+            consumed: 0,
+            end_checked: false,
         }
     }
 
@@ -32,6 +52,12 @@ impl<R: Read, const C: usize> PixelDecoder<R, C> {
         Self::new(Reader::UncompressedDecoder(data), pixels_count)
     }
 
+    // decodes the ops of files before version 2 (see `FileHeader::ops_reset_alpha`)
+    pub fn with_reset_alpha(mut self, reset_alpha: bool) -> Self {
+        self.reset_alpha = reset_alpha;
+        self
+    }
+
     // take a writer and decode the image into it
     pub fn decode<W: Write>(&mut self, mut writer: W) -> std::io::Result<u64> {
         io::copy(self, &mut writer)
@@ -42,247 +68,103 @@ impl<R: Read, const C: usize> PixelDecoder<R, C> {
         io::copy(&mut BufReader::new(self), &mut writer)
     }
 
-    #[allow(dead_code)]
-    fn handle_end_of_image(&mut self) -> std::io::Result<()> {
-        let mut padding = [0; 8];
-        self.read_decoder.read_exact(&mut padding)?;
-
-        // last 4 bytes should be END_OF_IMAGE
-        if padding[..] != END_OF_IMAGE[..] {
-            println!("padding: {:?}", padding);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid end of image",
-            ));
+    // makes sure at least `n` bytes are available in the buffer (unless the reader is exhausted)
+    fn fill_buffer(&mut self, n: usize) -> std::io::Result<()> {
+        if likely(self.buffer.len() - self.buffer_pos >= n) {
+            return Ok(());
         }
 
-        Ok(())
-    }
-
-    // #[inline]
-    // // somehow the faster version sometimes has issues with the hash function
-    // fn decode_pixels(&mut self, buf: &mut [u8], count: usize) -> std::io::Result<usize> {
-    //     let mut pixels_read = 0;
-
-    //     if self.pixels_in >= self.pixels_count {
-    //         self.handle_end_of_image()?;
-    //         return Ok(0);
-    //     }
-
-    //     let count = std::cmp::min(count, self.pixels_count - self.pixels_in);
-    //     for i in 0..count {
-    //         let [b1] = self.read_decoder.read_bytes::<1>()?;
-    //         let buffer_offset = i * C;
-
-    //         let pixel: Pixel<C> = match b1 {
-    //             OP_INDEX..=OP_INDEX_END => {
-    //                 self.last_px = self.cache[b1 as usize];
-    //                 buf[buffer_offset..buffer_offset + C].copy_from_slice(&self.last_px.data);
-    //                 self.pixels_in += 1;
-    //                 pixels_read += 1;
-    //                 continue;
-    //             }
-    //             OP_GRAY => {
-    //                 let [b2] = self.read_decoder.read_bytes::<1>()?;
-    //                 Pixel::from_grayscale(b2)
-    //             }
-    //             OP_GRAY_ALPHA => {
-    //                 if unlikely(C < Channels::GrayAlpha as u8 as usize) {
-    //                     return Err(std::io::Error::new(
-    //                         std::io::ErrorKind::InvalidData,
-    //                         format!("Invalid opcode GRAY_ALPHA for {} channels", C),
-    //                     ));
-    //                 }
-
-    //                 let [b2, b3] = self.read_decoder.read_bytes::<2>()?;
-    //                 Pixel::from([b2, b2, b2, b3])
-    //             }
-    //             OP_RGB => {
-    //                 let [b2, b3, b4] = self.read_decoder.read_bytes::<3>()?;
-    //                 Pixel::from([b2, b3, b4])
-    //             }
-    //             OP_RGBA => {
-    //                 if unlikely(C < Channels::Rgba as u8 as usize) {
-    //                     return Err(std::io::Error::new(
-    //                         std::io::ErrorKind::InvalidData,
-    //                         format!("Invalid opcode RGBA for {} channels", C),
-    //                     ));
-    //                 }
-    //                 Pixel::from(self.read_decoder.read_bytes::<4>()?)
-    //             }
-    //             OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END => self.last_px.apply_alpha_diff(b1),
-    //             OP_DIFF..=OP_DIFF_END => self.last_px.apply_diff(b1),
-    //             OP_LUMA..=OP_LUMA_END => {
-    //                 let [b2] = self.read_decoder.read_bytes::<1>()?;
-    //                 self.last_px.apply_luma(b1, b2)
-    //             }
-    //         };
+        self.buffer.drain(..self.buffer_pos);
+        self.consumed += self.buffer_pos;
+        self.buffer_pos = 0;
 
-    //         buf[buffer_offset..buffer_offset + C].copy_from_slice(&pixel.data);
-    //         self.pixels_in += 1;
-    //         self.last_px = pixel;
-    //         self.cache[pixel.hash() as usize] = pixel;
-    //         pixels_read += 1;
-    //     }
+        while self.buffer.len() < n {
+            let len = self.buffer.len();
+            self.buffer.resize(BUFFER_SIZE.max(n), 0);
 
-    //     Ok(pixels_read * C)
-    // }
+            // the lz4 frame decoder fails on truncated frames instead of returning fewer bytes
+            let read = match self.read_decoder.read(&mut self.buffer[len..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => 0,
+                Err(e) => return Err(e),
+            };
+            self.buffer.truncate(len + read);
 
-    #[inline]
-    fn read_pixels_fast(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let mut pixels_read = 0;
-
-        let mut buffer = vec![0u8; buf.len() / 4]; // max possible compression ratio is 4:1 (without factoring in lz4 compression on top of that)
-        let mut buffer_pos = 0;
-        let mut buffer_len = self.read_decoder.read(&mut buffer)?;
-
-        if buffer_len == 0 || self.pixels_in >= self.pixels_count {
-            return Ok(0);
-        }
-
-        let mut buffer_empty = false;
-
-        if self.pixels_in >= self.pixels_count {
-            self.handle_end_of_image()?;
-        }
-
-        while likely(buffer_pos < buffer_len && self.pixels_in < self.pixels_count && !buffer_empty)
-        {
-            let buffer_offset = pixels_read * C;
-            let required_bytes = Self::get_required_bytes(buffer[buffer_pos]);
-            let available_bytes = buffer_len - 1 - buffer_pos;
-
-            if unlikely(required_bytes > available_bytes) {
-                buffer_empty = true;
-                // read "required_bytes" bytes from read_decoder
-                let mut new_bytes = [0u8; 4];
-                let read_count = required_bytes - available_bytes;
-                self.read_decoder.read_exact(&mut new_bytes[..read_count])?;
-
-                // set buffer to current_bytes + new_bytes
-                buffer = buffer[buffer_pos..].to_vec();
-                buffer.extend_from_slice(&new_bytes[..read_count]);
-
-                buffer_len = buffer.len();
-                buffer_pos = 0;
+            if read == 0 {
+                break;
             }
-
-            buffer_pos =
-                self.read_pixel(&buffer, buffer_pos, buf, buffer_offset, required_bytes)?;
-            pixels_read += 1;
         }
 
-        Ok(pixels_read * C)
+        Ok(())
     }
 
-    fn get_required_bytes(opcode: u8) -> usize {
-        match opcode {
-            // OP_INDEX..=OP_INDEX_END => 0,
-            OP_GRAY => 1,
-            OP_GRAY_ALPHA => 2,
-            OP_RGB => 3,
-            OP_RGBA => 4,
-            OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END => 0,
-            OP_DIFF..=OP_DIFF_END => 0,
-            OP_LUMA..=OP_LUMA_END => 1,
-            _ => {
-                cold();
-                panic!("Invalid opcode {}", opcode)
-            }
+    fn handle_end_of_image(&mut self) -> std::io::Result<()> {
+        self.fill_buffer(END_OF_IMAGE.len())?;
+        let end = &self.buffer[self.buffer_pos..];
+
+        if end.len() < END_OF_IMAGE.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                KoiDecodeError::TruncatedChunk,
+            ));
+        }
+        if end[..END_OF_IMAGE.len()] != END_OF_IMAGE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                KoiDecodeError::InvalidEndOfImage,
+            ));
         }
+
+        self.buffer_pos += END_OF_IMAGE.len();
+        self.end_checked = true;
+        Ok(())
     }
 
-    // returns the number of bytes read from the input buffer, always writes C bytes to the output buffer
+    // decodes as many pixels as fit into buf, returns the number of bytes written
     #[inline]
-    fn read_pixel(
-        &mut self,
-        buf_in: &[u8],
-        buffer_in_pos: usize,
-        buf_out: &mut [u8],
-        buffer_offset: usize,
-        required_bytes: usize,
-    ) -> io::Result<usize> {
-        let b1 = buf_in[buffer_in_pos];
+    fn read_pixels(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut pixels_read = 0;
+        let pixels = (buf.len() / C).min(self.pixels_count - self.pixels_in);
 
-        let pixel: Pixel<C> = match b1 {
-            // OP_INDEX..=OP_INDEX_END => {
-            //     self.last_px = self.cache[b1 as usize];
-            //     buf_out[buffer_offset..buffer_offset + C].copy_from_slice(&self.last_px.data);
-            //     self.pixels_in += 1;
-            //     return Ok(buffer_in_pos + 1);
-            // }
-            OP_GRAY => {
-                let b2 = buf_in[buffer_in_pos + 1];
-                Pixel::from_grayscale(b2)
-            }
-            OP_GRAY_ALPHA => {
-                let b2 = buf_in[buffer_in_pos + 1];
-                let b3 = buf_in[buffer_in_pos + 2];
-                Pixel::from([b2, b2, b2, b3])
-            }
-            OP_RGB => {
-                let r = buf_in[buffer_in_pos + 1];
-                let g = buf_in[buffer_in_pos + 2];
-                let b = buf_in[buffer_in_pos + 3];
-                Pixel::from([r, g, b])
-            }
-            OP_RGBA => {
-                if unlikely(C < Channels::Rgba as u8 as usize) {
+        while likely(pixels_read < pixels) {
+            self.fill_buffer(MAX_OP_LEN)?;
+            let data = &self.buffer[self.buffer_pos..];
+
+            let (rest, px) = match decode_px::<C>(data, self.last_px, self.reset_alpha) {
+                Ok(decoded) => decoded,
+                Err(OpError::Truncated) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        KoiDecodeError::TruncatedChunk,
+                    ))
+                }
+                Err(e) => {
+                    let offset = self.consumed + self.buffer_pos;
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
-                        format!("Invalid opcode RGBA for {} channels", C),
+                        e.at(offset),
                     ));
                 }
-                buf_in[buffer_in_pos + 1..buffer_in_pos + 5].into()
-            }
-            OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END => self.last_px.apply_alpha_diff(b1),
-            OP_DIFF..=OP_DIFF_END => self.last_px.apply_diff(b1),
-            OP_LUMA..=OP_LUMA_END => {
-                let b2 = buf_in[buffer_in_pos + 1];
-                self.last_px.apply_luma(b1, b2)
-            }
-            _ => {
-                cold();
-                panic!("Invalid opcode {}", b1)
-            }
-        };
-
-        buf_out[buffer_offset..buffer_offset + C].copy_from_slice(&pixel.data);
-        self.pixels_in += 1;
-        self.last_px = pixel;
-
-        Ok(buffer_in_pos + required_bytes + 1)
-    }
-
-    pub fn read_all_pixels_buf(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<usize> {
-        let mut input_decoded = Vec::with_capacity(input.len());
-        self.read_decoder.read_to_end(&mut input_decoded)?;
+            };
+            self.buffer_pos = self.buffer.len() - rest.len();
 
-        let mut buffer_pos = 0;
-        while self.pixels_in < self.pixels_count {
-            let required_bytes = Self::get_required_bytes(input_decoded[buffer_pos]);
+            buf[pixels_read * C..(pixels_read + 1) * C].copy_from_slice(&px.data);
+            self.last_px = px;
+            pixels_read += 1;
+        }
 
-            buffer_pos = self.read_pixel(
-                &input_decoded,
-                buffer_pos,
-                output,
-                self.pixels_in * C,
-                required_bytes,
-            )?;
+        self.pixels_in += pixels_read;
+        if self.pixels_in == self.pixels_count && !self.end_checked {
+            self.handle_end_of_image()?;
         }
 
-        self.handle_end_of_image()?;
-        Ok(self.pixels_in * C + 8)
+        Ok(pixels_read * C)
     }
 }
 
 // implement read trait for Decoder
 impl<R: Read, const C: usize> Read for PixelDecoder<R, C> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.read_pixels_fast(buf)
-    }
-
-    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
-        self.read_pixels_fast(buf)
+        self.read_pixels(buf)
     }
 }
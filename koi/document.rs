@@ -0,0 +1,331 @@
+// The BSON document of the file header (see `file.rs`)
+//
+// With the "bson" feature the document is read and written by the bson crate. Otherwise a minimal
+// codec is used that only writes the element types the header needs (int32, int64, string, array
+// of int64 and generic binary data). When reading it skips any other type, so both produce and
+// accept the same headers.
+
+#[cfg(not(feature = "bson"))]
+pub(crate) use minimal::Document;
+#[cfg(feature = "bson")]
+pub(crate) use with_bson::Document;
+
+#[cfg(feature = "bson")]
+mod with_bson {
+    use alloc::vec::Vec;
+
+    use bson::{spec::BinarySubtype, Binary, Bson};
+
+    use crate::KoiEncodeError;
+
+    pub(crate) struct Document(bson::Document);
+
+    impl Document {
+        pub fn new() -> Self {
+            Self(bson::Document::new())
+        }
+
+        pub fn insert_i32(&mut self, key: &str, value: i32) {
+            self.0.insert(key, value);
+        }
+
+        pub fn insert_i64(&mut self, key: &str, value: i64) {
+            self.0.insert(key, value);
+        }
+
+        pub fn insert_str(&mut self, key: &str, value: &str) {
+            self.0.insert(key, value);
+        }
+
+        pub fn insert_i64_array(&mut self, key: &str, values: &[i64]) {
+            self.0.insert(key, values.to_vec());
+        }
+
+        pub fn insert_binary(&mut self, key: &str, bytes: &[u8]) {
+            let binary = Binary {
+                bytes: bytes.to_vec(),
+                subtype: BinarySubtype::Generic,
+            };
+            self.0.insert(key, binary);
+        }
+
+        pub fn write_to(&self, out: &mut Vec<u8>) -> Result<(), KoiEncodeError> {
+            Ok(self.0.to_writer(out)?)
+        }
+
+        // `bytes` is exactly one document
+        pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            bson::Document::from_reader(bytes).ok().map(Self)
+        }
+
+        pub fn get_i32(&self, key: &str) -> Option<i32> {
+            self.0.get_i32(key).ok()
+        }
+
+        pub fn get_i64(&self, key: &str) -> Option<i64> {
+            self.0.get_i64(key).ok()
+        }
+
+        pub fn get_str(&self, key: &str) -> Option<&str> {
+            self.0.get_str(key).ok()
+        }
+
+        pub fn get_binary(&self, key: &str) -> Option<&[u8]> {
+            self.0.get_binary_generic(key).ok().map(|b| &b[..])
+        }
+
+        // the elements of an array, None for elements that aren't int64
+        pub fn get_i64_array(&self, key: &str) -> Option<Vec<Option<i64>>> {
+            let array = self.0.get_array(key).ok()?;
+            Some(array.iter().map(Bson::as_i64).collect())
+        }
+    }
+}
+
+#[cfg(not(feature = "bson"))]
+mod minimal {
+    use alloc::{string::ToString, vec::Vec};
+
+    use crate::KoiEncodeError;
+
+    const DOUBLE: u8 = 0x01;
+    const STRING: u8 = 0x02;
+    const DOCUMENT: u8 = 0x03;
+    const ARRAY: u8 = 0x04;
+    const BINARY: u8 = 0x05;
+    const INT32: u8 = 0x10;
+    const INT64: u8 = 0x12;
+
+    const SUBTYPE_GENERIC: u8 = 0x00;
+
+    // nesting limit for skipped documents, which are validated recursively
+    const MAX_DEPTH: usize = 32;
+
+    // the encoded elements, without the document's size and terminator
+    pub(crate) struct Document(Vec<u8>);
+
+    impl Document {
+        pub fn new() -> Self {
+            Self(Vec::new())
+        }
+
+        fn insert(&mut self, element: u8, key: &str, value: &[u8]) {
+            self.0.push(element);
+            write_cstring(&mut self.0, key);
+            self.0.extend_from_slice(value);
+        }
+
+        pub fn insert_i32(&mut self, key: &str, value: i32) {
+            self.insert(INT32, key, &value.to_le_bytes());
+        }
+
+        pub fn insert_i64(&mut self, key: &str, value: i64) {
+            self.insert(INT64, key, &value.to_le_bytes());
+        }
+
+        pub fn insert_str(&mut self, key: &str, value: &str) {
+            let mut string = ((value.len() + 1) as i32).to_le_bytes().to_vec();
+            write_cstring(&mut string, value);
+            self.insert(STRING, key, &string);
+        }
+
+        // arrays are documents with the indices as keys
+        pub fn insert_i64_array(&mut self, key: &str, values: &[i64]) {
+            let mut array = Document::new();
+            for (i, value) in values.iter().enumerate() {
+                array.insert_i64(&i.to_string(), *value);
+            }
+
+            let mut bytes = Vec::new();
+            array
+                .write_to(&mut bytes)
+                .expect("arrays can always be written");
+            self.insert(ARRAY, key, &bytes);
+        }
+
+        pub fn insert_binary(&mut self, key: &str, bytes: &[u8]) {
+            let mut binary = (bytes.len() as i32).to_le_bytes().to_vec();
+            binary.push(SUBTYPE_GENERIC);
+            binary.extend_from_slice(bytes);
+            self.insert(BINARY, key, &binary);
+        }
+
+        pub fn write_to(&self, out: &mut Vec<u8>) -> Result<(), KoiEncodeError> {
+            let size = i32::try_from(self.0.len() + 5)
+                .map_err(|_| KoiEncodeError::InvalidHeader("header too large".to_string()))?;
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&self.0);
+            out.push(0);
+            Ok(())
+        }
+
+        // `bytes` is exactly one document, which is validated completely
+        pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+            validate(bytes, 0).map(|elements| Self(elements.to_vec()))
+        }
+
+        // the value of the last element with this key (like the bson crate, later keys win)
+        fn get(&self, key: &str) -> Option<(u8, &[u8])> {
+            let mut rest = &self.0[..];
+            let mut found = None;
+            while !rest.is_empty() {
+                let (element, name, value, next) = element(rest, 0)?;
+                if name == key.as_bytes() {
+                    found = Some((element, value));
+                }
+                rest = next;
+            }
+            found
+        }
+
+        pub fn get_i32(&self, key: &str) -> Option<i32> {
+            match self.get(key)? {
+                (INT32, value) => Some(i32::from_le_bytes(value.try_into().ok()?)),
+                _ => None,
+            }
+        }
+
+        pub fn get_i64(&self, key: &str) -> Option<i64> {
+            match self.get(key)? {
+                (INT64, value) => Some(i64::from_le_bytes(value.try_into().ok()?)),
+                _ => None,
+            }
+        }
+
+        pub fn get_str(&self, key: &str) -> Option<&str> {
+            match self.get(key)? {
+                (STRING, value) => core::str::from_utf8(&value[4..value.len() - 1]).ok(),
+                _ => None,
+            }
+        }
+
+        pub fn get_binary(&self, key: &str) -> Option<&[u8]> {
+            match self.get(key)? {
+                (BINARY, [_, _, _, _, SUBTYPE_GENERIC, bytes @ ..]) => Some(bytes),
+                _ => None,
+            }
+        }
+
+        // the elements of an array, None for elements that aren't int64
+        pub fn get_i64_array(&self, key: &str) -> Option<Vec<Option<i64>>> {
+            let (ARRAY, value) = self.get(key)? else {
+                return None;
+            };
+
+            let mut values = Vec::new();
+            let mut rest = elements(value)?;
+            while !rest.is_empty() {
+                let (element, _, value, next) = element(rest, 0)?;
+                values.push(match element {
+                    INT64 => Some(i64::from_le_bytes(value.try_into().ok()?)),
+                    _ => None,
+                });
+                rest = next;
+            }
+            Some(values)
+        }
+    }
+
+    fn write_cstring(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(s.as_bytes());
+        out.push(0);
+    }
+
+    fn read_i32(bytes: &[u8]) -> Option<i32> {
+        Some(i32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+    }
+
+    // splits off a null terminated string (without the terminator)
+    fn read_cstring(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+        let end = bytes.iter().position(|&b| b == 0)?;
+        Some((&bytes[..end], &bytes[end + 1..]))
+    }
+
+    // the elements of a document, whose size has to match `bytes`
+    fn elements(bytes: &[u8]) -> Option<&[u8]> {
+        let size = usize::try_from(read_i32(bytes)?).ok()?;
+        if size != bytes.len() || size < 5 || bytes[size - 1] != 0 {
+            return None;
+        }
+        Some(&bytes[4..size - 1])
+    }
+
+    // splits off the size prefixed value of a string or document
+    fn sized(bytes: &[u8], extra: usize) -> Option<usize> {
+        let size = usize::try_from(read_i32(bytes)?).ok()?;
+        let len = size.checked_add(extra)?;
+        (len <= bytes.len()).then_some(len)
+    }
+
+    // a string value (size, UTF-8 bytes, terminator), returns its length
+    fn string(bytes: &[u8]) -> Option<usize> {
+        let len = sized(bytes, 4)?;
+        match &bytes[4..len] {
+            [text @ .., 0] => core::str::from_utf8(text).ok().map(|_| len),
+            _ => None,
+        }
+    }
+
+    // the elements of a document after validating all of them
+    fn validate(bytes: &[u8], depth: usize) -> Option<&[u8]> {
+        let elements = elements(bytes)?;
+        let mut rest = elements;
+        while !rest.is_empty() {
+            (_, _, _, rest) = element(rest, depth)?;
+        }
+        Some(elements)
+    }
+
+    // a nested document, returns its length
+    fn document(bytes: &[u8], depth: usize) -> Option<usize> {
+        if depth == MAX_DEPTH {
+            return None;
+        }
+        let len = sized(bytes, 0)?;
+        validate(&bytes[..len], depth + 1).map(|_| len)
+    }
+
+    // type, key, value and the bytes after the element
+    type Element<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+    // splits off the first element, other types than the ones the header uses are validated and
+    // skipped
+    fn element(bytes: &[u8], depth: usize) -> Option<Element<'_>> {
+        let (&element, rest) = bytes.split_first()?;
+        let (key, rest) = read_cstring(rest)?;
+        core::str::from_utf8(key).ok()?;
+
+        let len = match element {
+            DOUBLE | 0x09 | 0x11 | INT64 => 8, // double, datetime, timestamp, int64
+            STRING | 0x0D | 0x0E => string(rest)?, // string, JavaScript code, symbol
+            DOCUMENT | ARRAY => document(rest, depth)?,
+            BINARY => sized(rest, 5)?,
+            0x06 | 0x0A | 0x7F | 0xFF => 0, // undefined, null, max key, min key
+            0x07 => 12,                     // ObjectId
+            0x08 => match rest.first()? {
+                0 | 1 => 1, // boolean
+                _ => return None,
+            },
+            0x0B => {
+                // regular expression (pattern and options)
+                let (pattern, after) = read_cstring(rest)?;
+                let (options, _) = read_cstring(after)?;
+                pattern.len() + options.len() + 2
+            }
+            0x0C => string(rest)?.checked_add(12)?, // DBPointer
+            0x0F => {
+                // JavaScript code with scope (size, code, scope document)
+                let len = sized(rest, 0)?;
+                let code = string(rest.get(4..len)?)?;
+                document(&rest[4 + code..len], depth).filter(|doc| 4 + code + doc == len)?;
+                len
+            }
+            INT32 => 4,
+            0x13 => 16, // decimal128
+            _ => return None,
+        };
+
+        let value = rest.get(..len)?;
+        Some((element, key, value, &rest[len..]))
+    }
+}
@@ -1,11 +1,20 @@
+use alloc::{format, string::ToString, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use super::EncodeOptions;
 use crate::{
-    file::FileHeader,
+    alpha,
+    cfa::Cfa,
+    channels,
+    file::{FileHeader, VERSION_BLOCK},
+    layout::{read_px, Frame, Rows},
+    ops::encode_px,
     types::*,
-    util::{BufferMut, Writer},
+    util::BufferMut,
     KoiEncodeError,
 };
 
-// has to be devisible by 1, 2, 3 and 4 so chunks_exact works properly
 const CHUNK_SIZE: usize = MAX_CHUNK_SIZE; // about 200kb
 
 pub fn encode_to_vec<const C: usize>(
@@ -13,70 +22,484 @@ pub fn encode_to_vec<const C: usize>(
     header: FileHeader,
     compression_level: CompressionLevel,
 ) -> Result<Vec<u8>, KoiEncodeError> {
-    let mut out = vec![0; header.width as usize * header.height as usize * C];
+    let mut out = vec![0; max_encoded_size::<C>(&header)?];
     let len = encode::<C>(data, &mut out, header, compression_level)?;
     out.truncate(len);
 
     Ok(out)
 }
 
+pub fn encode_to_vec_with_options<const C: usize>(
+    data: &[u8],
+    mut header: FileHeader,
+    compression_level: CompressionLevel,
+    options: &EncodeOptions,
+) -> Result<Vec<u8>, KoiEncodeError> {
+    if options.reduce_channels && header.channels.count() == C {
+        if let Some(data) = channels::reduce(data, &mut header) {
+            return match header.channels.count() {
+                1 => encode_to_vec::<1>(&data, header, compression_level),
+                2 => encode_to_vec::<2>(&data, header, compression_level),
+                _ => encode_to_vec::<3>(&data, header, compression_level),
+            };
+        }
+    }
+
+    encode_to_vec::<C>(data, header, compression_level)
+}
+
+// upper bound for the size of an encoded image, e.g. when images don't compress at all
+pub fn max_encoded_size<const C: usize>(header: &FileHeader) -> Result<usize, KoiEncodeError> {
+    let data_size = header.data_size().ok_or_else(too_large)?;
+    let chunk_size = chunk_size::<C>(header)?;
+    let chunks = data_size.div_ceil(chunk_size);
+
+    let max_chunk_size = compress_bound(chunk_size * 2);
+
+    chunks
+        .checked_mul(8 + max_chunk_size)
+        .and_then(|size| size.checked_add(header.write_to_vec().ok()?.len()))
+        .ok_or_else(too_large)
+}
+
+fn too_large() -> KoiEncodeError {
+    KoiEncodeError::InvalidHeader("image too large".to_string())
+}
+
+// encodes into `out` and returns the number of bytes written, fails with `InvalidLength` if `data`
+// doesn't contain exactly the pixels described by the header or if `out` is too small (buffers
+// of `max_encoded_size` bytes are always large enough)
 pub fn encode<const C: usize>(
     data: &[u8],
     out: &mut [u8],
     header: FileHeader,
     compression_level: CompressionLevel,
 ) -> Result<usize, KoiEncodeError> {
-    if header.version != 1 {
-        return Err(KoiEncodeError::UnsupportedVersion(header.version as u8));
+    encode_with_format::<C>(
+        data,
+        out,
+        header,
+        compression_level,
+        &BufferFormat::default(),
+    )
+}
+
+// encodes from a buffer with the given format, returns the number of bytes written
+pub fn encode_with_format<const C: usize>(
+    data: &[u8],
+    out: &mut [u8],
+    header: FileHeader,
+    compression_level: CompressionLevel,
+    format: &BufferFormat,
+) -> Result<usize, KoiEncodeError> {
+    let encoder = ChunkEncoder::<C>::new(&header, compression_level)?;
+    let frame = Frame::new(format, &header, C);
+    if !format.is_packed(frame.row_size()) {
+        return encode_formatted(encoder, data, out, header, format, frame);
+    }
+
+    encode_packed(encoder, data, out, header)
+}
+
+fn encode_packed<const C: usize>(
+    mut encoder: ChunkEncoder<C>,
+    data: &[u8],
+    out: &mut [u8],
+    header: FileHeader,
+) -> Result<usize, KoiEncodeError> {
+    if Some(data.len()) != header.data_size() {
+        return Err(KoiEncodeError::InvalidLength);
+    }
+
+    let chunk_size = chunk_size::<C>(&header)?;
+
+    let out_buf_cap = out.len();
+    let mut out_buf = BufferMut::new(out);
+    out_buf = header.write_to_buf(out_buf)?;
+
+    let mut out_chunk = [0; MAX_OPS_CHUNK_SIZE];
+
+    for start in (0..data.len()).step_by(chunk_size) {
+        let end = (start + chunk_size).min(data.len());
+        let len = encoder.encode_chunk(&data[..end], 0, start, &mut out_chunk, &mut out_buf)?;
+        out_buf = out_buf.advance(len);
+    }
+
+    Ok(out_buf_cap - out_buf.len())
+}
+
+fn encode_formatted<const C: usize>(
+    mut encoder: ChunkEncoder<C>,
+    data: &[u8],
+    out: &mut [u8],
+    header: FileHeader,
+    format: &BufferFormat,
+    frame: Frame,
+) -> Result<usize, KoiEncodeError> {
+    let layout = format.layout;
+    if !layout.supports(C) {
+        return Err(KoiEncodeError::UnsupportedLayout {
+            layout,
+            channels: C,
+        });
     }
 
-    if compression_level == CompressionLevel::None && header.compression != Compression::None {
-        return Err(KoiEncodeError::InvalidHeader(
-            "compression level is None but header.compression is not None".to_string(),
-        ));
+    if frame.row_stride < frame.row_size() {
+        return Err(KoiEncodeError::InvalidStride {
+            stride: frame.row_stride,
+            row_size: frame.row_size(),
+        });
     }
 
+    let pixels = header.pixel_count().ok_or_else(too_large)?;
+    if data.len() < frame.buffer_size().ok_or_else(too_large)? {
+        return Err(KoiEncodeError::InvalidLength);
+    }
+
+    let chunk_pixels = chunk_size::<C>(&header)? / (C * encoder.bytes_per_sample);
+
     let out_buf_cap = out.len();
     let mut out_buf = BufferMut::new(out);
     out_buf = header.write_to_buf(out_buf)?;
 
-    let mut prev_pixel = Pixel::default();
+    let mut out_chunk = [0; MAX_OPS_CHUNK_SIZE];
 
-    const OUT_CHUNK_LEN: usize = CHUNK_SIZE * 2;
-    let mut out_chunk = [0; OUT_CHUNK_LEN];
+    for start in (0..pixels).step_by(chunk_pixels) {
+        let end = (start + chunk_pixels).min(pixels);
+        let rows = frame.rows(start..end);
+        let len = encoder.encode_chunk_as(data, rows, layout, &mut out_chunk, &mut out_buf)?;
+        out_buf = out_buf.advance(len);
+    }
 
-    for chunk in data.chunks(CHUNK_SIZE) {
-        let mut out_chunk_buf = BufferMut::new(&mut out_chunk);
-        let pixel_count = chunk.len() / C;
+    Ok(out_buf_cap - out_buf.len())
+}
+
+// BlockEncoder encodes an image in the block layout while its pixels are written to it, only
+// keeping a single chunk in memory
+// - W is the writer the encoded image is written to
+// - C is the number of channels in the image
+#[cfg(feature = "std")]
+pub struct BlockEncoder<W: Write, const C: usize> {
+    writer: W,
+    encoder: ChunkEncoder<C>,
+    chunk_size: usize, // number of input bytes per chunk
 
-        for px in chunk.chunks_exact(C) {
-            let px: [u8; C] = unsafe { px.try_into().unwrap_unchecked() };
-            let curr_pixel = px.into();
-            out_chunk_buf = encode_px::<C>(curr_pixel, prev_pixel, out_chunk_buf);
-            prev_pixel = curr_pixel;
+    window: Vec<u8>, // pixels of the current chunk, preceded by the history needed for CFA data
+    window_offset: usize, // index of the first pixel in window
+    pending: usize,  // position of the first pixel in window that hasn't been encoded yet
+    remaining: usize, // number of bytes still expected
+
+    ops: Vec<u8>,
+    out: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write, const C: usize> BlockEncoder<W, C> {
+    // writes the header and prepares encoding the pixels
+    pub fn new(
+        mut writer: W,
+        header: FileHeader,
+        compression_level: CompressionLevel,
+    ) -> Result<Self, KoiEncodeError> {
+        let encoder = ChunkEncoder::<C>::new(&header, compression_level)?;
+        let chunk_size = chunk_size::<C>(&header)?;
+        let remaining = header.data_size().ok_or_else(too_large)?;
+
+        header.write(&mut writer)?;
+
+        Ok(Self {
+            writer,
+            encoder,
+            chunk_size,
+
+            window: Vec::with_capacity(chunk_size),
+            window_offset: 0,
+            pending: 0,
+            remaining,
+
+            ops: vec![0; MAX_OPS_CHUNK_SIZE],
+            out: vec![0; 8 + MAX_COMPRESSED_CHUNK_SIZE],
+        })
+    }
+
+    // encodes the last chunk and returns the writer, fails if not all pixels have been written
+    pub fn finish(mut self) -> Result<W, KoiEncodeError> {
+        if self.remaining != 0 {
+            return Err(KoiEncodeError::InvalidLength);
         }
 
-        let bytes_written = OUT_CHUNK_LEN - out_chunk_buf.len();
+        if self.pending < self.window.len() {
+            self.encode_chunk()?;
+        }
 
-        let compress_size = compress(
-            &out_chunk[..bytes_written],
-            &mut out_buf[8..],
-            compression_level, // diminishing returns after 4
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn encode_chunk(&mut self) -> Result<(), KoiEncodeError> {
+        let end = (self.pending + self.chunk_size).min(self.window.len());
+        let len = self.encoder.encode_chunk(
+            &self.window[..end],
+            self.window_offset,
+            self.pending,
+            &mut self.ops,
+            &mut self.out,
         )?;
+        self.writer.write_all(&self.out[..len])?;
+
+        // only keep the pixels that are needed to predict the next chunk
+        let bytes_per_pixel = C * self.encoder.bytes_per_sample;
+        let keep = match &self.encoder.cfa {
+            Some(cfa) => (cfa.history() * bytes_per_pixel).min(end),
+            None => 0,
+        };
+        self.window.drain(..end - keep);
+        self.window_offset += (end - keep) / bytes_per_pixel;
+        self.pending = keep;
 
-        if compress_size > ((MAX_CHUNK_SIZE * 3) / 2) {
-            panic!("compress_size > MAX_CHUNK_SIZE");
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write, const C: usize> Write for BlockEncoder<W, C> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let buf = &buf[..buf.len().min(self.remaining)];
+
+        let len = buf
+            .len()
+            .min(self.pending + self.chunk_size - self.window.len());
+        self.window.extend_from_slice(&buf[..len]);
+        self.remaining -= len;
+
+        if self.window.len() - self.pending == self.chunk_size {
+            self.encode_chunk()?;
         }
 
-        let bytes_length: &[u8; 4] = &(compress_size as u32).to_le_bytes();
-        let bytes_pixels: &[u8; 4] = &(pixel_count as u32).to_le_bytes();
+        Ok(len)
+    }
 
-        out_buf = out_buf.write_many(bytes_length);
-        out_buf = out_buf.write_many(bytes_pixels);
-        out_buf = out_buf.advance(compress_size);
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
     }
+}
 
-    Ok(out_buf_cap - out_buf.len())
+// encoding state carried from one chunk to the next
+struct ChunkEncoder<const C: usize> {
+    prev_pixel: Pixel<C>,
+    alpha_plane: bool,
+    cfa: Option<Cfa>,
+    bytes_per_sample: usize,
+    compression_level: CompressionLevel,
+
+    // alpha planes and CFA data are copied from other layouts here before they're encoded, for CFA
+    // data preceded by the history of the previous chunks
+    scratch: Vec<u8>,
+    scratch_offset: usize, // index of the first pixel in scratch
+}
+
+impl<const C: usize> ChunkEncoder<C> {
+    fn new(
+        header: &FileHeader,
+        compression_level: CompressionLevel,
+    ) -> Result<Self, KoiEncodeError> {
+        if header.version != VERSION_BLOCK {
+            return Err(KoiEncodeError::UnsupportedVersion(header.version as u8));
+        }
+
+        if header.channels.count() != C {
+            return Err(KoiEncodeError::ChannelMismatch {
+                image: header.channels.count(),
+                requested: C,
+            });
+        }
+
+        if compression_level == CompressionLevel::None && header.compression != Compression::None {
+            return Err(KoiEncodeError::InvalidHeader(
+                "compression level is None but header.compression is not None".to_string(),
+            ));
+        }
+
+        let alpha_plane = header.alpha_mode == AlphaMode::Plane;
+        if alpha_plane && !(header.channels.has_alpha() && matches!(C, 2 | 4 | 5)) {
+            return Err(KoiEncodeError::InvalidHeader(format!(
+                "alpha planes require an alpha channel, got {:?}",
+                header.channels
+            )));
+        }
+
+        let cfa = Cfa::new(header);
+        if cfa.is_some() && (C != 1 || header.channels != Channels::Gray) {
+            return Err(KoiEncodeError::InvalidHeader(format!(
+                "CFA data requires a single channel, got {:?}",
+                header.channels
+            )));
+        }
+
+        if header.bit_depth != 8 && (cfa.is_none() || header.bit_depth != 16) {
+            return Err(KoiEncodeError::InvalidHeader(format!(
+                "{} bits per sample are not supported",
+                header.bit_depth
+            )));
+        }
+
+        Ok(Self {
+            prev_pixel: Pixel::default(),
+            alpha_plane,
+            cfa,
+            bytes_per_sample: header.bytes_per_sample(),
+            compression_level,
+            scratch: Vec::new(),
+            scratch_offset: 0,
+        })
+    }
+
+    // encodes the chunk `data[start..]` into `out`, including the chunk header, and returns the
+    // number of bytes written
+    // - `data` starts at pixel `offset`, `data[..start]` contains the history needed for CFA data
+    // - `ops` is a scratch buffer for the uncompressed ops
+    fn encode_chunk(
+        &mut self,
+        data: &[u8],
+        offset: usize,
+        start: usize,
+        ops: &mut [u8],
+        out: &mut [u8],
+    ) -> Result<usize, KoiEncodeError> {
+        let bytes_per_pixel = C * self.bytes_per_sample;
+        let chunk = &data[start..];
+        let pixel_count = chunk.len() / bytes_per_pixel;
+
+        let ops_len = ops.len();
+        let mut ops_buf = BufferMut::new(ops);
+
+        if let Some(cfa) = &self.cfa {
+            let first = offset + start / bytes_per_pixel;
+            ops_buf = cfa.encode_chunk(data, offset, first..first + pixel_count, ops_buf);
+        } else if self.alpha_plane {
+            ops_buf = alpha::encode_chunk::<_, C>(chunk, &mut self.prev_pixel, ops_buf);
+        } else {
+            for px in chunk.chunks_exact(C) {
+                let px: [u8; C] = unsafe { px.try_into().unwrap_unchecked() };
+                let curr_pixel = px.into();
+                ops_buf = encode_px::<_, C>(curr_pixel, self.prev_pixel, ops_buf);
+                self.prev_pixel = curr_pixel;
+            }
+        }
+
+        let bytes_written = ops_len - ops_buf.len();
+        self.write_chunk(&ops[..bytes_written], pixel_count, out)
+    }
+
+    // encodes the pixels in `rows` of a buffer in another format (see BufferFormat), like
+    // `encode_chunk`
+    fn encode_chunk_as(
+        &mut self,
+        data: &[u8],
+        rows: Rows,
+        layout: PixelLayout,
+        ops: &mut [u8],
+        out: &mut [u8],
+    ) -> Result<usize, KoiEncodeError> {
+        let order = layout.order();
+        let bytes_per_pixel = layout.bytes_per_pixel(C);
+        let pixel_count = rows.pixels();
+
+        // predictions need the previous rows, so only they're kept instead of the whole image
+        if let Some(cfa) = &self.cfa {
+            let bytes_per_pixel = C * self.bytes_per_sample;
+            let mut window = core::mem::take(&mut self.scratch);
+            let keep = (cfa.history() * bytes_per_pixel).min(window.len());
+            let dropped = window.len() - keep;
+            window.drain(..dropped);
+            self.scratch_offset += dropped / bytes_per_pixel;
+
+            let start = window.len();
+            for row in rows {
+                window.extend_from_slice(&data[row]);
+            }
+            let result = self.encode_chunk(&window, self.scratch_offset, start, ops, out);
+            self.scratch = window;
+            return result;
+        }
+
+        let ops_len = ops.len();
+        let mut ops_buf = BufferMut::new(ops);
+
+        if self.alpha_plane {
+            self.scratch.clear();
+            for row in rows {
+                for px in data[row].chunks_exact(bytes_per_pixel) {
+                    self.scratch.extend_from_slice(&read_px::<C>(px, order));
+                }
+            }
+            ops_buf = alpha::encode_chunk::<_, C>(&self.scratch, &mut self.prev_pixel, ops_buf);
+        } else {
+            for row in rows {
+                for px in data[row].chunks_exact(bytes_per_pixel) {
+                    let curr_pixel = read_px::<C>(px, order).into();
+                    ops_buf = encode_px::<_, C>(curr_pixel, self.prev_pixel, ops_buf);
+                    self.prev_pixel = curr_pixel;
+                }
+            }
+        }
+
+        let bytes_written = ops_len - ops_buf.len();
+        self.write_chunk(&ops[..bytes_written], pixel_count, out)
+    }
+
+    // compresses the ops of a chunk into `out` after the chunk header
+    fn write_chunk(
+        &self,
+        ops: &[u8],
+        pixel_count: usize,
+        out: &mut [u8],
+    ) -> Result<usize, KoiEncodeError> {
+        // decoders reject larger chunks, so compressing fails instead of writing them
+        let end = out.len().min(8 + MAX_COMPRESSED_CHUNK_SIZE);
+        let Some(chunk) = out.get_mut(8..end) else {
+            return Err(KoiEncodeError::InvalidLength);
+        };
+        let compress_size = compress(
+            ops,
+            chunk,
+            self.compression_level, // diminishing returns after 4
+        )?;
+
+        out[..4].copy_from_slice(&(compress_size as u32).to_le_bytes());
+        out[4..8].copy_from_slice(&(pixel_count as u32).to_le_bytes());
+
+        Ok(8 + compress_size)
+    }
+}
+
+// number of input bytes per chunk, either a fixed size or a whole number of rows
+fn chunk_size<const C: usize>(header: &FileHeader) -> Result<usize, KoiEncodeError> {
+    let bytes_per_pixel = C * header.bytes_per_sample();
+    let Some(rows) = header.rows_per_chunk else {
+        // chunks can't end in the middle of a pixel
+        return Ok(CHUNK_SIZE / bytes_per_pixel * bytes_per_pixel);
+    };
+
+    let row_size = header.width.saturating_mul(bytes_per_pixel as u64);
+    if row_size > CHUNK_SIZE as u64 {
+        return Err(KoiEncodeError::InvalidHeader(format!(
+            "rows of {} bytes are larger than a chunk ({CHUNK_SIZE} bytes), chunks of whole rows \
+             require narrower images",
+            row_size
+        )));
+    }
+
+    let size = (rows as u64).saturating_mul(row_size);
+    if rows == 0 || size > CHUNK_SIZE as u64 {
+        return Err(KoiEncodeError::InvalidHeader(format!(
+            "{} rows of {} pixels don't fit into a single chunk",
+            rows, header.width
+        )));
+    }
+
+    // zero-width images have no pixels, so any non-zero chunk size works
+    Ok((size as usize).max(bytes_per_pixel))
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -94,87 +517,31 @@ pub fn compress(
     level: CompressionLevel,
 ) -> Result<usize, KoiEncodeError> {
     let out_size = match level {
-        CompressionLevel::Lz4(level) => {
-            lzzzz::lz4::compress(&input, &mut output, level).map_err(|e| {
-                println!("error: {}", e);
-                KoiEncodeError::InvalidLength
-            })?
-        }
+        #[cfg(feature = "lzzzz")]
+        CompressionLevel::Lz4(level) => lzzzz::lz4::compress(&input, &mut output, level)
+            .map_err(|_| KoiEncodeError::InvalidLength)?,
 
+        #[cfg(feature = "lzzzz")]
         CompressionLevel::Lz4Hc(level) => lzzzz::lz4_hc::compress(&input, &mut output, level)
-            .map_err(|e| {
-                println!("error: {}", e);
-                KoiEncodeError::InvalidLength
-            })?,
-
-        CompressionLevel::Lz4Flex => lz4_flex::compress_into(&input, &mut output).map_err(|e| {
-            println!("error: {}", e);
-            KoiEncodeError::InvalidLength
-        })?,
+            .map_err(|_| KoiEncodeError::InvalidLength)?,
+
+        // without the C library every level is compressed with lz4_flex
+        #[cfg(not(feature = "lzzzz"))]
+        CompressionLevel::Lz4(_) | CompressionLevel::Lz4Hc(_) => {
+            lz4_flex::compress_into(&input, &mut output)
+                .map_err(|_| KoiEncodeError::InvalidLength)?
+        }
+
+        CompressionLevel::Lz4Flex => lz4_flex::compress_into(&input, &mut output)
+            .map_err(|_| KoiEncodeError::InvalidLength)?,
         CompressionLevel::None => {
-            output[..input.len()].copy_from_slice(input);
+            output
+                .get_mut(..input.len())
+                .ok_or(KoiEncodeError::InvalidLength)?
+                .copy_from_slice(input);
             input.len()
         }
     };
 
     Ok(out_size)
 }
-
-#[allow(clippy::all)] // clippy is making the code slower
-fn encode_px<'a, const C: usize>(
-    curr_pixel: Pixel<C>,
-    prev_pixel: Pixel<C>,
-    buf: BufferMut<'a>,
-) -> BufferMut<'a> {
-    if curr_pixel == prev_pixel {
-        return buf.write_one(OP_SAME);
-    }
-
-    if (C == 2 || C == 4) && curr_pixel.rgb() == prev_pixel.rgb() {
-        if let Some(diff) = prev_pixel.alpha_diff(&curr_pixel) {
-            return buf.write_one(diff);
-        }
-    }
-
-    let is_transparent = curr_pixel.a() != 255;
-    if C == 4 && curr_pixel.a() != prev_pixel.a() && is_transparent {
-        // RGBA encoding (whenever alpha channel changes and rgb is not the same)
-        return buf.write_many(&[
-            Op::Rgba as u8,
-            curr_pixel.r(),
-            curr_pixel.g(),
-            curr_pixel.b(),
-            curr_pixel.a(),
-        ]);
-    }
-
-    let is_gray = curr_pixel.is_gray();
-    if is_gray {
-        if C == 2 && is_transparent {
-            return buf.write_many(&[Op::GrayAlpha as u8, curr_pixel.r(), curr_pixel.a()]);
-        }
-
-        // Gray encoding
-        return buf.write_many(&[Op::Gray as u8, curr_pixel.r()]);
-    }
-
-    // Difference between current and previous pixel
-    let diff = curr_pixel.diff(&prev_pixel);
-
-    // Diff encoding
-    if let Some(diff) = diff.color() {
-        return buf.write_one(diff);
-    }
-
-    // Luma encoding
-    if let Some(luma) = diff.luma() {
-        return buf.write_many(&luma);
-    }
-
-    buf.write_many(&[
-        Op::Rgb as u8,
-        curr_pixel.r(),
-        curr_pixel.g(),
-        curr_pixel.b(),
-    ])
-}
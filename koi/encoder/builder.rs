@@ -0,0 +1,324 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{Cursor, Write};
+
+#[cfg(feature = "std")]
+use super::block::BlockEncoder;
+use super::{
+    block::{self, CompressionLevel},
+    EncodeOptions,
+};
+use crate::{
+    channels,
+    file::{FileHeader, Layout, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels, Compression, Orientation},
+    KoiEncodeError,
+};
+
+// Encoder builds the header of an image from typed settings, so the layout, the header's
+// compression and the compression level always agree
+// - the defaults are the block layout compressed with lz4_flex and 8 bits per sample
+// - the stream layout only distinguishes between compressed and uncompressed images, its
+//   compression level is ignored otherwise
+// - without the "std" feature only the block layout can be encoded
+#[derive(Clone, Debug)]
+pub struct Encoder {
+    width: u64,
+    height: u64,
+    channels: Channels,
+    layout: Layout,
+    level: CompressionLevel,
+    chunks: Chunks,
+    alpha_mode: AlphaMode,
+    cfa: Option<(CfaPattern, u8)>,
+    exif: Option<Vec<u8>>,
+    color_space: u32,
+    orientation: Orientation,
+    pixels_per_meter: Option<(u32, u32)>,
+    pixel_aspect_ratio: Option<(u32, u32)>,
+    options: EncodeOptions,
+}
+
+// how the block layout cuts the image into chunks
+#[derive(Clone, Copy, Debug)]
+enum Chunks {
+    Fixed,
+    Rows(u32),
+    RowAligned, // as many rows as fit into a chunk (see FileHeader::with_row_aligned_chunks)
+}
+
+impl Encoder {
+    pub fn new(width: u64, height: u64, channels: Channels) -> Self {
+        Self {
+            width,
+            height,
+            channels,
+            layout: Layout::Block,
+            level: CompressionLevel::Lz4Flex,
+            chunks: Chunks::Fixed,
+            alpha_mode: AlphaMode::Interleaved,
+            cfa: None,
+            exif: None,
+            color_space: 0,
+            orientation: Orientation::Normal,
+            pixels_per_meter: None,
+            pixel_aspect_ratio: None,
+            options: EncodeOptions::default(),
+        }
+    }
+
+    pub fn dimensions(mut self, width: u64, height: u64) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn channels(mut self, channels: Channels) -> Self {
+        self.channels = channels;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    // CompressionLevel::None stores the pixel ops uncompressed
+    pub fn level(mut self, level: CompressionLevel) -> Self {
+        self.level = level;
+        self
+    }
+
+    // chunks of exactly `rows` rows (block layout only)
+    pub fn rows_per_chunk(mut self, rows: u32) -> Self {
+        self.chunks = Chunks::Rows(rows);
+        self
+    }
+
+    // chunks of as many whole rows as fit into a chunk (block layout only), encoding fails with
+    // InvalidHeader if a single row is larger than a chunk (e.g. RGBA images wider than 49998 pixels)
+    pub fn row_aligned_chunks(mut self) -> Self {
+        self.chunks = Chunks::RowAligned;
+        self
+    }
+
+    pub fn alpha_mode(mut self, alpha_mode: AlphaMode) -> Self {
+        self.alpha_mode = alpha_mode;
+        self
+    }
+
+    // single-channel raw sensor data with 8 or 16 bits per sample (block layout only)
+    pub fn cfa(mut self, pattern: CfaPattern, bit_depth: u8) -> Self {
+        self.cfa = Some((pattern, bit_depth));
+        self
+    }
+
+    pub fn exif(mut self, exif: Vec<u8>) -> Self {
+        self.exif = Some(exif);
+        self
+    }
+
+    pub fn color_space(mut self, color_space: u32) -> Self {
+        self.color_space = color_space;
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> Self {
+        self.orientation = orientation;
+        self
+    }
+
+    pub fn pixels_per_meter(mut self, x: u32, y: u32) -> Self {
+        self.pixels_per_meter = Some((x, y));
+        self
+    }
+
+    pub fn pixel_aspect_ratio(mut self, numerator: u32, denominator: u32) -> Self {
+        self.pixel_aspect_ratio = Some((numerator, denominator));
+        self
+    }
+
+    // see EncodeOptions::reduce_channels
+    pub fn reduce_channels(mut self, reduce: bool) -> Self {
+        self.options.reduce_channels = reduce;
+        self
+    }
+
+    // the header the image is encoded with (before channels are reduced)
+    pub fn header(&self) -> FileHeader {
+        let (version, compression) = match (self.layout, self.level) {
+            (Layout::Stream, CompressionLevel::None) => (VERSION_STREAM, Compression::None),
+            (Layout::Stream, _) => (VERSION_STREAM, Compression::Lz4),
+            (Layout::Block, CompressionLevel::None) => (VERSION_BLOCK, Compression::None),
+            (Layout::Block, _) => (VERSION_BLOCK, Compression::Lz4),
+        };
+
+        let mut header = FileHeader::new(
+            version,
+            self.exif.clone(),
+            self.width,
+            self.height,
+            self.channels,
+            compression,
+            None,
+            Some(self.color_space),
+        );
+        header.alpha_mode = self.alpha_mode;
+        header.orientation = self.orientation;
+        header.pixels_per_meter = self.pixels_per_meter;
+        header.pixel_aspect_ratio = self.pixel_aspect_ratio;
+        if let Some((pattern, bit_depth)) = self.cfa {
+            header.cfa_pattern = Some(pattern);
+            header.bit_depth = bit_depth;
+        }
+
+        match self.chunks {
+            Chunks::Fixed => header,
+            Chunks::Rows(rows) => {
+                header.rows_per_chunk = Some(rows);
+                header
+            }
+            Chunks::RowAligned => header.with_row_aligned_chunks(),
+        }
+    }
+
+    // upper bound for the size of the encoded image (block layout only, the size of images in
+    // the stream layout isn't bounded in advance)
+    pub fn max_encoded_size<const C: usize>(&self) -> Result<usize, KoiEncodeError> {
+        let header = self.header();
+        match self.layout {
+            Layout::Block => block::max_encoded_size::<C>(&header),
+            Layout::Stream => Err(KoiEncodeError::UnsupportedVersion(VERSION_STREAM as u8)),
+        }
+    }
+
+    pub fn encode_to_vec<const C: usize>(&self, data: &[u8]) -> Result<Vec<u8>, KoiEncodeError> {
+        let mut header = self.header();
+        match self.reduce::<C>(data, &mut header) {
+            Some(data) => match header.channels.count() {
+                1 => self.encode_to_vec_as::<1>(&data, header),
+                2 => self.encode_to_vec_as::<2>(&data, header),
+                _ => self.encode_to_vec_as::<3>(&data, header),
+            },
+            None => self.encode_to_vec_as::<C>(data, header),
+        }
+    }
+
+    // encodes into `out` and returns the number of bytes written, `out` has to be at least
+    // `max_encoded_size` bytes for the block layout (compressing the last chunk may need more
+    // space than the chunk ends up taking)
+    pub fn encode_into<const C: usize>(
+        &self,
+        data: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, KoiEncodeError> {
+        let mut header = self.header();
+        match self.reduce::<C>(data, &mut header) {
+            Some(data) => match header.channels.count() {
+                1 => self.encode_into_as::<1>(&data, out, header),
+                2 => self.encode_into_as::<2>(&data, out, header),
+                _ => self.encode_into_as::<3>(&data, out, header),
+            },
+            None => self.encode_into_as::<C>(data, out, header),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub fn encode_to_writer<W: Write, const C: usize>(
+        &self,
+        data: &[u8],
+        writer: W,
+    ) -> Result<(), KoiEncodeError> {
+        let mut header = self.header();
+        match self.reduce::<C>(data, &mut header) {
+            Some(data) => match header.channels.count() {
+                1 => self.encode_to_writer_as::<W, 1>(&data, writer, header),
+                2 => self.encode_to_writer_as::<W, 2>(&data, writer, header),
+                _ => self.encode_to_writer_as::<W, 3>(&data, writer, header),
+            },
+            None => self.encode_to_writer_as::<W, C>(data, writer, header),
+        }
+    }
+
+    // the pixels with fewer channels if they can be reduced, `header` is updated accordingly
+    fn reduce<const C: usize>(&self, data: &[u8], header: &mut FileHeader) -> Option<Vec<u8>> {
+        if !self.options.reduce_channels || header.channels.count() != C {
+            return None;
+        }
+        channels::reduce(data, header)
+    }
+
+    fn encode_to_vec_as<const C: usize>(
+        &self,
+        data: &[u8],
+        header: FileHeader,
+    ) -> Result<Vec<u8>, KoiEncodeError> {
+        match self.layout {
+            Layout::Block => block::encode_to_vec::<C>(data, header, self.level),
+            #[cfg(feature = "std")]
+            Layout::Stream => {
+                let mut out = Vec::new();
+                encode_stream::<_, C>(data, &mut out, header)?;
+                Ok(out)
+            }
+            #[cfg(not(feature = "std"))]
+            Layout::Stream => Err(KoiEncodeError::UnsupportedVersion(VERSION_STREAM as u8)),
+        }
+    }
+
+    fn encode_into_as<const C: usize>(
+        &self,
+        data: &[u8],
+        out: &mut [u8],
+        header: FileHeader,
+    ) -> Result<usize, KoiEncodeError> {
+        match self.layout {
+            Layout::Block => {
+                if out.len() < block::max_encoded_size::<C>(&header)? {
+                    return Err(KoiEncodeError::InvalidLength);
+                }
+                block::encode::<C>(data, out, header, self.level)
+            }
+            #[cfg(feature = "std")]
+            Layout::Stream => {
+                let mut cursor = Cursor::new(out);
+                encode_stream::<_, C>(data, &mut cursor, header)?;
+                Ok(cursor.position() as usize)
+            }
+            #[cfg(not(feature = "std"))]
+            Layout::Stream => Err(KoiEncodeError::UnsupportedVersion(VERSION_STREAM as u8)),
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn encode_to_writer_as<W: Write, const C: usize>(
+        &self,
+        data: &[u8],
+        writer: W,
+        header: FileHeader,
+    ) -> Result<(), KoiEncodeError> {
+        match self.layout {
+            Layout::Block => {
+                let mut encoder = BlockEncoder::<W, C>::new(writer, header, self.level)?;
+                encoder.write_all(data)?;
+                encoder.finish()?;
+                Ok(())
+            }
+            Layout::Stream => encode_stream::<_, C>(data, writer, header),
+        }
+    }
+}
+
+// the stream encoder only notices missing pixels after it has written the others, so the length is
+// checked before anything is written
+#[cfg(feature = "std")]
+fn encode_stream<W: Write, const C: usize>(
+    data: &[u8],
+    writer: W,
+    header: FileHeader,
+) -> Result<(), KoiEncodeError> {
+    if header.data_size() != Some(data.len()) {
+        return Err(KoiEncodeError::InvalidLength);
+    }
+    crate::encode::<_, _, C>(header, data, writer)
+}
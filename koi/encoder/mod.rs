@@ -1,4 +1,19 @@
+mod builder;
+#[cfg(feature = "std")]
 mod stream;
+#[cfg(feature = "std")]
 mod writer;
+pub use builder::Encoder;
+#[cfg(feature = "std")]
 pub use stream::PixelEncoder;
 pub mod block;
+
+// options for `koi::encode_with_options` and `block::encode_to_vec_with_options`
+#[derive(Clone, Debug, Default)]
+pub struct EncodeOptions {
+    // stores gray, gray + alpha, RGB and RGBA images with fewer channels if their alpha channel is
+    // always opaque or their color is always gray (the header describes the stored channels),
+    // decoding them with the original number of channels and `DecodeOptions::expand_channels`
+    // expands them again
+    pub reduce_channels: bool,
+}
@@ -1,5 +1,9 @@
 use super::writer::Writer;
-use crate::types::{Channels, Op, Pixel, END_OF_IMAGE};
+use crate::{
+    ops::{encode_px, MAX_OP_LEN},
+    types::{Pixel, END_OF_IMAGE, MAX_CHANNELS},
+    util::BufferMut,
+};
 use lz4_flex::frame::FrameEncoder;
 use std::io::{self, Read, Write};
 
@@ -11,8 +15,9 @@ pub struct PixelEncoder<W: Write, const C: usize> {
     pixels_in: usize, // pixels encoded so far
     pixels_count: usize,
     prev_pixel: Pixel<C>,
+    finished: bool, // whether the end of image marker has been written
 
-    remainder: smallvec::SmallVec<[u8; 3]>,
+    remainder: smallvec::SmallVec<[u8; MAX_CHANNELS - 1]>, // bytes of an incomplete pixel
 }
 
 impl<W: Write, const C: usize> PixelEncoder<W, C> {
@@ -23,8 +28,9 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
             pixels_in: 0,
             pixels_count,
             prev_pixel: Pixel::default(),
+            finished: false,
 
-            remainder: smallvec::SmallVec::with_capacity(3),
+            remainder: smallvec::SmallVec::new(),
         }
     }
 
@@ -34,7 +40,6 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
         frame_info.block_size = lz4_flex::frame::BlockSize::Max64KB;
         frame_info.block_mode = lz4_flex::frame::BlockMode::Linked;
         frame_info.content_checksum = true;
-        frame_info.content_size = Some((pixels_count * C) as u64);
 
         Self::new(
             Writer::Lz4Encoder(FrameEncoder::with_frame_info(frame_info, writer)),
@@ -49,78 +54,31 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
     #[inline]
     fn encode_pixel(&mut self, curr_pixel: Pixel<C>, prev_pixel: Pixel<C>) -> std::io::Result<()> {
         self.pixels_in += 1;
-        // alpha diff encoding (whenever only alpha channel changes)
-        if (C == 2 || C == 4) && curr_pixel.rgb() == prev_pixel.rgb() {
-            if let Some(diff) = prev_pixel.alpha_diff(&curr_pixel) {
-                self.writer.write_one(diff)?;
-                return Ok(());
-            }
-        }
-
-        let is_gray = curr_pixel.is_gray();
-        if C != 1 && curr_pixel.a() != prev_pixel.a() && curr_pixel.a() != 255 {
-            if is_gray {
-                // Gray Alpha encoding (whenever alpha channel changes and pixel is gray)
-                self.writer
-                    .write_all(&[Op::GrayAlpha as u8, curr_pixel.r(), curr_pixel.a()])?;
-            } else {
-                if C != Channels::Rgba as u8 as usize {
-                    panic!("RGBA encoding is only supported for RGBA images");
-                }
-
-                // RGBA encoding (whenever alpha channel changes)
-                self.writer.write_all(&[
-                    Op::Rgba as u8,
-                    curr_pixel.r(),
-                    curr_pixel.g(),
-                    curr_pixel.b(),
-                    curr_pixel.a(),
-                ])?;
-            }
-
-            return Ok(());
-        }
-
-        // Difference between current and previous pixel
-        let diff = curr_pixel.diff(&prev_pixel);
-
-        // Diff encoding
-        if let Some(diff) = diff.color() {
-            self.writer.write_one(diff)?;
-            return Ok(());
-        }
-
-        if is_gray {
-            // Gray encoding
-            self.writer.write_one(Op::Gray as u8)?;
-            self.writer.write_one(curr_pixel.r())?;
-            return Ok(());
-        }
 
-        // Luma encoding (a little bit broken on fast_decode) TODO: fix
-        if let Some(luma) = diff.luma() {
-            self.writer.write_all(&luma)?;
-            return Ok(());
-        }
+        let mut op = [0; MAX_OP_LEN];
+        let rest = encode_px::<_, C>(curr_pixel, prev_pixel, BufferMut::new(&mut op));
+        let op_len = MAX_OP_LEN - rest.len();
 
-        // RGB encoding
-        self.writer.write_all(&[
-            Op::Rgb as u8,
-            curr_pixel.r(),
-            curr_pixel.g(),
-            curr_pixel.b(),
-        ])?;
-        Ok(())
+        self.writer.write_all(&op[..op_len])
     }
 
     // flushes the remaining pixels and writes the end of image marker, automatically called after N pixels are encoded
     pub fn finish(&mut self) -> std::io::Result<()> {
-        self.writer.write_all(&END_OF_IMAGE)
+        self.finished = true;
+        self.writer.write_all(&END_OF_IMAGE)?;
+        self.writer.finish()
     }
 
     // take a reader and encode it pixel by pixel
     pub fn encode<R: Read>(&mut self, mut reader: R) -> std::io::Result<u64> {
-        io::copy(&mut reader, self)
+        let len = io::copy(&mut reader, self)?;
+
+        // images without any pixels never call write
+        if self.pixels_in == self.pixels_count && !self.finished {
+            self.finish()?;
+        }
+
+        Ok(len)
     }
 
     #[inline]
@@ -132,7 +90,7 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
             self.prev_pixel = curr_pixel;
         }
 
-        if buf.len() % C != 0 {
+        if !buf.len().is_multiple_of(C) {
             // save the remainder for the next write
             self.remainder = buf[buf.len() - (buf.len() % C)..].into();
         }
@@ -144,6 +102,19 @@ impl<W: Write, const C: usize> PixelEncoder<W, C> {
 impl<W: Write, const C: usize> Write for PixelEncoder<W, C> {
     // Currently always buffers C bytes before encoding a pixel, this could be improved by only buffering the remaining bytes until the next pixel boundary is reached
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // ops after the end of image marker would make the file undecodable
+        let remaining = (self.pixels_count - self.pixels_in) * C - self.remainder.len();
+        if buf.len() > remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes written after the last of {} pixels",
+                    buf.len() - remaining,
+                    self.pixels_count
+                ),
+            ));
+        }
+
         // append the remainder from the previous write to the beginning of the buffer
         if !self.remainder.is_empty() {
             let mut new_buf = self.remainder.clone();
@@ -154,7 +125,7 @@ impl<W: Write, const C: usize> Write for PixelEncoder<W, C> {
             self.write_aligned(buf)?
         };
 
-        if self.pixels_in == self.pixels_count {
+        if self.pixels_in == self.pixels_count && !self.finished {
             self.finish()?;
         }
 
@@ -165,12 +136,10 @@ impl<W: Write, const C: usize> Write for PixelEncoder<W, C> {
         self.writer.flush()?;
 
         if !self.remainder.is_empty() {
-            println!("remainder buffer not empty, are the amount of channels correct?");
-            println!("remainder: {:?}", self.remainder);
-            Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "buffer not empty",
-            ))
+            Err(std::io::Error::other(format!(
+                "{} bytes of an incomplete pixel remain, is the channel count correct?",
+                self.remainder.len()
+            )))
         } else {
             Ok(())
         }
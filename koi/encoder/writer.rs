@@ -11,6 +11,14 @@ impl<W: Write> Writer<W> {
     pub fn write_one(&mut self, byte: u8) -> std::io::Result<()> {
         self.write_all(&[byte])
     }
+
+    // ends the lz4 frame, no more data can be written afterwards
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Writer::Lz4Encoder(ref mut encoder) => Ok(encoder.try_finish()?),
+            Writer::UncompressedEncoder(ref mut encoder) => encoder.flush(),
+        }
+    }
 }
 
 impl<W: Write> Write for Writer<W> {
@@ -1,11 +1,52 @@
+//! Koi files start with the `KOI ` magic number followed by a BSON encoded [`FileHeader`].
+//! The header's version (`v`) determines the layout of the pixel data that follows:
+//!
+//! - version 2 ([`Layout::Stream`]): a single stream of pixel ops, terminated by 4 zero bytes
+//!   (`END_OF_IMAGE`). If the header's compression is `Lz4`, the whole stream (including the
+//!   terminator) is wrapped in a single LZ4 frame. Written by [`crate::encode`].
+//! - version 3 ([`Layout::Block`]): a sequence of chunks, each starting with the compressed
+//!   length and the number of pixels in the chunk (both `u32` little endian), followed by the
+//!   chunk's pixel ops (a raw LZ4 block if the header's compression is `Lz4`). The image ends
+//!   after the last chunk or at a chunk with a length of 0. Written by [`crate::encoder::block`].
+//!
+//! Versions 0 (stream) and 1 (block) are the same layouts written by earlier encoders, whose
+//! OP_GRAY, OP_RGB and OP_DIFF set alpha to 255 instead of keeping the previous pixel's alpha
+//! (see `ops.rs`). They can still be decoded, but not written anymore.
+//!
+//! Block files with an alpha mode (`a`) of 1 ([`AlphaMode::Plane`]) store the alpha channel of
+//! every chunk as a separate plane after the ops for the color channels (see `alpha.rs`).
+//!
+//! Block files with a CFA pattern (`p`) contain single-channel raw sensor data with 8 or 16 bits
+//! per sample (`d`), stored as differences to predictions from same-color neighbors instead of
+//! pixel ops (see `cfa.rs`).
+//!
+//! Both layouts share the same pixel ops and can be read with [`crate::decode`] and
+//! [`crate::decode_to_vec`], which pick the right decoder based on the header.
+
+use alloc::{string::ToString, vec::Vec};
+#[cfg(feature = "std")]
 use std::io::{Read, Write};
 
 use crate::{
-    types::{Channels, Compression, MAGIC},
+    decoder::{DecodeLimits, Limit},
+    document::Document,
+    types::{AlphaMode, CfaPattern, Channels, Compression, Orientation, MAGIC, MAX_CHUNK_SIZE},
     util::{Buffer, BufferMut, Writer},
     KoiDecodeError, KoiEncodeError,
 };
-use bson::{Binary, Document};
+
+pub const VERSION_STREAM: u32 = 2;
+pub const VERSION_BLOCK: u32 = 3;
+
+// the layouts with the ops of earlier encoders, which are only decoded
+pub const VERSION_STREAM_V0: u32 = 0;
+pub const VERSION_BLOCK_V1: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Stream,
+    Block,
+}
 
 #[derive(Debug, Clone)]
 pub struct FileHeader {
@@ -19,21 +60,96 @@ pub struct FileHeader {
 
     // defaults to a dynamic value based on the image size if not specified (version >= 1)
     pub block_size: Option<u32>, // b
-}
 
-#[inline]
-fn to_binary(bytes: Vec<u8>) -> Binary {
-    Binary {
-        bytes,
-        subtype: bson::spec::BinarySubtype::Generic,
-    }
+    // if set, chunks are only cut at row boundaries and contain exactly this many rows (except the last one)
+    pub rows_per_chunk: Option<u32>, // r
+
+    pub alpha_mode: AlphaMode, // a
+
+    // Bayer pattern of single-channel raw sensor data
+    pub cfa_pattern: Option<CfaPattern>, // p
+
+    // bits per sample, 16 bits are only supported for CFA data
+    pub bit_depth: u8, // d
+
+    // how the image has to be rotated or flipped for display, the pixels are stored unchanged
+    pub orientation: Orientation, // o
+
+    // physical resolution (horizontal, vertical)
+    pub pixels_per_meter: Option<(u32, u32)>, // m
+
+    // width of a pixel relative to its height (numerator, denominator)
+    pub pixel_aspect_ratio: Option<(u32, u32)>, // q
 }
 
+const METERS_PER_INCH: f64 = 0.0254;
+
 impl FileHeader {
+    // the layout of the pixel data following the header, based on the file version
+    pub fn layout(&self) -> Result<Layout, KoiDecodeError> {
+        match self.version {
+            VERSION_STREAM | VERSION_STREAM_V0 => Ok(Layout::Stream),
+            VERSION_BLOCK | VERSION_BLOCK_V1 => Ok(Layout::Block),
+            v => Err(KoiDecodeError::UnsupportedVersion(v as u8)),
+        }
+    }
+
+    // whether OP_GRAY, OP_RGB and OP_DIFF set alpha to 255 instead of keeping the previous
+    // pixel's alpha, which is the case for files written before version 2
+    pub fn ops_reset_alpha(&self) -> bool {
+        matches!(self.version, VERSION_STREAM_V0 | VERSION_BLOCK_V1)
+    }
+
+    // size of the decoded pixels in bytes, saturating instead of overflowing (see `data_size`)
     pub fn min_output_size(&self) -> usize {
-        self.width as usize * self.height as usize * self.channels as usize * self.channels as usize
+        (self.width as usize)
+            .saturating_mul(self.height as usize)
+            .saturating_mul(self.channels.count())
+            .saturating_mul(self.bytes_per_sample())
+    }
+
+    // number of pixels, None if it doesn't fit into usize
+    pub fn pixel_count(&self) -> Option<usize> {
+        usize::try_from(self.width.checked_mul(self.height)?).ok()
+    }
+
+    // size of the decoded pixels in bytes, None if it doesn't fit into usize
+    pub fn data_size(&self) -> Option<usize> {
+        self.pixel_count()?
+            .checked_mul(self.channels.count() * self.bytes_per_sample())
+    }
+
+    pub fn bytes_per_sample(&self) -> usize {
+        self.bit_depth.div_ceil(8) as usize
+    }
+
+    // physical resolution in dots per inch (horizontal, vertical)
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.pixels_per_meter
+            .map(|(x, y)| (x as f64 * METERS_PER_INCH, y as f64 * METERS_PER_INCH))
+    }
+
+    pub fn set_dpi(&mut self, x: f64, y: f64) {
+        // rounded to the nearest integer (f64::round isn't available without std)
+        let ppm = |dpi: f64| (dpi / METERS_PER_INCH + 0.5) as u32;
+        self.pixels_per_meter = Some((ppm(x), ppm(y)));
+    }
+
+    // describes the first header setting that can't be used with the stream layout
+    #[cfg(feature = "std")]
+    pub(crate) fn block_only_feature(&self) -> Option<&'static str> {
+        if self.alpha_mode != AlphaMode::Interleaved {
+            return Some("alpha planes");
+        }
+
+        if self.cfa_pattern.is_some() || self.bit_depth != 8 {
+            return Some("CFA data");
+        }
+
+        None
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         version: u32,
         exif: Option<Vec<u8>>,
@@ -53,40 +169,102 @@ impl FileHeader {
             compression,
             block_size,
             color_space: color_space.unwrap_or(0),
+            rows_per_chunk: None,
+            alpha_mode: AlphaMode::Interleaved,
+            cfa_pattern: None,
+            bit_depth: 8,
+            orientation: Orientation::Normal,
+            pixels_per_meter: None,
+            pixel_aspect_ratio: None,
         }
     }
 
+    // enables row-aligned chunks, using the largest number of rows that still fits into a single chunk
+    // - a single row may not fit into a chunk either, the encoder then rejects the header
+    pub fn with_row_aligned_chunks(mut self) -> Self {
+        let row_size = usize::try_from(self.width)
+            .ok()
+            .and_then(|width| width.checked_mul(self.channels.count() * self.bytes_per_sample()));
+        let rows = match row_size {
+            Some(0) => MAX_CHUNK_SIZE,
+            Some(row_size) => MAX_CHUNK_SIZE / row_size,
+            None => 0,
+        };
+        self.rows_per_chunk = Some(rows.max(1) as u32);
+        self
+    }
+
     fn doc(&self) -> Document {
         let mut doc = Document::new();
-        doc.insert("v", self.version as i32);
-        doc.insert("w", self.width as i64);
-        doc.insert("h", self.height as i64);
-        doc.insert("c", self.channels as i32);
-        doc.insert("x", self.compression as i32);
-        doc.insert("s", self.color_space as i32);
+        doc.insert_i32("v", self.version as i32);
+        doc.insert_i64("w", self.width as i64);
+        doc.insert_i64("h", self.height as i64);
+        doc.insert_i32("c", self.channels.count() as i32);
+
+        // channel semantics are only stored if they differ from the default for the channel count
+        if Channels::try_from(self.channels.count() as u8) != Ok(self.channels) {
+            doc.insert_str("k", self.channels.name());
+        }
+        doc.insert_i32("x", self.compression as i32);
+        doc.insert_i32("s", self.color_space as i32);
+
+        // more rows never fit into a chunk (unless the rows are empty, which any number of them does)
+        if let Some(rows_per_chunk) = self.rows_per_chunk {
+            doc.insert_i32("r", rows_per_chunk.min(i32::MAX as u32) as i32);
+        }
+
+        if self.alpha_mode != AlphaMode::Interleaved {
+            doc.insert_i32("a", self.alpha_mode as i32);
+        }
+
+        if let Some(cfa_pattern) = self.cfa_pattern {
+            doc.insert_str("p", cfa_pattern.name());
+        }
+
+        if self.bit_depth != 8 {
+            doc.insert_i32("d", self.bit_depth as i32);
+        }
+
+        if self.orientation != Orientation::Normal {
+            doc.insert_i32("o", self.orientation as i32);
+        }
+
+        if let Some((x, y)) = self.pixels_per_meter {
+            doc.insert_i64_array("m", &[x as i64, y as i64]);
+        }
+
+        if let Some((num, den)) = self.pixel_aspect_ratio {
+            doc.insert_i64_array("q", &[num as i64, den as i64]);
+        }
 
         if let Some(exif) = &self.exif {
-            doc.insert("e", to_binary(exif.clone()));
+            doc.insert_binary("e", exif);
         }
 
         doc
     }
 
+    #[cfg(feature = "std")]
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), KoiEncodeError> {
-        writer.write_all(&MAGIC)?;
-        Ok(self.doc().to_writer(writer)?)
+        writer.write_all(&self.write_to_vec()?)?;
+        Ok(())
     }
 
     pub fn write_to_buf<'a>(&self, buf: BufferMut<'a>) -> Result<BufferMut<'a>, KoiEncodeError> {
-        Ok(buf.write_many(&self.write_to_vec()?))
+        let bytes = self.write_to_vec()?;
+        if bytes.len() > buf.len() {
+            return Err(KoiEncodeError::InvalidLength);
+        }
+        Ok(buf.write_many(&bytes))
     }
 
     pub fn write_to_vec(&self) -> Result<Vec<u8>, KoiEncodeError> {
-        let mut bytes = vec![];
-        self.write(&mut bytes)?;
+        let mut bytes = MAGIC.to_vec();
+        self.doc().write_to(&mut bytes)?;
         Ok(bytes)
     }
 
+    #[cfg(feature = "std")]
     pub fn check_magic(reader: &mut dyn Read) -> Result<(), KoiDecodeError> {
         let mut magic = [0u8; MAGIC.len()];
         reader.read_exact(&mut magic).map_err(|_| {
@@ -109,47 +287,181 @@ impl FileHeader {
     }
 
     pub fn read_bytes(bytes: &[u8]) -> Result<(usize, FileHeader), KoiDecodeError> {
-        let mut reader = std::io::Cursor::new(bytes);
-        FileHeader::read(&mut reader).map(|header| (reader.position() as usize, header))
+        FileHeader::read_bytes_with_limits(bytes, &DecodeLimits::default())
+    }
+
+    // returns the size of the header and the header, see `read_with_limits`
+    pub fn read_bytes_with_limits(
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<(usize, FileHeader), KoiDecodeError> {
+        match bytes.get(..MAGIC.len()) {
+            Some(magic) if magic == MAGIC => {}
+            Some(_) => return Err(err("Invalid magic number")(())),
+            None => return Err(err("Failed to read magic number")(())),
+        }
+        let bytes = &bytes[MAGIC.len()..];
+
+        let size = bytes
+            .first_chunk()
+            .ok_or_else(|| err("Failed to read file header")(()))?;
+        let size = doc_size(*size, limits)?;
+        let doc = bytes
+            .get(..size)
+            .ok_or_else(|| err("Failed to read file header")(()))?;
+
+        Ok((MAGIC.len() + size, FileHeader::from_doc(doc, limits)?))
     }
 
+    #[cfg(feature = "std")]
     pub fn read(reader: &mut dyn Read) -> Result<FileHeader, KoiDecodeError> {
+        FileHeader::read_with_limits(reader, &DecodeLimits::default())
+    }
+
+    // reads the header, the size of the BSON document and the metadata are checked against the
+    // limits before they are copied, dimensions are checked by the decoder
+    #[cfg(feature = "std")]
+    pub fn read_with_limits(
+        reader: &mut dyn Read,
+        limits: &DecodeLimits,
+    ) -> Result<FileHeader, KoiDecodeError> {
         FileHeader::check_magic(reader)?;
 
-        let doc = Document::from_reader(reader).map_err(err("Failed to read file header"))?;
+        let mut size = [0u8; 4];
+        reader
+            .read_exact(&mut size)
+            .map_err(err("Failed to read file header"))?;
+        let len = doc_size(size, limits)?;
+
+        let mut bytes = size.to_vec();
+        reader
+            .take(len as u64 - 4)
+            .read_to_end(&mut bytes)
+            .map_err(err("Failed to read file header"))?;
+
+        FileHeader::from_doc(&bytes, limits)
+    }
+
+    // parses the BSON document of the header
+    fn from_doc(bytes: &[u8], limits: &DecodeLimits) -> Result<FileHeader, KoiDecodeError> {
+        let doc =
+            Document::from_bytes(bytes).ok_or_else(|| err("Failed to read file header")(()))?;
+
+        if let Some(exif) = doc.get_binary("e") {
+            DecodeLimits::check(
+                Limit::MetadataSize,
+                exif.len() as u64,
+                limits.max_metadata_size as u64,
+            )?;
+        }
 
         let (version, exif, width, height, channels, compression, block_size, color_space) = (
             doc.get_i32("v")
-                .map_err(err("Failed to read file version"))? as u32,
-            doc.get_binary_generic("e").ok().map(|b| b.to_vec()),
-            doc.get_i64("w").map_err(err("Failed to read width"))? as u64,
-            doc.get_i64("h").map_err(err("Failed to read height"))? as u64,
-            doc.get_i32("c").map_err(err("Failed to read channels"))? as u32,
+                .ok_or_else(|| err("Failed to read file version")(()))? as u32,
+            doc.get_binary("e").map(|b| b.to_vec()),
+            doc.get_i64("w")
+                .ok_or_else(|| err("Failed to read width")(()))? as u64,
+            doc.get_i64("h")
+                .ok_or_else(|| err("Failed to read height")(()))? as u64,
+            doc.get_i32("c")
+                .ok_or_else(|| err("Failed to read channels")(()))? as u32,
             doc.get_i32("x")
-                .map_err(err("Failed to read compression"))? as u32,
-            doc.get_i32("b").ok().map(|b| b as u32),
-            doc.get_i32("s").ok().map(|b| b as u32),
+                .ok_or_else(|| err("Failed to read compression")(()))? as u32,
+            doc.get_i32("b").map(|b| b as u32),
+            doc.get_i32("s").map(|b| b as u32),
         );
 
+        let channel_count = u8::try_from(channels).map_err(err("Invalid channels"))?;
+        let channels = match doc.get_str("k") {
+            Some(name) => Channels::from_name(name, channel_count),
+            None => Channels::try_from(channel_count).ok(),
+        }
+        .ok_or_else(|| err("Invalid channels")(()))?;
+
         Ok(Self {
             version,
             exif,
             width,
             height,
-            channels: u8::try_from(channels)
-                .map_err(err("Invalid channels"))?
-                .try_into()
-                .map_err(err("Invalid channels"))?,
+            channels,
             compression: u8::try_from(compression)
                 .map_err(err("Invalid compression"))?
                 .try_into()
                 .map_err(err("Invalid compression"))?,
             block_size,
             color_space: color_space.unwrap_or(0),
+            rows_per_chunk: match doc.get_i32("r") {
+                Some(rows) => Some(
+                    u32::try_from(rows)
+                        .ok()
+                        .filter(|&rows| rows > 0)
+                        .ok_or_else(|| err("Invalid rows per chunk")(()))?,
+                ),
+                None => None,
+            },
+            alpha_mode: match doc.get_i32("a") {
+                Some(a) => u8::try_from(a)
+                    .map_err(err("Invalid alpha mode"))?
+                    .try_into()
+                    .map_err(err("Invalid alpha mode"))?,
+                None => AlphaMode::Interleaved,
+            },
+            cfa_pattern: match doc.get_str("p") {
+                Some(name) => Some(
+                    CfaPattern::from_name(name).ok_or_else(|| err("Invalid CFA pattern")(()))?,
+                ),
+                None => None,
+            },
+            bit_depth: match doc.get_i32("d") {
+                Some(d @ (8 | 16)) => d as u8,
+                Some(_) => return Err(err("Invalid bit depth")(())),
+                None => 8,
+            },
+            orientation: match doc.get_i32("o") {
+                Some(o) => u8::try_from(o)
+                    .map_err(err("Invalid orientation"))?
+                    .try_into()
+                    .map_err(err("Invalid orientation"))?,
+                None => Orientation::Normal,
+            },
+            pixels_per_meter: read_pair(&doc, "m", "Invalid pixels per meter")?,
+            pixel_aspect_ratio: read_pair(&doc, "q", "Invalid pixel aspect ratio")?,
         })
     }
 }
 
+// BSON documents start with their size (including the size itself)
+fn doc_size(size: [u8; 4], limits: &DecodeLimits) -> Result<usize, KoiDecodeError> {
+    let size = i32::from_le_bytes(size);
+    if size < 5 {
+        return Err(err("Invalid file header size")(()));
+    }
+    DecodeLimits::check(
+        Limit::HeaderSize,
+        size as u64,
+        limits.max_header_size as u64,
+    )?;
+    Ok(size as usize)
+}
+
+// reads an optional array of two non-negative integers
+fn read_pair(doc: &Document, key: &str, e: &str) -> Result<Option<(u32, u32)>, KoiDecodeError> {
+    let Some(array) = doc.get_i64_array(key) else {
+        return Ok(None);
+    };
+
+    let values: Vec<u32> = array
+        .into_iter()
+        .map(|v| v.and_then(|v| u32::try_from(v).ok()))
+        .collect::<Option<_>>()
+        .ok_or_else(|| err(e)(()))?;
+
+    match values[..] {
+        [a, b] => Ok(Some((a, b))),
+        _ => Err(err(e)(())),
+    }
+}
+
 fn err<F>(e: &str) -> impl FnOnce(F) -> KoiDecodeError + '_ {
     |_| KoiDecodeError::InvalidFileHeader(e.to_string())
 }
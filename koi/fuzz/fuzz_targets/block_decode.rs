@@ -0,0 +1,31 @@
+#![no_main]
+
+// decodes arbitrary bytes as an image in the block layout, the first byte selects the number of
+// channels the image is decoded with
+
+use koi::decoder::block;
+use libfuzzer_sys::fuzz_target;
+
+// keeps corrupted dimensions from exceeding the memory limit of the fuzzer
+const MAX_OUTPUT_SIZE: usize = 64 << 20;
+
+fn decode<const C: usize>(data: &[u8]) {
+    if block::min_output_size::<C>(data).is_ok_and(|size| size <= MAX_OUTPUT_SIZE) {
+        let _ = block::decode_to_vec::<C>(data);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&channels, data)) = data.split_first() else {
+        return;
+    };
+
+    match channels % 6 {
+        0 => decode::<1>(data),
+        1 => decode::<2>(data),
+        2 => decode::<3>(data),
+        3 => decode::<4>(data),
+        4 => decode::<5>(data),
+        _ => decode::<16>(data),
+    }
+});
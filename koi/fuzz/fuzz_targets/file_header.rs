@@ -0,0 +1,23 @@
+#![no_main]
+
+// parses arbitrary bytes as a file header, both from a reader and from a slice
+
+use koi::file::FileHeader;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let from_reader = FileHeader::read(&mut &data[..]);
+    let from_bytes = FileHeader::read_bytes(data);
+
+    if let (Ok(header), Ok((len, same))) = (from_reader, from_bytes) {
+        assert_eq!(format!("{header:?}"), format!("{same:?}"));
+        assert!(len <= data.len());
+
+        // whatever was accepted has to survive being written again
+        let mut written = Vec::new();
+        if header.write(&mut written).is_ok() {
+            let reread = FileHeader::read_bytes(&written).unwrap().1;
+            assert_eq!(format!("{reread:?}"), format!("{header:?}"));
+        }
+    }
+});
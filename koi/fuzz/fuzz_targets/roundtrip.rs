@@ -0,0 +1,119 @@
+#![no_main]
+
+// encodes arbitrary pixels with every channel count, layout, compression level, alpha mode and
+// CFA pattern and checks that decoding them gives back the same pixels
+
+use arbitrary::Arbitrary;
+use koi::{
+    encoder::block::CompressionLevel,
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels, Compression, MAX_CHANNELS},
+};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Mode {
+    Stream(bool), // lz4 compressed
+    Block(Level, Option<Layout>),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Level {
+    None,
+    Lz4Flex,
+    Lz4(i8),
+    Lz4Hc(i8),
+}
+
+// header fields that are only supported by the block layout
+#[derive(Arbitrary, Debug)]
+enum Layout {
+    AlphaPlane,
+    Cfa(u8, bool), // pattern (modulo 4), 16 bits per sample
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    channels: u8, // taken modulo MAX_CHANNELS
+    cmyk: bool,   // CMYK(A) instead of RGBA or 5 bands
+    width: u8,
+    rows_per_chunk: Option<u8>, // taken modulo 16, so that the rows always fit into a chunk
+    mode: Mode,
+    pixels: Vec<u8>,
+}
+
+fn channels(input: &Input) -> Channels {
+    let count = input.channels % MAX_CHANNELS as u8 + 1;
+    match (count, input.cmyk) {
+        (4, true) => Channels::Cmyk,
+        (5, true) => Channels::Cmyka,
+        _ => Channels::try_from(count).unwrap(),
+    }
+}
+
+fn header(input: &Input) -> FileHeader {
+    let (version, compression) = match input.mode {
+        Mode::Stream(false) => (VERSION_STREAM, Compression::None),
+        Mode::Stream(true) => (VERSION_STREAM, Compression::Lz4),
+        Mode::Block(Level::None, _) => (VERSION_BLOCK, Compression::None),
+        Mode::Block(..) => (VERSION_BLOCK, Compression::Lz4),
+    };
+
+    let mut header = FileHeader::new(
+        version,
+        None,
+        input.width as u64 + 1,
+        0,
+        channels(input),
+        compression,
+        None,
+        None,
+    );
+
+    if let Mode::Block(_, layout) = &input.mode {
+        header.rows_per_chunk = input.rows_per_chunk.map(|rows| rows as u32 % 16 + 1);
+
+        match *layout {
+            // only applies to channels with alpha, the encoder rejects the others
+            Some(Layout::AlphaPlane) if header.channels.has_alpha() => {
+                header.alpha_mode = AlphaMode::Plane;
+            }
+            Some(Layout::Cfa(pattern, wide)) => {
+                let patterns = [
+                    CfaPattern::Rggb,
+                    CfaPattern::Bggr,
+                    CfaPattern::Grbg,
+                    CfaPattern::Gbrg,
+                ];
+                header.channels = Channels::Gray;
+                header.cfa_pattern = Some(patterns[pattern as usize % 4]);
+                header.bit_depth = if wide { 16 } else { 8 };
+            }
+            _ => {}
+        }
+    }
+
+    // as many rows as there are pixels for
+    let row_size = header.width as usize * header.channels.count() * header.bytes_per_sample();
+    header.height = (input.pixels.len() / row_size) as u64;
+    header
+}
+
+fuzz_target!(|input: Input| {
+    let header = header(&input);
+    let pixels = &input.pixels[..header.data_size().unwrap()];
+
+    let level = match input.mode {
+        Mode::Stream(_) | Mode::Block(Level::None, _) => CompressionLevel::None,
+        Mode::Block(Level::Lz4Flex, _) => CompressionLevel::Lz4Flex,
+        Mode::Block(Level::Lz4(level), _) => CompressionLevel::Lz4(level as i32),
+        Mode::Block(Level::Lz4Hc(level), _) => CompressionLevel::Lz4Hc(level as i32),
+    };
+    let file = koi::encode_dynamic(pixels, header.clone(), level).unwrap();
+
+    let image = koi::decode_dynamic(&file).unwrap();
+    assert_eq!(image.header.channels, header.channels);
+    assert_eq!(image.header.alpha_mode, header.alpha_mode);
+    assert_eq!(image.header.cfa_pattern, header.cfa_pattern);
+    assert_eq!(image.data, pixels);
+});
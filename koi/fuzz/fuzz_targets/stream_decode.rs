@@ -0,0 +1,36 @@
+#![no_main]
+
+// decodes arbitrary bytes as the pixel ops of the stream layout
+// - byte 0 selects the number of channels and whether the ops are lz4 compressed
+// - bytes 1..3 contain the number of pixels (little endian)
+
+use std::io;
+
+use koi::decoder::PixelDecoder;
+use libfuzzer_sys::fuzz_target;
+
+fn decode<const C: usize>(data: &[u8], pixels: usize, lz4: bool) {
+    let mut decoder = match lz4 {
+        true => PixelDecoder::<_, C>::new_lz4(data, pixels),
+        false => PixelDecoder::<_, C>::new_uncompressed(data, pixels),
+    };
+    let _ = decoder.decode(io::sink());
+}
+
+fuzz_target!(|data: &[u8]| {
+    let [mode, p0, p1, data @ ..] = data else {
+        return;
+    };
+
+    let pixels = u16::from_le_bytes([*p0, *p1]) as usize;
+    let lz4 = mode & 0x80 != 0;
+
+    match mode % 6 {
+        0 => decode::<1>(data, pixels, lz4),
+        1 => decode::<2>(data, pixels, lz4),
+        2 => decode::<3>(data, pixels, lz4),
+        3 => decode::<4>(data, pixels, lz4),
+        4 => decode::<5>(data, pixels, lz4),
+        _ => decode::<16>(data, pixels, lz4),
+    }
+});
@@ -0,0 +1,245 @@
+// Integration with the `image` crate (feature "image")
+//
+// KoiDecoder and KoiEncoder implement image's ImageDecoder and ImageEncoder for gray, gray + alpha,
+// RGB and RGBA images with 8 bits per sample, and 16 bit gray images for CFA data. After
+// `register()`, `image::open` and `image::load_from_memory` read koi files like any other format.
+
+use std::io::{Read, Write};
+
+use ::image::{
+    error::{
+        DecodingError, EncodingError, ImageFormatHint, LimitError, LimitErrorKind,
+        UnsupportedError, UnsupportedErrorKind,
+    },
+    hooks, ColorType, DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError,
+    ImageResult, Limits,
+};
+
+use crate::{
+    decode_pixels,
+    decoder::{DecodeLimits, Limit},
+    encoder::Encoder,
+    file::FileHeader,
+    types::{Channels, Orientation, MAGIC},
+    KoiDecodeError, KoiEncodeError,
+};
+
+fn format_hint() -> ImageFormatHint {
+    ImageFormatHint::Name("koi".to_string())
+}
+
+fn decoding_error(err: KoiDecodeError) -> ImageError {
+    match err {
+        KoiDecodeError::Io(err) => ImageError::IoError(err),
+        KoiDecodeError::LimitExceeded { limit, .. } => {
+            let kind = match limit {
+                Limit::Width | Limit::Height | Limit::Pixels => LimitErrorKind::DimensionError,
+                _ => LimitErrorKind::InsufficientMemory,
+            };
+            ImageError::Limits(LimitError::from_kind(kind))
+        }
+        err => ImageError::Decoding(DecodingError::new(format_hint(), err)),
+    }
+}
+
+fn encoding_error(err: KoiEncodeError) -> ImageError {
+    match err {
+        KoiEncodeError::Io(err) => ImageError::IoError(err),
+        err => ImageError::Encoding(EncodingError::new(format_hint(), err)),
+    }
+}
+
+fn unsupported(kind: UnsupportedErrorKind) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(format_hint(), kind))
+}
+
+// decodes a koi file in either layout chunk by chunk while it is read from
+pub struct KoiDecoder<R: Read> {
+    reader: R,
+    header: FileHeader,
+    color_type: ColorType,
+}
+
+impl<R: Read> KoiDecoder<R> {
+    // reads the header, fails for images image can't represent (e.g. CMYK)
+    // - the header is checked against `DecodeLimits::default()`, `set_limits` can only restrict
+    //   these further, use `with_limits` for larger images
+    pub fn new(reader: R) -> ImageResult<Self> {
+        Self::with_limits(reader, &DecodeLimits::default())
+    }
+
+    pub fn with_limits(mut reader: R, limits: &DecodeLimits) -> ImageResult<Self> {
+        let header = FileHeader::read_with_limits(&mut reader, limits).map_err(decoding_error)?;
+        limits.check_header(&header).map_err(decoding_error)?;
+
+        // image's dimensions are u32
+        if header.width > u32::MAX as u64 || header.height > u32::MAX as u64 {
+            return Err(ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::DimensionError,
+            )));
+        }
+
+        let color_type = match (header.channels, header.bit_depth) {
+            (Channels::Gray, 8) => ColorType::L8,
+            (Channels::Gray, 16) => ColorType::L16,
+            (Channels::GrayAlpha, 8) => ColorType::La8,
+            (Channels::Rgb, 8) => ColorType::Rgb8,
+            (Channels::Rgba, 8) => ColorType::Rgba8,
+            (channels, bit_depth) => {
+                return Err(unsupported(UnsupportedErrorKind::GenericFeature(format!(
+                    "{channels:?} with {bit_depth} bits per sample"
+                ))))
+            }
+        };
+
+        Ok(Self {
+            reader,
+            header,
+            color_type,
+        })
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+}
+
+impl<R: Read> ImageDecoder for KoiDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.header.width as u32, self.header.height as u32)
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.color_type
+    }
+
+    fn exif_metadata(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.header.exif.clone())
+    }
+
+    fn orientation(&mut self) -> ImageResult<::image::metadata::Orientation> {
+        Ok(self.header.orientation.into())
+    }
+
+    fn set_limits(&mut self, limits: Limits) -> ImageResult<()> {
+        limits.check_support(&Default::default())?;
+        let (width, height) = self.dimensions();
+        limits.check_dimensions(width, height)?;
+
+        if limits.max_alloc.is_some_and(|max| self.total_bytes() > max) {
+            return Err(ImageError::Limits(LimitError::from_kind(
+                LimitErrorKind::InsufficientMemory,
+            )));
+        }
+        Ok(())
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        let (reader, header) = (self.reader, &self.header);
+        match header.channels.count() {
+            1 => decode_pixels::<_, _, 1>(reader, &mut *buf, header),
+            2 => decode_pixels::<_, _, 2>(reader, &mut *buf, header),
+            3 => decode_pixels::<_, _, 3>(reader, &mut *buf, header),
+            _ => decode_pixels::<_, _, 4>(reader, &mut *buf, header),
+        }
+        .map_err(decoding_error)?;
+
+        // 16 bit samples are stored little endian, image expects them in native byte order
+        if self.color_type == ColorType::L16 {
+            for sample in buf.chunks_exact_mut(2) {
+                let value = u16::from_le_bytes([sample[0], sample[1]]);
+                sample.copy_from_slice(&value.to_ne_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+// encodes images with the settings of an Encoder, whose dimensions and channels are replaced by
+// those of the image
+pub struct KoiEncoder<W: Write> {
+    writer: W,
+    encoder: Encoder,
+}
+
+impl<W: Write> KoiEncoder<W> {
+    // block layout compressed with lz4_flex (see Encoder::new)
+    pub fn new(writer: W) -> Self {
+        Self::with_encoder(writer, Encoder::new(0, 0, Channels::Rgba))
+    }
+
+    pub fn with_encoder(writer: W, encoder: Encoder) -> Self {
+        Self { writer, encoder }
+    }
+}
+
+impl<W: Write> ImageEncoder for KoiEncoder<W> {
+    fn write_image(
+        self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        let channels = match color_type {
+            ExtendedColorType::L8 => Channels::Gray,
+            ExtendedColorType::La8 => Channels::GrayAlpha,
+            ExtendedColorType::Rgb8 => Channels::Rgb,
+            ExtendedColorType::Rgba8 => Channels::Rgba,
+            color_type => return Err(unsupported(UnsupportedErrorKind::Color(color_type))),
+        };
+
+        let encoder = self
+            .encoder
+            .dimensions(width as u64, height as u64)
+            .channels(channels);
+        match channels.count() {
+            1 => encoder.encode_to_writer::<_, 1>(buf, self.writer),
+            2 => encoder.encode_to_writer::<_, 2>(buf, self.writer),
+            3 => encoder.encode_to_writer::<_, 3>(buf, self.writer),
+            _ => encoder.encode_to_writer::<_, 4>(buf, self.writer),
+        }
+        .map_err(encoding_error)
+    }
+
+    fn set_exif_metadata(&mut self, exif: Vec<u8>) -> Result<(), UnsupportedError> {
+        self.encoder = self.encoder.clone().exif(exif);
+        Ok(())
+    }
+}
+
+// decodes a koi file into the DynamicImage matching its channels, without applying its orientation
+pub fn decode_image(data: &[u8]) -> ImageResult<DynamicImage> {
+    DynamicImage::from_decoder(KoiDecoder::new(data)?)
+}
+
+// encodes an image with 8 bits per sample with the default settings, others have to be converted
+// first (e.g. with `DynamicImage::to_rgba8`), see KoiEncoder::with_encoder for other settings
+pub fn encode_image(image: &DynamicImage) -> ImageResult<Vec<u8>> {
+    let mut out = Vec::new();
+    image.write_with_encoder(KoiEncoder::new(&mut out))?;
+    Ok(out)
+}
+
+// lets `image::open`, `image::ImageReader` and `image::load_from_memory` read koi files, which are
+// recognized by their extension and magic number, returns false if koi files were already
+// registered
+pub fn register() -> bool {
+    let hook: hooks::DecodingHook = Box::new(|reader| Ok(Box::new(KoiDecoder::new(reader)?)));
+    if !hooks::register_decoding_hook("koi".into(), hook) {
+        return false;
+    }
+
+    hooks::register_format_detection_hook("koi".into(), &MAGIC, None);
+    true
+}
+
+impl From<Orientation> for ::image::metadata::Orientation {
+    fn from(orientation: Orientation) -> Self {
+        Self::from_exif(orientation as u8).unwrap_or(Self::NoTransforms)
+    }
+}
@@ -0,0 +1,123 @@
+// Reading and writing pixels in the buffer formats of the block codec (see BufferFormat), `order`
+// is the position of every channel within a pixel of the buffer (PixelLayout::order)
+
+use core::ops::Range;
+
+use crate::{
+    file::FileHeader,
+    types::{BufferFormat, PixelLayout},
+};
+
+// reads a pixel with C channels from a pixel of the buffer, channels after the fourth are never
+// reordered
+#[inline]
+pub(crate) fn read_px<const C: usize>(src: &[u8], order: [usize; 4]) -> [u8; C] {
+    core::array::from_fn(|c| src[if c < 4 { order[c] } else { c }])
+}
+
+// writes a pixel with C channels to a pixel of the buffer, padding is set to 255
+#[inline]
+pub(crate) fn write_px<const C: usize>(px: &[u8], dst: &mut [u8], order: [usize; 4]) {
+    for c in 0..C {
+        dst[if c < 4 { order[c] } else { c }] = px[c];
+    }
+    if dst.len() > C {
+        dst[C..].fill(255);
+    }
+}
+
+// where the pixels of an image are in a buffer of a given format
+pub(crate) struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixel_size: usize, // bytes per pixel in the buffer
+    pub row_stride: usize,
+    pub bottom_up: bool,
+}
+
+impl Frame {
+    pub(crate) fn new(format: &BufferFormat, header: &FileHeader, channels: usize) -> Self {
+        let pixel_size = match format.layout {
+            PixelLayout::Rgba => channels * header.bytes_per_sample(),
+            layout => layout.bytes_per_pixel(channels),
+        };
+
+        // sizes that don't fit into usize are rejected before the buffer is accessed
+        let width = usize::try_from(header.width).unwrap_or(usize::MAX);
+        let height = usize::try_from(header.height).unwrap_or(usize::MAX);
+        let row_size = width.saturating_mul(pixel_size);
+
+        Self {
+            width,
+            height,
+            pixel_size,
+            row_stride: format.row_stride.unwrap_or(row_size),
+            bottom_up: format.bottom_up,
+        }
+    }
+
+    // size of a row without the padding up to the stride
+    pub(crate) fn row_size(&self) -> usize {
+        self.width.saturating_mul(self.pixel_size)
+    }
+
+    pub(crate) fn buffer_size(&self) -> Option<usize> {
+        if self.width == 0 || self.height == 0 {
+            return Some(0);
+        }
+
+        (self.height - 1)
+            .checked_mul(self.row_stride)?
+            .checked_add(self.width.checked_mul(self.pixel_size)?)
+    }
+
+    // byte ranges of the pixels `pixels` (counted in image order) within the buffer
+    pub(crate) fn rows(&self, pixels: Range<usize>) -> Rows<'_> {
+        Rows {
+            frame: self,
+            pos: pixels.start,
+            end: pixels.end,
+        }
+    }
+}
+
+// iterator over the byte ranges of consecutive pixels, one range per row or part of a row
+pub(crate) struct Rows<'a> {
+    frame: &'a Frame,
+    pos: usize,
+    end: usize,
+}
+
+impl Rows<'_> {
+    pub(crate) fn pixels(&self) -> usize {
+        self.end - self.pos
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Range<usize>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Range<usize>> {
+        if self.pos >= self.end {
+            return None;
+        }
+
+        let Frame {
+            width,
+            height,
+            pixel_size,
+            row_stride,
+            bottom_up,
+        } = *self.frame;
+        let (x, mut y) = (self.pos % width, self.pos / width);
+        let len = (width - x).min(self.end - self.pos);
+        self.pos += len;
+
+        if bottom_up {
+            y = height - 1 - y;
+        }
+        let start = y * row_stride + x * pixel_size;
+        Some(start..start + len * pixel_size)
+    }
+}
@@ -1,57 +1,422 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+#[cfg(feature = "std")]
 use std::io::Write;
 
-use file::FileHeader;
+use decoder::{block::Image, DecodeOptions, DecodedImage, ImageInfo};
+use encoder::block::CompressionLevel;
+#[cfg(feature = "std")]
+use encoder::EncodeOptions;
+use file::{FileHeader, Layout};
 use thiserror::Error;
 
+pub(crate) mod alpha;
+pub(crate) mod cfa;
+pub(crate) mod channels;
 pub mod decoder;
+pub(crate) mod document;
 pub mod encoder;
 pub mod file;
+#[cfg(feature = "image")]
+pub mod image;
+pub(crate) mod layout;
+pub(crate) mod ops;
+pub(crate) mod orientation;
 pub mod types;
 pub mod util;
 
+// encodes an image using the stream layout (see [`file`] for details)
+#[cfg(feature = "std")]
 pub fn encode<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
     header: file::FileHeader,
-    reader: READER, // unbuffered reader, if you want to use a buffered reader (e.g. when reading a file), wrap it in a BufReader
+    mut reader: READER, // unbuffered reader, if you want to use a buffered reader (e.g. when reading a file), wrap it in a BufReader
     mut writer: WRITER,
 ) -> Result<(), KoiEncodeError> {
+    if header.version != file::VERSION_STREAM {
+        return Err(KoiEncodeError::UnsupportedVersion(header.version as u8));
+    }
+
+    if header.channels.count() != C {
+        return Err(KoiEncodeError::ChannelMismatch {
+            image: header.channels.count(),
+            requested: C,
+        });
+    }
+
+    if let Some(feature) = header.block_only_feature() {
+        return Err(KoiEncodeError::InvalidHeader(format!(
+            "{feature} are only supported by the block layout"
+        )));
+    }
+
+    let too_large = || KoiEncodeError::InvalidHeader("image too large".to_string());
+    let pixels = header.pixel_count().ok_or_else(too_large)?;
+    let size = header.data_size().ok_or_else(too_large)?;
+
     header.write(&mut writer)?;
 
     let mut encoder = match header.compression {
         types::Compression::None => encoder::PixelEncoder::<WRITER, C>::new_uncompressed,
         types::Compression::Lz4 => encoder::PixelEncoder::<WRITER, C>::new_lz4,
-    }(writer, (header.width * header.height) as usize);
+    }(writer, pixels);
 
-    encoder.encode(reader)?;
+    // fewer bytes than the header describes leave the image without its end marker, more bytes
+    // than that aren't passed to the encoder at all
+    let len = encoder.encode(std::io::Read::take(&mut reader, size as u64))?;
+    if len != size as u64 || std::io::Read::read(&mut reader, &mut [0])? != 0 {
+        return Err(KoiEncodeError::InvalidLength);
+    }
     encoder.flush()?;
 
     Ok(())
 }
 
+// the whole image is read into memory first if the channels are reduced
+#[cfg(feature = "std")]
+pub fn encode_with_options<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
+    mut header: file::FileHeader,
+    mut reader: READER,
+    writer: WRITER,
+    options: &EncodeOptions,
+) -> Result<(), KoiEncodeError> {
+    if !options.reduce_channels || header.channels.count() != C {
+        return encode::<_, _, C>(header, reader, writer);
+    }
+
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    match channels::reduce(&data, &mut header) {
+        Some(data) => match header.channels.count() {
+            1 => encode::<_, _, 1>(header, &data[..], writer),
+            2 => encode::<_, _, 2>(header, &data[..], writer),
+            _ => encode::<_, _, 3>(header, &data[..], writer),
+        },
+        None => encode::<_, _, C>(header, &data[..], writer),
+    }
+}
+
+// decodes an image in either layout, the layout is detected based on the file header
+// - C has to be the number of channels the image is stored with, whatever their color model is
+//   (e.g. 4 for both RGBA and CMYK), the returned header describes them
+// - with `DecodeOptions::expand_channels`, images stored with fewer channels than C (see
+//   EncodeOptions::reduce_channels) are expanded, the returned header then describes the expanded
+//   image
+#[cfg(feature = "std")]
 pub fn decode<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
+    reader: READER,
+    writer: WRITER,
+) -> Result<FileHeader, KoiDecodeError> {
+    decode_with_options::<_, _, C>(reader, writer, &DecodeOptions::default())
+}
+
+// applying the orientation requires the whole image, which is then decoded into memory first
+#[cfg(feature = "std")]
+pub fn decode_with_options<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
     mut reader: READER,
+    writer: WRITER,
+    options: &DecodeOptions,
+) -> Result<FileHeader, KoiDecodeError> {
+    let header = file::FileHeader::read_with_limits(&mut reader, &options.limits)?;
+    options.limits.check_header(&header)?;
+
+    let Some(to) = channels::expanded::<C>(header.channels, options.expand_channels)? else {
+        return decode_body::<_, _, C>(reader, writer, header, options);
+    };
+
+    let from = header.channels;
+    let writer = channels::Expand::new(writer, from, to);
+    let mut header = match from.count() {
+        1 => decode_body::<_, _, 1>(reader, writer, header, options)?,
+        2 => decode_body::<_, _, 2>(reader, writer, header, options)?,
+        _ => decode_body::<_, _, 3>(reader, writer, header, options)?,
+    };
+    header.channels = to;
+    Ok(header)
+}
+
+#[cfg(feature = "std")]
+fn decode_body<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
+    reader: READER,
     mut writer: WRITER,
+    mut header: FileHeader,
+    options: &DecodeOptions,
 ) -> Result<FileHeader, KoiDecodeError> {
-    let header = file::FileHeader::read(&mut reader)?;
+    if !options.apply_orientation || header.orientation == types::Orientation::Normal {
+        decode_pixels::<_, _, C>(reader, writer, &header)?;
+        return Ok(header);
+    }
+
+    let size = header.data_size().ok_or_else(image_too_large)?;
+    options.limits.check_alloc(size)?;
+
+    let mut out = Vec::with_capacity(size);
+    decode_pixels::<_, _, C>(reader, &mut out, &header)?;
+    writer.write_all(&orientation::apply(out, &mut header)?)?;
+
+    Ok(header)
+}
+
+#[cfg(feature = "std")]
+fn decode_pixels<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
+    reader: READER,
+    mut writer: WRITER,
+    header: &FileHeader,
+) -> Result<(), KoiDecodeError> {
+    match header.layout()? {
+        Layout::Stream => decode_stream::<_, _, C>(reader, writer, header),
+        Layout::Block => {
+            let mut decoder =
+                decoder::block::BlockDecoder::<_, C>::with_header(reader, header.clone())?;
+            std::io::copy(&mut decoder, &mut writer).map_err(decode_error)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn decode_stream<WRITER: std::io::Write, READER: std::io::Read, const C: usize>(
+    reader: READER,
+    writer: WRITER,
+    header: &FileHeader,
+) -> Result<(), KoiDecodeError> {
+    if header.channels.count() != C {
+        return Err(KoiDecodeError::ChannelMismatch {
+            image: header.channels.count(),
+            requested: C,
+        });
+    }
+
+    if let Some(feature) = header.block_only_feature() {
+        return Err(KoiDecodeError::InvalidFileHeader(format!(
+            "{feature} are only supported by the block layout"
+        )));
+    }
+
+    let pixels = header.pixel_count().ok_or_else(image_too_large)?;
 
     let mut decoder = match header.compression {
         types::Compression::None => decoder::PixelDecoder::<READER, C>::new_uncompressed,
         types::Compression::Lz4 => decoder::PixelDecoder::<READER, C>::new_lz4,
-    }(reader, (header.width * header.height) as usize);
+    }(reader, pixels)
+    .with_reset_alpha(header.ops_reset_alpha());
 
-    decoder.decode(&mut writer)?;
-    Ok(header)
+    decoder.decode(writer).map_err(decode_error)?;
+    Ok(())
+}
+
+// the decoders implement Read and have to wrap their errors in io errors, this unwraps them again
+#[cfg(feature = "std")]
+fn decode_error(err: std::io::Error) -> KoiDecodeError {
+    if !err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<KoiDecodeError>())
+    {
+        return KoiDecodeError::Io(err);
+    }
+
+    match err
+        .into_inner()
+        .map(|inner| inner.downcast::<KoiDecodeError>())
+    {
+        Some(Ok(inner)) => *inner,
+        _ => unreachable!("the inner error is a KoiDecodeError"),
+    }
+}
+
+// decodes an image in either layout from a byte slice, the layout is detected based on the file header
+// - C and `DecodeOptions::expand_channels` work like for `decode`
+pub fn decode_to_vec<const C: usize>(data: &[u8]) -> Result<Image, KoiDecodeError> {
+    decode_to_vec_with_options::<C>(data, &DecodeOptions::default())
 }
 
-// pub fn decode_all<const C: usize>(
-//     data: &[u8],
-//     out: &mut [u8],
-// ) -> Result<(FileHeader, usize), QoirDecodeError> {
-//     let mut reader = std::io::Cursor::new(data);
-//     let header = file::FileHeader::read(&mut reader)?;
+pub fn decode_to_vec_with_options<const C: usize>(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<Image, KoiDecodeError> {
+    let (header_len, header) = file::FileHeader::read_bytes_with_limits(data, &options.limits)?;
+    options.limits.check_header(&header)?;
+    let data = &data[header_len..];
+
+    let Some(to) = channels::expanded::<C>(header.channels, options.expand_channels)? else {
+        return decode_to_vec_body::<C>(data, header, options);
+    };
+
+    let size = header
+        .pixel_count()
+        .and_then(|pixels| pixels.checked_mul(C));
+    options
+        .limits
+        .check_alloc(size.ok_or_else(image_too_large)?)?;
+
+    let from = header.channels;
+    let mut image = match from.count() {
+        1 => decode_to_vec_body::<1>(data, header, options)?,
+        2 => decode_to_vec_body::<2>(data, header, options)?,
+        _ => decode_to_vec_body::<3>(data, header, options)?,
+    };
+    image.data = channels::to_channels(&image.data, from, to);
+    image.header.channels = to;
+    Ok(image)
+}
 
-//     let mut decoder =
-//         decoder::PixelDecoder::<_, C>::new_lz4(reader, (header.width * header.height) as usize);
-// }
+// `data` starts after the header
+fn decode_to_vec_body<const C: usize>(
+    data: &[u8],
+    mut header: FileHeader,
+    options: &DecodeOptions,
+) -> Result<Image, KoiDecodeError> {
+    header.data_size().ok_or_else(image_too_large)?;
+    options.limits.check_alloc(header.min_output_size())?;
+
+    let mut out = match header.layout()? {
+        #[cfg(feature = "std")]
+        Layout::Stream => {
+            let mut out = Vec::with_capacity(header.min_output_size());
+            decode_stream::<_, _, C>(data, &mut out, &header)?;
+            out
+        }
+        // the stream layout is only supported with the "std" feature
+        #[cfg(not(feature = "std"))]
+        Layout::Stream => return Err(KoiDecodeError::UnsupportedVersion(header.version as u8)),
+        Layout::Block => {
+            let mut out = vec![0; header.min_output_size()];
+            let len = decoder::block::decode_impl::<C>(
+                data,
+                &mut out,
+                header.clone(),
+                &types::BufferFormat::default(),
+            )?;
+            out.truncate(len);
+            out
+        }
+    };
+
+    if options.apply_orientation {
+        out = orientation::apply(out, &mut header)?;
+    }
+
+    Ok(Image { header, data: out })
+}
+
+// calls `$f::<N>($args)` with the number of channels as N, `$other` for invalid channel counts
+macro_rules! with_channels {
+    ($channels:expr, $f:ident($($args:expr),*), $other:expr) => {
+        match $channels {
+            1 => $f::<1>($($args),*),
+            2 => $f::<2>($($args),*),
+            3 => $f::<3>($($args),*),
+            4 => $f::<4>($($args),*),
+            5 => $f::<5>($($args),*),
+            6 => $f::<6>($($args),*),
+            7 => $f::<7>($($args),*),
+            8 => $f::<8>($($args),*),
+            9 => $f::<9>($($args),*),
+            10 => $f::<10>($($args),*),
+            11 => $f::<11>($($args),*),
+            12 => $f::<12>($($args),*),
+            13 => $f::<13>($($args),*),
+            14 => $f::<14>($($args),*),
+            15 => $f::<15>($($args),*),
+            16 => $f::<16>($($args),*),
+            _ => $other,
+        }
+    };
+}
+
+// reads only the header of an image, e.g. to allocate the output buffer before decoding it
+pub fn probe(data: &[u8]) -> Result<ImageInfo, KoiDecodeError> {
+    let (header_size, header) = FileHeader::read_bytes(data)?;
+
+    Ok(ImageInfo {
+        width: header.width,
+        height: header.height,
+        channels: header.channels,
+        bit_depth: header.bit_depth,
+        compression: header.compression,
+        layout: header.layout()?,
+        has_exif: header.exif.is_some(),
+        header_size,
+        header,
+    })
+}
+
+// decodes an image in either layout with the channels it was stored with, for images whose
+// channels aren't known in advance
+pub fn decode_dynamic(data: &[u8]) -> Result<DecodedImage, KoiDecodeError> {
+    decode_dynamic_with_options(data, &DecodeOptions::default())
+}
+
+pub fn decode_dynamic_with_options(
+    data: &[u8],
+    options: &DecodeOptions,
+) -> Result<DecodedImage, KoiDecodeError> {
+    let (header_len, header) = file::FileHeader::read_bytes_with_limits(data, &options.limits)?;
+    options.limits.check_header(&header)?;
+    let data = &data[header_len..];
+
+    // the image is decoded with its own channels, so there's nothing to expand
+    let channels = header.channels;
+    let image = with_channels!(
+        channels.count(),
+        decode_to_vec_body(data, header, options),
+        Err(KoiDecodeError::InvalidFileHeader(format!(
+            "invalid channels: {channels:?}"
+        )))
+    )?;
+
+    Ok(DecodedImage {
+        header: image.header,
+        data: image.data,
+    })
+}
+
+// encodes an image with as many channels as `header.channels` in the layout selected by
+// `header.version`, the compression level only applies to the block layout
+pub fn encode_dynamic(
+    data: &[u8],
+    header: FileHeader,
+    compression_level: CompressionLevel,
+) -> Result<Vec<u8>, KoiEncodeError> {
+    let channels = header.channels;
+    with_channels!(
+        channels.count(),
+        encode_dynamic_impl(data, header, compression_level),
+        Err(KoiEncodeError::InvalidHeader(format!(
+            "invalid channels: {channels:?}"
+        )))
+    )
+}
+
+fn encode_dynamic_impl<const C: usize>(
+    data: &[u8],
+    header: FileHeader,
+    compression_level: CompressionLevel,
+) -> Result<Vec<u8>, KoiEncodeError> {
+    match header.layout() {
+        Ok(Layout::Block) => encoder::block::encode_to_vec::<C>(data, header, compression_level),
+        #[cfg(feature = "std")]
+        _ => {
+            let mut out = Vec::new();
+            encode::<_, _, C>(header, data, &mut out)?;
+            Ok(out)
+        }
+        #[cfg(not(feature = "std"))]
+        _ => Err(KoiEncodeError::UnsupportedVersion(header.version as u8)),
+    }
+}
+
+pub(crate) fn image_too_large() -> KoiDecodeError {
+    KoiDecodeError::InvalidFileHeader("image too large".to_string())
+}
 
 #[derive(Error, Debug)]
 pub enum KoiDecodeError {
@@ -61,6 +426,7 @@ pub enum KoiDecodeError {
     #[error("Invalid chunk length")]
     InvalidChunkLength,
 
+    #[cfg(feature = "std")]
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -69,13 +435,62 @@ pub enum KoiDecodeError {
 
     #[error("Failed to decompress: {0}")]
     Decompress(String),
+
+    #[error("Invalid alpha plane")]
+    InvalidAlphaPlane,
+
+    #[error("Invalid CFA data")]
+    InvalidCfaData,
+
+    #[error("Invalid opcode {opcode:#04x} at offset {offset} of the pixel ops")]
+    InvalidOpcode { opcode: u8, offset: usize },
+
+    // a chunk of the block layout or the pixel ops of the stream layout end before every pixel
+    #[error("Truncated chunk")]
+    TruncatedChunk,
+
+    #[error("Invalid end of image marker")]
+    InvalidEndOfImage,
+
+    #[error("Pixel count mismatch: expected {expected}, got {actual}")]
+    PixelCountMismatch { expected: u64, actual: u64 },
+
+    #[error("Channel mismatch: the image has {image} channels, but {requested} were requested")]
+    ChannelMismatch { image: usize, requested: usize },
+
+    #[error("Images with {from:?} channels can't be expanded to {to:?}")]
+    UnsupportedExpansion {
+        from: types::Channels,
+        to: types::Channels,
+    },
+
+    #[error("Images with {channels} channels can't be decoded to {layout:?}")]
+    UnsupportedLayout {
+        layout: types::PixelLayout,
+        channels: usize,
+    },
+
+    #[error("Row stride {stride} is smaller than a row of {row_size} bytes")]
+    InvalidStride { stride: usize, row_size: usize },
+
+    #[error("Output buffer too small: {size} bytes, but {required} are required")]
+    OutputTooSmall { size: usize, required: usize },
+
+    #[error("Image exceeds the {limit} limit: {value} > {max}")]
+    LimitExceeded {
+        limit: decoder::Limit,
+        value: u64,
+        max: u64,
+    },
 }
 
 #[derive(Error, Debug)]
 pub enum KoiEncodeError {
+    #[cfg(feature = "std")]
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[cfg(feature = "bson")]
     #[error(transparent)]
     Bson(#[from] bson::ser::Error),
 
@@ -87,10 +502,23 @@ pub enum KoiEncodeError {
 
     #[error("Unsupported version: {0}")]
     UnsupportedVersion(u8),
+
+    #[error("Channel mismatch: the header has {image} channels, but {requested} were given")]
+    ChannelMismatch { image: usize, requested: usize },
+
+    #[error("Images with {channels} channels can't be encoded from {layout:?}")]
+    UnsupportedLayout {
+        layout: types::PixelLayout,
+        channels: usize,
+    },
+
+    #[error("Row stride {stride} is smaller than a row of {row_size} bytes")]
+    InvalidStride { stride: usize, row_size: usize },
 }
 
 #[derive(Error, Debug)]
 pub enum KoiError {
+    #[cfg(feature = "std")]
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
     #[error(transparent)]
@@ -99,20 +527,22 @@ pub enum KoiError {
     QoirDecodeError(#[from] KoiDecodeError),
 }
 
+#[cfg(feature = "std")]
 impl From<KoiEncodeError> for std::io::Error {
     fn from(err: KoiEncodeError) -> Self {
         match err {
             KoiEncodeError::Io(err) => err,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, err),
+            _ => std::io::Error::other(err),
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl From<KoiDecodeError> for std::io::Error {
     fn from(err: KoiDecodeError) -> Self {
         match err {
             KoiDecodeError::Io(err) => err,
-            _ => std::io::Error::new(std::io::ErrorKind::Other, err),
+            _ => std::io::Error::other(err),
         }
     }
 }
@@ -0,0 +1,199 @@
+// Pixel ops shared by the stream and block codecs
+//
+// Every op describes a pixel relative to the previous one, ops that don't store an alpha value
+// keep the alpha value of the previous pixel (in files before version 2, OP_GRAY, OP_RGB and
+// OP_DIFF set it to 255 instead, see `FileHeader::ops_reset_alpha`):
+// - OP_SAME: the previous pixel
+// - OP_DIFF: small difference in r, g and b (-2..1 each)
+// - OP_LUMA: larger difference in g (-32..31), r and b relative to g (-8..7)
+// - OP_DIFF_ALPHA: difference in alpha (-30..29), color stays the same
+// - OP_GRAY, OP_GRAY_ALPHA: gray value (and alpha)
+// - OP_RGB, OP_RGBA: literal color (and alpha)
+//
+// Images with more than four channels encode the first four channels as RGBA, every op except
+// OP_SAME is then followed by one byte per additional channel, containing the (wrapping)
+// difference to the previous pixel.
+
+use crate::{
+    types::*,
+    util::{cold, Writer},
+    KoiDecodeError,
+};
+
+// the longest op (OP_RGBA followed by the differences of all additional channels)
+#[cfg(feature = "std")]
+pub(crate) const MAX_OP_LEN: usize = 5 + MAX_CHANNELS - 4;
+
+// length of an op in bytes, including the opcode, None for invalid opcodes
+#[inline]
+pub(crate) fn op_len<const C: usize>(opcode: u8) -> Option<usize> {
+    let len = rgba_op_len(opcode)?;
+    if C > 4 && opcode != OP_SAME {
+        return Some(len + C - 4);
+    }
+
+    Some(len)
+}
+
+#[inline]
+fn rgba_op_len(opcode: u8) -> Option<usize> {
+    match opcode {
+        OP_SAME => Some(1),
+        OP_GRAY => Some(2),
+        OP_GRAY_ALPHA => Some(3),
+        OP_RGB => Some(4),
+        OP_RGBA => Some(5),
+        OP_DIFF..=OP_DIFF_END => Some(1),
+        OP_LUMA..=OP_LUMA_END => Some(2),
+        OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END => Some(1),
+        _ => None,
+    }
+}
+
+// why decode_px couldn't decode an op
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OpError {
+    Invalid(u8),
+    // the data ends in the middle of the op
+    Truncated,
+}
+
+impl OpError {
+    // `offset` is the position of the op in the uncompressed ops
+    pub(crate) fn at(self, offset: usize) -> KoiDecodeError {
+        match self {
+            OpError::Invalid(opcode) => KoiDecodeError::InvalidOpcode { opcode, offset },
+            OpError::Truncated => KoiDecodeError::TruncatedChunk,
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn encode_px<W: Writer, const C: usize>(
+    curr_pixel: Pixel<C>,
+    prev_pixel: Pixel<C>,
+    buf: W,
+) -> W {
+    if curr_pixel == prev_pixel {
+        return buf.write_one(OP_SAME);
+    }
+
+    let mut buf = encode_rgba_px::<W, C>(curr_pixel, prev_pixel, buf);
+    for c in 4..C {
+        buf = buf.write_one(curr_pixel.data[c].wrapping_sub(prev_pixel.data[c]));
+    }
+    buf
+}
+
+#[allow(clippy::all)] // clippy is making the code slower
+#[inline]
+fn encode_rgba_px<W: Writer, const C: usize>(
+    curr_pixel: Pixel<C>,
+    prev_pixel: Pixel<C>,
+    buf: W,
+) -> W {
+    // alpha can only be changed by ops that store it explicitly
+    if (C == 2 || C >= 4) && curr_pixel.a() != prev_pixel.a() {
+        if curr_pixel.rgb() == prev_pixel.rgb() {
+            if let Some(diff) = prev_pixel.alpha_diff(&curr_pixel) {
+                return buf.write_one(diff);
+            }
+        }
+
+        if curr_pixel.is_gray() {
+            return buf.write_many(&[Op::GrayAlpha as u8, curr_pixel.r(), curr_pixel.a()]);
+        }
+
+        return buf.write_many(&[
+            Op::Rgba as u8,
+            curr_pixel.r(),
+            curr_pixel.g(),
+            curr_pixel.b(),
+            curr_pixel.a(),
+        ]);
+    }
+
+    // Difference between current and previous pixel
+    let diff = curr_pixel.diff(&prev_pixel);
+
+    // Diff encoding
+    if let Some(diff) = diff.color() {
+        return buf.write_one(diff);
+    }
+
+    // Gray encoding
+    if curr_pixel.is_gray() {
+        return buf.write_many(&[Op::Gray as u8, curr_pixel.r()]);
+    }
+
+    // Luma encoding
+    if let Some(luma) = diff.luma() {
+        return buf.write_many(&luma);
+    }
+
+    buf.write_many(&[
+        Op::Rgb as u8,
+        curr_pixel.r(),
+        curr_pixel.g(),
+        curr_pixel.b(),
+    ])
+}
+
+// decodes a single op, returns the remaining data and the decoded pixel
+// - `reset_alpha` selects the ops of files before version 2 (see `FileHeader::ops_reset_alpha`)
+#[inline]
+pub(crate) fn decode_px<const C: usize>(
+    data: &[u8],
+    prev_pixel: Pixel<C>,
+    reset_alpha: bool,
+) -> Result<(&[u8], Pixel<C>), OpError> {
+    let Some((rest, mut px)) = decode_rgba_px::<C>(data, prev_pixel, reset_alpha) else {
+        cold();
+        return Err(match data.first() {
+            Some(&opcode) if op_len::<C>(opcode).is_none() => OpError::Invalid(opcode),
+            _ => OpError::Truncated,
+        });
+    };
+
+    if C <= 4 || data[0] == OP_SAME {
+        return Ok((rest, px));
+    }
+
+    if rest.len() < C - 4 {
+        cold();
+        return Err(OpError::Truncated);
+    }
+
+    let (diffs, rest) = rest.split_at(C - 4);
+    for (c, diff) in (4..C).zip(diffs) {
+        px.data[c] = prev_pixel.data[c].wrapping_add(*diff);
+    }
+    Ok((rest, px))
+}
+
+#[allow(clippy::all)] // clippy is making the code slower
+#[inline]
+fn decode_rgba_px<'a, const C: usize>(
+    data: &'a [u8],
+    prev_pixel: Pixel<C>,
+    reset_alpha: bool,
+) -> Option<(&'a [u8], Pixel<C>)> {
+    // alpha of the pixels decoded from OP_GRAY, OP_RGB and OP_DIFF
+    let alpha = if reset_alpha { 255 } else { prev_pixel.a() };
+
+    Some(match data {
+        [OP_SAME, rest @ ..] => (rest, prev_pixel),
+        [OP_GRAY, v, rest @ ..] => (rest, Pixel::from_gray_alpha(*v, alpha)),
+        [OP_GRAY_ALPHA, v, a, rest @ ..] => (rest, Pixel::from_gray_alpha(*v, *a)),
+        [OP_RGB, r, g, b, rest @ ..] => (rest, Pixel::from_rgba(*r, *g, *b, alpha)),
+        [OP_RGBA, r, g, b, a, rest @ ..] => (rest, Pixel::from_rgba(*r, *g, *b, *a)),
+
+        [b1 @ OP_DIFF..=OP_DIFF_END, rest @ ..] => (rest, prev_pixel.apply_diff(*b1, alpha)),
+        [b1 @ OP_LUMA..=OP_LUMA_END, b2, rest @ ..] => (rest, prev_pixel.apply_luma(*b1, *b2)),
+        [b1 @ OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END, rest @ ..] => {
+            (rest, prev_pixel.apply_alpha_diff(*b1))
+        }
+
+        _ => return None,
+    })
+}
@@ -0,0 +1,69 @@
+// Applies the header's orientation to decoded pixels (DecodeOptions::apply_orientation)
+
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    file::FileHeader,
+    types::{CfaPattern, Orientation},
+    KoiDecodeError,
+};
+
+// transforms the decoded pixels for display and updates the header to describe the result
+pub(crate) fn apply(data: Vec<u8>, header: &mut FileHeader) -> Result<Vec<u8>, KoiDecodeError> {
+    let orientation = header.orientation;
+    if orientation == Orientation::Normal {
+        return Ok(data);
+    }
+
+    let (width, height) = (header.width as usize, header.height as usize);
+    let pixel_size = header.channels.count() * header.bytes_per_sample();
+    if data.len() != width * height * pixel_size {
+        return Err(KoiDecodeError::InvalidChunkLength);
+    }
+
+    let swap = orientation.swaps_dimensions();
+    let out_width = if swap { height } else { width };
+
+    let mut out = vec![0; data.len()];
+    for (i, px) in out.chunks_exact_mut(pixel_size).enumerate() {
+        let (x, y) = orientation.source(i % out_width, i / out_width, width, height);
+        let src = (y * width + x) * pixel_size;
+        px.copy_from_slice(&data[src..src + pixel_size]);
+    }
+
+    header.orientation = Orientation::Normal;
+    header.cfa_pattern = header
+        .cfa_pattern
+        .map(|pattern| transform_pattern(pattern, orientation, width, height));
+    if swap {
+        (header.width, header.height) = (header.height, header.width);
+        header.pixels_per_meter = header.pixels_per_meter.map(|(x, y)| (y, x));
+        header.pixel_aspect_ratio = header.pixel_aspect_ratio.map(|(num, den)| (den, num));
+    }
+
+    Ok(out)
+}
+
+// the pattern of a transformed mosaic, from the stored colors of its top left 2x2 samples (adding 2
+// to the dimensions keeps the parity of every position and avoids underflows for 1 pixel wide images)
+fn transform_pattern(
+    pattern: CfaPattern,
+    orientation: Orientation,
+    width: usize,
+    height: usize,
+) -> CfaPattern {
+    let colors = pattern.name().as_bytes();
+    let mut name = [0; 4];
+    for (i, color) in name.iter_mut().enumerate() {
+        let (x, y) = orientation.source(i % 2, i / 2, width + 2, height + 2);
+        *color = colors[y % 2 * 2 + x % 2];
+    }
+
+    // green stays on one diagonal of every 2x2 square, so the result is one of the patterns
+    match &name {
+        b"rggb" => CfaPattern::Rggb,
+        b"bggr" => CfaPattern::Bggr,
+        b"grbg" => CfaPattern::Grbg,
+        _ => CfaPattern::Gbrg,
+    }
+}
@@ -0,0 +1,208 @@
+// Encoder builder: the header is derived from the settings, and all outputs produce the same file.
+#![cfg(feature = "std")]
+
+use koi::{
+    decoder::DecodeOptions,
+    encoder::{
+        block::{encode_to_vec, CompressionLevel},
+        Encoder,
+    },
+    file::{FileHeader, Layout, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels, Compression, Orientation},
+    KoiEncodeError,
+};
+
+const WIDTH: u64 = 31;
+const HEIGHT: u64 = 17;
+
+fn pixels(channels: usize) -> Vec<u8> {
+    (0..WIDTH as usize * HEIGHT as usize * channels)
+        .map(|i| (i * 7 / 3) as u8)
+        .collect()
+}
+
+// encodes with every output, which have to agree
+fn encode<const C: usize>(encoder: &Encoder, data: &[u8]) -> Vec<u8> {
+    let file = encoder.encode_to_vec::<C>(data).unwrap();
+
+    let mut written = Vec::new();
+    encoder
+        .encode_to_writer::<_, C>(data, &mut written)
+        .unwrap();
+    assert!(written == file);
+
+    let size = match encoder.header().layout().unwrap() {
+        Layout::Block => encoder.max_encoded_size::<C>().unwrap(),
+        Layout::Stream => file.len(),
+    };
+    let mut out = vec![0; size];
+    let len = encoder.encode_into::<C>(data, &mut out).unwrap();
+    assert!(out[..len] == file);
+
+    file
+}
+
+#[test]
+fn headers_are_consistent() {
+    let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgb);
+    let header = encoder.header();
+    assert_eq!(header.version, VERSION_BLOCK);
+    assert_eq!(header.compression, Compression::Lz4);
+    assert_eq!((header.width, header.height), (WIDTH, HEIGHT));
+
+    let header = encoder.clone().level(CompressionLevel::None).header();
+    assert_eq!(header.compression, Compression::None);
+
+    let header = encoder
+        .clone()
+        .layout(Layout::Stream)
+        .level(CompressionLevel::Lz4Hc(9))
+        .header();
+    assert_eq!(header.version, VERSION_STREAM);
+    assert_eq!(header.compression, Compression::Lz4);
+
+    // row-aligned chunks depend on the final dimensions
+    let header = encoder
+        .row_aligned_chunks()
+        .dimensions(1000, 10)
+        .channels(Channels::Rgba)
+        .header();
+    assert_eq!(
+        header.rows_per_chunk,
+        FileHeader::new(
+            VERSION_BLOCK,
+            None,
+            1000,
+            10,
+            Channels::Rgba,
+            Compression::Lz4,
+            None,
+            None
+        )
+        .with_row_aligned_chunks()
+        .rows_per_chunk
+    );
+}
+
+#[test]
+fn builder_matches_the_block_encoder() {
+    let data = pixels(4);
+    for level in [
+        CompressionLevel::None,
+        CompressionLevel::Lz4Flex,
+        CompressionLevel::Lz4(3),
+        CompressionLevel::Lz4Hc(4),
+    ] {
+        let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgba)
+            .level(level)
+            .rows_per_chunk(3);
+        let file = encode::<4>(&encoder, &data);
+
+        let expected = encode_to_vec::<4>(&data, encoder.header(), level).unwrap();
+        assert!(file == expected, "{level:?}");
+        assert_eq!(koi::decode_to_vec::<4>(&file).unwrap().data, data);
+    }
+}
+
+#[test]
+fn builder_writes_both_layouts() {
+    let data = pixels(3);
+    for layout in [Layout::Stream, Layout::Block] {
+        for level in [CompressionLevel::None, CompressionLevel::Lz4Flex] {
+            let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgb)
+                .layout(layout)
+                .level(level);
+            let file = encode::<3>(&encoder, &data);
+
+            let image = koi::decode_to_vec::<3>(&file).unwrap();
+            assert_eq!(image.header.layout().unwrap(), layout);
+            assert_eq!(image.data, data, "{layout:?} {level:?}");
+        }
+    }
+}
+
+#[test]
+fn builder_sets_metadata() {
+    let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgba)
+        .exif(b"Exif\0\0II".to_vec())
+        .orientation(Orientation::Rotate90)
+        .pixels_per_meter(3780, 3780)
+        .pixel_aspect_ratio(2, 1)
+        .alpha_mode(AlphaMode::Plane);
+    let file = encode::<4>(&encoder, &pixels(4));
+
+    let header = koi::probe(&file).unwrap().header;
+    assert_eq!(header.exif.as_deref(), Some(&b"Exif\0\0II"[..]));
+    assert_eq!(header.orientation, Orientation::Rotate90);
+    assert_eq!(header.pixels_per_meter, Some((3780, 3780)));
+    assert_eq!(header.pixel_aspect_ratio, Some((2, 1)));
+    assert_eq!(header.alpha_mode, AlphaMode::Plane);
+
+    let cfa = Encoder::new(WIDTH, HEIGHT, Channels::Gray).cfa(CfaPattern::Bggr, 16);
+    let data = pixels(2);
+    let file = encode::<1>(&cfa, &data);
+    assert_eq!(koi::probe(&file).unwrap().bit_depth, 16);
+    assert_eq!(koi::decode_to_vec::<1>(&file).unwrap().data, data);
+}
+
+#[test]
+fn builder_reduces_channels() {
+    let data: Vec<u8> = pixels(1).iter().flat_map(|&v| [v, v, v, 255]).collect();
+    for layout in [Layout::Stream, Layout::Block] {
+        let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgba)
+            .layout(layout)
+            .reduce_channels(true);
+        let file = encode::<4>(&encoder, &data);
+
+        assert_eq!(koi::probe(&file).unwrap().channels, Channels::Gray);
+        let options = DecodeOptions {
+            expand_channels: true,
+            ..Default::default()
+        };
+        let image = koi::decode_to_vec_with_options::<4>(&file, &options).unwrap();
+        assert_eq!(image.data, data);
+    }
+}
+
+#[test]
+fn builder_reports_errors() {
+    let encoder = Encoder::new(WIDTH, HEIGHT, Channels::Rgb);
+    assert!(matches!(
+        encoder.encode_to_vec::<4>(&pixels(4)),
+        Err(KoiEncodeError::ChannelMismatch {
+            image: 3,
+            requested: 4
+        })
+    ));
+
+    let mut out = vec![0; 16];
+    assert!(matches!(
+        encoder.encode_into::<3>(&pixels(3), &mut out),
+        Err(KoiEncodeError::InvalidLength)
+    ));
+
+    let stream = encoder.layout(Layout::Stream);
+    assert!(stream.max_encoded_size::<3>().is_err());
+    assert!(stream.encode_into::<3>(&pixels(3), &mut out).is_err());
+
+    // the stream layout rejects too few or too many pixels like the block layout
+    let mut long = pixels(3);
+    long.extend_from_slice(&[1; 30]);
+    for data in [&[1; 9][..], &long] {
+        for encoder in [stream.clone(), stream.clone().level(CompressionLevel::None)] {
+            assert!(matches!(
+                encoder.encode_to_vec::<3>(data),
+                Err(KoiEncodeError::InvalidLength)
+            ));
+            assert!(matches!(
+                encoder.encode_to_writer::<_, 3>(data, Vec::new()),
+                Err(KoiEncodeError::InvalidLength)
+            ));
+            let mut out = vec![0; 4096];
+            assert!(matches!(
+                encoder.encode_into::<3>(data, &mut out),
+                Err(KoiEncodeError::InvalidLength)
+            ));
+        }
+    }
+}
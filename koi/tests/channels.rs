@@ -0,0 +1,211 @@
+// Channel reduction: images with unused channels are stored with fewer channels and expanded again
+// when they're decoded with the original number of channels and `expand_channels`.
+#![cfg(feature = "std")]
+
+use koi::{
+    decoder::DecodeOptions,
+    encoder::{
+        block::{encode_to_vec_with_options, CompressionLevel},
+        EncodeOptions,
+    },
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, Channels},
+    KoiDecodeError,
+};
+
+mod common;
+use common::header;
+
+const WIDTH: usize = 37;
+const HEIGHT: usize = 11;
+
+const REDUCE: EncodeOptions = EncodeOptions {
+    reduce_channels: true,
+};
+
+fn expand() -> DecodeOptions {
+    DecodeOptions {
+        expand_channels: true,
+        ..Default::default()
+    }
+}
+
+// RGBA pixels that are gray and/or opaque
+fn rgba(gray: bool, opaque: bool) -> Vec<u8> {
+    (0..WIDTH * HEIGHT)
+        .flat_map(|i| {
+            let v = (i * 7) as u8;
+            let a = if opaque { 255 } else { (i % 5 * 60) as u8 };
+            match gray {
+                true => [v, v, v, a],
+                false => [v, v / 3, 255 - v, a],
+            }
+        })
+        .collect()
+}
+
+// drops the channels of RGBA pixels that aren't part of `channels`
+fn select(data: &[u8], channels: Channels) -> Vec<u8> {
+    data.chunks_exact(4)
+        .flat_map(|px| match channels {
+            Channels::Gray => vec![px[0]],
+            Channels::GrayAlpha => vec![px[0], px[3]],
+            Channels::Rgb => px[..3].to_vec(),
+            _ => px.to_vec(),
+        })
+        .collect()
+}
+
+fn encode<const C: usize>(version: u32, channels: Channels, data: &[u8]) -> Vec<u8> {
+    let header = header(version, WIDTH as u64, HEIGHT as u64, channels);
+    if version == VERSION_BLOCK {
+        return encode_to_vec_with_options::<C>(data, header, CompressionLevel::Lz4Flex, &REDUCE)
+            .unwrap();
+    }
+
+    let mut out = Vec::new();
+    koi::encode_with_options::<_, _, C>(header, data, &mut out, &REDUCE).unwrap();
+    out
+}
+
+fn stored_channels(file: &[u8]) -> Channels {
+    FileHeader::read_bytes(file).unwrap().1.channels
+}
+
+// decodes with both APIs, which have to agree
+fn decode<const C: usize>(file: &[u8]) -> (Channels, Vec<u8>) {
+    let image = koi::decode_to_vec_with_options::<C>(file, &expand()).unwrap();
+
+    let mut streamed = Vec::new();
+    let header = koi::decode_with_options::<_, _, C>(file, &mut streamed, &expand()).unwrap();
+    assert_eq!(streamed, image.data);
+    assert_eq!(header.channels, image.header.channels);
+
+    (image.header.channels, image.data)
+}
+
+fn check<const C: usize>(channels: Channels, gray: bool, opaque: bool, expected: Channels) {
+    let original = rgba(gray, opaque);
+    let data = select(&original, channels);
+
+    for version in [VERSION_BLOCK, VERSION_STREAM] {
+        let file = encode::<C>(version, channels, &data);
+        assert_eq!(stored_channels(&file), expected, "{channels:?} {version}");
+
+        // decoding with the original channels expands the pixels again
+        assert_eq!(decode::<C>(&file), (channels, data.clone()));
+
+        // decoding with the stored channels returns them as they are
+        let stored = match expected.count() {
+            1 => decode::<1>(&file),
+            2 => decode::<2>(&file),
+            3 => decode::<3>(&file),
+            _ => decode::<4>(&file),
+        };
+        assert_eq!(stored, (expected, select(&original, expected)));
+    }
+}
+
+#[test]
+fn unused_channels_are_dropped() {
+    check::<4>(Channels::Rgba, true, true, Channels::Gray);
+    check::<4>(Channels::Rgba, true, false, Channels::GrayAlpha);
+    check::<4>(Channels::Rgba, false, true, Channels::Rgb);
+    check::<4>(Channels::Rgba, false, false, Channels::Rgba);
+
+    check::<3>(Channels::Rgb, true, true, Channels::Gray);
+    check::<3>(Channels::Rgb, false, true, Channels::Rgb);
+
+    check::<2>(Channels::GrayAlpha, true, true, Channels::Gray);
+    check::<2>(Channels::GrayAlpha, true, false, Channels::GrayAlpha);
+}
+
+#[test]
+fn reduced_images_drop_the_alpha_plane() {
+    let mut header = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba);
+    header.alpha_mode = AlphaMode::Plane;
+
+    let data = rgba(false, true);
+    let file =
+        encode_to_vec_with_options::<4>(&data, header, CompressionLevel::Lz4Flex, &REDUCE).unwrap();
+
+    let stored = FileHeader::read_bytes(&file).unwrap().1;
+    assert_eq!(stored.channels, Channels::Rgb);
+    assert_eq!(stored.alpha_mode, AlphaMode::Interleaved);
+    assert_eq!(decode::<4>(&file).1, data);
+}
+
+#[test]
+fn other_channels_are_kept() {
+    let data: Vec<u8> = (0..WIDTH * HEIGHT * 4).map(|i| (i / 4) as u8).collect();
+    let file = encode::<4>(VERSION_BLOCK, Channels::Cmyk, &data);
+    assert_eq!(stored_channels(&file), Channels::Cmyk);
+
+    let image = koi::decode_to_vec::<4>(&file).unwrap();
+    assert_eq!((image.header.channels, image.data), (Channels::Cmyk, data));
+}
+
+#[test]
+fn channels_are_only_expanded_on_request() {
+    let data = rgba(true, true);
+    let file = encode::<4>(VERSION_BLOCK, Channels::Rgba, &data);
+    assert_eq!(stored_channels(&file), Channels::Gray);
+
+    assert!(matches!(
+        koi::decode_to_vec::<4>(&file),
+        Err(KoiDecodeError::ChannelMismatch {
+            image: 1,
+            requested: 4
+        })
+    ));
+    assert!(matches!(
+        koi::decode::<_, _, 4>(&file[..], Vec::new()),
+        Err(KoiDecodeError::ChannelMismatch { .. })
+    ));
+    assert_eq!(decode::<4>(&file), (Channels::Rgba, data));
+}
+
+#[test]
+fn expansion_compares_color_models() {
+    let data: Vec<u8> = (0..WIDTH * HEIGHT * 4).map(|i| (i / 4) as u8).collect();
+    for channels in [Channels::Cmyk, Channels::Bands(4)] {
+        let file = encode::<4>(VERSION_BLOCK, channels, &data);
+
+        // 4 channels are 4 channels without expanding
+        let image = koi::decode_to_vec::<4>(&file).unwrap();
+        assert_eq!(
+            (image.header.channels, image.data),
+            (channels, data.clone())
+        );
+
+        // but they aren't RGBA
+        for result in [
+            koi::decode_to_vec_with_options::<4>(&file, &expand()).map(|_| ()),
+            koi::decode_with_options::<_, _, 4>(&file[..], Vec::new(), &expand()).map(|_| ()),
+        ] {
+            match result {
+                Err(KoiDecodeError::UnsupportedExpansion { from, to }) => {
+                    assert_eq!((from, to), (channels, Channels::Rgba));
+                }
+                other => panic!("unexpected result: {other:?}"),
+            }
+        }
+
+        let image = koi::decode_dynamic_with_options(&file, &expand()).unwrap();
+        assert_eq!(image.header.channels, channels);
+    }
+
+    // alpha can't be dropped
+    let file = encode::<2>(
+        VERSION_BLOCK,
+        Channels::GrayAlpha,
+        &select(&rgba(true, false), Channels::GrayAlpha),
+    );
+    assert!(matches!(
+        koi::decode_to_vec_with_options::<3>(&file, &expand()),
+        Err(KoiDecodeError::UnsupportedExpansion {
+            from: Channels::GrayAlpha,
+            to: Channels::Rgb
+        })
+    ));
+}
@@ -0,0 +1,171 @@
+// Row-aligned chunks: every chunk of the block layout covers whole rows, and the number of rows per
+// chunk is stored in the header ("r").
+
+use koi::{
+    encoder::{block::CompressionLevel, Encoder},
+    file::{FileHeader, VERSION_BLOCK},
+    types::{Channels, Compression},
+    KoiDecodeError, KoiEncodeError,
+};
+
+fn pixels(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 / 5) as u8).collect()
+}
+
+// the header and the pixel count of every chunk
+fn chunks(file: &[u8]) -> (FileHeader, Vec<u32>) {
+    let (header_len, header) = FileHeader::read_bytes(file).unwrap();
+
+    let mut data = &file[header_len..];
+    let mut pixels = Vec::new();
+    while !data.is_empty() {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        pixels.push(u32::from_le_bytes(data[4..8].try_into().unwrap()));
+        data = &data[8 + len..];
+    }
+
+    (header, pixels)
+}
+
+fn check<const C: usize>(encoder: Encoder, width: u64, height: u64) {
+    let data = pixels(width as usize * height as usize * C);
+    let file = encoder.encode_to_vec::<C>(&data).unwrap();
+
+    let (header, chunks) = chunks(&file);
+    let rows = header.rows_per_chunk.expect("rows per chunk are stored");
+    assert_eq!(Some(rows), encoder.header().rows_per_chunk);
+
+    let (last, full) = chunks.split_last().unwrap();
+    for pixels in full {
+        assert_eq!(*pixels as u64, rows as u64 * width, "{width}x{height}");
+    }
+    let last = *last as u64;
+    assert!(last.is_multiple_of(width) && last <= rows as u64 * width);
+    assert_eq!(
+        chunks.iter().map(|&p| p as u64).sum::<u64>(),
+        width * height
+    );
+
+    assert_eq!(koi::decode_to_vec::<C>(&file).unwrap().data, data);
+}
+
+#[test]
+fn chunks_contain_whole_rows() {
+    for (width, height) in [(1, 70_000), (7, 20_000), (333, 500), (50_000, 3)] {
+        check::<3>(
+            Encoder::new(width, height, Channels::Rgb).row_aligned_chunks(),
+            width,
+            height,
+        );
+    }
+
+    check::<4>(
+        Encoder::new(31, 17, Channels::Rgba).rows_per_chunk(5),
+        31,
+        17,
+    );
+    check::<1>(
+        Encoder::new(31, 17, Channels::Gray)
+            .rows_per_chunk(4)
+            .level(CompressionLevel::None),
+        31,
+        17,
+    );
+}
+
+#[test]
+fn row_aligned_chunks_use_as_many_rows_as_fit() {
+    let header = Encoder::new(1000, 1000, Channels::Rgba)
+        .row_aligned_chunks()
+        .header();
+    let rows = header.rows_per_chunk.unwrap() as usize;
+
+    let row_size = 1000 * 4;
+    assert!(rows * row_size <= 199_992 && (rows + 1) * row_size > 199_992);
+
+    // rows wider than a chunk still get one row per chunk, which the encoder rejects
+    let encoder = Encoder::new(100_000, 1, Channels::Rgba).row_aligned_chunks();
+    assert_eq!(encoder.header().rows_per_chunk, Some(1));
+    match encoder.encode_to_vec::<4>(&pixels(400_000)) {
+        Err(KoiEncodeError::InvalidHeader(message)) => {
+            assert!(message.contains("larger than a chunk"), "{message}");
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+
+    // row sizes that don't fit into usize don't overflow
+    let header = FileHeader::new(
+        VERSION_BLOCK,
+        None,
+        u64::MAX,
+        1,
+        Channels::Rgba,
+        Compression::Lz4,
+        None,
+        None,
+    )
+    .with_row_aligned_chunks();
+    assert_eq!(header.rows_per_chunk, Some(1));
+}
+
+#[test]
+fn rows_per_chunk_roundtrip_through_the_header() {
+    for (rows, stored) in [
+        (None, None),
+        (Some(1), Some(1)),
+        (Some(16), Some(16)),
+        (Some(u32::MAX), Some(i32::MAX as u32)),
+    ] {
+        let mut header = Encoder::new(8, 4, Channels::Rgb).header();
+        header.rows_per_chunk = rows;
+
+        let (_, read) = FileHeader::read_bytes(&header.write_to_vec().unwrap()).unwrap();
+        assert_eq!(read.rows_per_chunk, stored);
+    }
+}
+
+#[test]
+fn rows_per_chunk_have_to_be_positive() {
+    let mut header = Encoder::new(8, 4, Channels::Rgb).header();
+    header.rows_per_chunk = Some(7);
+    let file = header.write_to_vec().unwrap();
+
+    // the "r" field is an int32 element: type 0x10, the name and the value
+    let field = file
+        .windows(7)
+        .position(|field| field == [0x10, b'r', 0, 7, 0, 0, 0])
+        .unwrap();
+
+    for rows in [0i32, -1, i32::MIN] {
+        let mut file = file.clone();
+        file[field + 3..field + 7].copy_from_slice(&rows.to_le_bytes());
+        assert!(
+            matches!(
+                FileHeader::read_bytes(&file),
+                Err(KoiDecodeError::InvalidFileHeader(_))
+            ),
+            "{rows}"
+        );
+    }
+}
+
+#[test]
+fn chunks_ending_mid_row_are_rejected() {
+    let data = pixels(10 * 6 * 3);
+    let mut file = Encoder::new(10, 6, Channels::Rgb)
+        .rows_per_chunk(2)
+        .level(CompressionLevel::None)
+        .encode_to_vec::<3>(&data)
+        .unwrap();
+
+    // move a pixel from the first chunk to the second one
+    let (header_len, _) = FileHeader::read_bytes(&file).unwrap();
+    let first = &mut file[header_len + 4..header_len + 8];
+    assert_eq!(first, 20u32.to_le_bytes());
+    first.copy_from_slice(&19u32.to_le_bytes());
+
+    assert!(matches!(
+        koi::decode_to_vec::<3>(&file),
+        Err(KoiDecodeError::InvalidChunkLength)
+    ));
+}
@@ -0,0 +1,20 @@
+// Fixtures shared by the integration tests.
+
+use koi::{
+    file::FileHeader,
+    types::{Channels, Compression},
+};
+
+// an LZ4 compressed image without metadata
+pub fn header(version: u32, width: u64, height: u64, channels: Channels) -> FileHeader {
+    FileHeader::new(
+        version,
+        None,
+        width,
+        height,
+        channels,
+        Compression::Lz4,
+        None,
+        None,
+    )
+}
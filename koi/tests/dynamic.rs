@@ -0,0 +1,153 @@
+// Runtime channel dispatch: `encode_dynamic` and `decode_dynamic` work with any number of
+// channels, the typed APIs reject images whose channels don't match C.
+#![cfg(feature = "std")]
+
+use std::io;
+
+use koi::{
+    decoder::{
+        block::{self, BlockDecoder},
+        DecodeOptions,
+    },
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{VERSION_BLOCK, VERSION_STREAM},
+    types::{Channels, MAX_CHANNELS},
+    KoiDecodeError, KoiEncodeError,
+};
+
+mod common;
+use common::header;
+
+const WIDTH: u64 = 19;
+const HEIGHT: u64 = 7;
+
+fn pixels(channels: usize) -> Vec<u8> {
+    (0..WIDTH as usize * HEIGHT as usize * channels)
+        .map(|i| (i * 13 / 7) as u8)
+        .collect()
+}
+
+#[test]
+fn dynamic_roundtrip() {
+    for count in 1..=MAX_CHANNELS as u8 {
+        let channels = Channels::try_from(count).unwrap();
+        let data = pixels(count as usize);
+
+        for version in [VERSION_BLOCK, VERSION_STREAM] {
+            let file = koi::encode_dynamic(
+                &data,
+                header(version, WIDTH, HEIGHT, channels),
+                CompressionLevel::Lz4Flex,
+            )
+            .unwrap();
+
+            let image = koi::decode_dynamic(&file).unwrap();
+            assert_eq!(image.channels(), channels);
+            assert_eq!(image.bytes_per_pixel(), count as usize);
+            assert_eq!(image.data, data, "{channels:?} {version}");
+        }
+    }
+}
+
+#[test]
+fn invalid_channels_are_rejected() {
+    let result = koi::encode_dynamic(
+        &[],
+        header(VERSION_BLOCK, WIDTH, HEIGHT, Channels::Bands(0)),
+        CompressionLevel::Lz4Flex,
+    );
+    assert!(matches!(result, Err(KoiEncodeError::InvalidHeader(_))));
+}
+
+fn mismatch<T>(result: Result<T, KoiDecodeError>) -> (usize, usize) {
+    match result {
+        Err(KoiDecodeError::ChannelMismatch { image, requested }) => (image, requested),
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("channel mismatch not detected"),
+    }
+}
+
+#[test]
+fn typed_decoders_reject_other_channel_counts() {
+    let data = pixels(4);
+    let block = encode_to_vec::<4>(
+        &data,
+        header(VERSION_BLOCK, WIDTH, HEIGHT, Channels::Rgba),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap();
+    let mut stream = Vec::new();
+    koi::encode::<_, _, 4>(
+        header(VERSION_STREAM, WIDTH, HEIGHT, Channels::Rgba),
+        &data[..],
+        &mut stream,
+    )
+    .unwrap();
+
+    for file in [&block, &stream] {
+        assert_eq!(mismatch(koi::decode_to_vec::<3>(file)), (4, 3));
+        assert_eq!(
+            mismatch(koi::decode::<_, _, 5>(&file[..], io::sink())),
+            (4, 5)
+        );
+    }
+
+    assert_eq!(mismatch(block::decode_to_vec::<1>(&block)), (4, 1));
+    assert_eq!(mismatch(BlockDecoder::<_, 2>::new(&block[..])), (4, 2));
+}
+
+#[test]
+fn typed_encoders_reject_other_channel_counts() {
+    let data = pixels(3);
+
+    let result = encode_to_vec::<3>(
+        &data,
+        header(VERSION_BLOCK, WIDTH, HEIGHT, Channels::Rgba),
+        CompressionLevel::Lz4Flex,
+    );
+    assert!(matches!(
+        result,
+        Err(KoiEncodeError::ChannelMismatch {
+            image: 4,
+            requested: 3
+        })
+    ));
+
+    let result = koi::encode::<_, _, 3>(
+        header(VERSION_STREAM, WIDTH, HEIGHT, Channels::Gray),
+        &data[..],
+        io::sink(),
+    );
+    assert!(matches!(
+        result,
+        Err(KoiEncodeError::ChannelMismatch {
+            image: 1,
+            requested: 3
+        })
+    ));
+}
+
+// images stored with fewer channels can still be expanded (see EncodeOptions::reduce_channels)
+#[test]
+fn typed_decoders_expand_fewer_channels_on_request() {
+    let data = pixels(1);
+    let file = koi::encode_dynamic(
+        &data,
+        header(VERSION_BLOCK, WIDTH, HEIGHT, Channels::Gray),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap();
+
+    assert!(koi::decode_to_vec::<3>(&file).is_err());
+
+    let options = DecodeOptions {
+        expand_channels: true,
+        ..Default::default()
+    };
+    let image = koi::decode_to_vec_with_options::<3>(&file, &options).unwrap();
+    assert_eq!(image.header.channels, Channels::Rgb);
+    assert_eq!(
+        image.data,
+        data.iter().flat_map(|&v| [v; 3]).collect::<Vec<_>>()
+    );
+}
@@ -0,0 +1,429 @@
+// Buffer formats: the block codec reads from and writes to buffers in other pixel layouts,
+// producing the same files as for tightly packed pixels.
+
+use koi::{
+    decoder::block::{decode, decode_with_format},
+    encoder::block::{encode, encode_with_format, max_encoded_size, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK},
+    types::{AlphaMode, BufferFormat, CfaPattern, Channels, PixelLayout},
+    KoiDecodeError, KoiEncodeError,
+};
+
+mod common;
+use common::header;
+
+const WIDTH: usize = 45;
+const HEIGHT: usize = 23;
+
+fn pixels<const C: usize>() -> Vec<u8> {
+    (0..WIDTH * HEIGHT)
+        .flat_map(|i| {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            let px = [
+                (x * 5) as u8,
+                (y * 9) as u8,
+                (x ^ y) as u8,
+                [0, 255, 128][x % 3],
+            ];
+            px[..C].to_vec()
+        })
+        .collect()
+}
+
+// converts packed pixels to a layout by hand, padding is set to `padding`
+fn to_layout<const C: usize>(data: &[u8], layout: PixelLayout, padding: u8) -> Vec<u8> {
+    data.chunks_exact(C)
+        .flat_map(|px| match (layout, px) {
+            (PixelLayout::Rgba, px) => px.to_vec(),
+            (PixelLayout::Bgra, [r, g, b, a]) => vec![*b, *g, *r, *a],
+            (PixelLayout::Argb, [r, g, b, a]) => vec![*a, *r, *g, *b],
+            (PixelLayout::Abgr, [r, g, b, a]) => vec![*a, *b, *g, *r],
+            (PixelLayout::Rgbx, [r, g, b]) => vec![*r, *g, *b, padding],
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn encode_packed<const C: usize>(data: &[u8], header: FileHeader) -> Vec<u8> {
+    let mut out = vec![0; max_encoded_size::<C>(&header).unwrap()];
+    let len = encode::<C>(data, &mut out, header, CompressionLevel::Lz4Flex).unwrap();
+    out.truncate(len);
+    out
+}
+
+fn check_layout<const C: usize>(header: FileHeader, layout: PixelLayout) {
+    let data = pixels::<C>();
+    let packed = encode_packed::<C>(&data, header.clone());
+    let format = BufferFormat {
+        layout,
+        ..Default::default()
+    };
+
+    // encoding from the layout gives the same file, padding is ignored
+    let input = to_layout::<C>(&data, layout, 7);
+    let mut file = vec![0; max_encoded_size::<C>(&header).unwrap()];
+    let len = encode_with_format::<C>(
+        &input,
+        &mut file,
+        header,
+        CompressionLevel::Lz4Flex,
+        &format,
+    )
+    .unwrap();
+    assert!(file[..len] == packed, "{layout:?}");
+
+    // decoding to the layout gives the converted pixels, padding is opaque
+    let mut out = vec![0; WIDTH * HEIGHT * layout.bytes_per_pixel(C)];
+    let (len, _) = decode_with_format::<C>(&packed, &mut out, &format).unwrap();
+    assert_eq!(len, out.len());
+    assert!(out == to_layout::<C>(&data, layout, 255), "{layout:?}");
+}
+
+#[test]
+fn layouts_roundtrip() {
+    for layout in [
+        PixelLayout::Rgba,
+        PixelLayout::Bgra,
+        PixelLayout::Argb,
+        PixelLayout::Abgr,
+    ] {
+        check_layout::<4>(
+            header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+            layout,
+        );
+    }
+    check_layout::<3>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        PixelLayout::Rgbx,
+    );
+    check_layout::<3>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        PixelLayout::Rgba,
+    );
+}
+
+#[test]
+fn layouts_with_alpha_planes() {
+    let mut header = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba);
+    header.alpha_mode = AlphaMode::Plane;
+
+    for layout in [PixelLayout::Bgra, PixelLayout::Argb] {
+        check_layout::<4>(header.clone(), layout);
+    }
+}
+
+#[test]
+fn layouts_require_matching_channels() {
+    let data = pixels::<3>();
+    let mut out = vec![0; 1 << 16];
+    let format = BufferFormat {
+        layout: PixelLayout::Bgra,
+        ..Default::default()
+    };
+
+    let result = encode_with_format::<3>(
+        &data,
+        &mut out,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        CompressionLevel::Lz4Flex,
+        &format,
+    );
+    assert!(matches!(
+        result,
+        Err(KoiEncodeError::UnsupportedLayout {
+            layout: PixelLayout::Bgra,
+            channels: 3
+        })
+    ));
+
+    let file = encode_packed::<3>(
+        &data,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+    );
+    let result = decode_with_format::<3>(&file, &mut out, &format);
+    assert!(matches!(
+        result,
+        Err(KoiDecodeError::UnsupportedLayout {
+            layout: PixelLayout::Bgra,
+            channels: 3
+        })
+    ));
+
+    // RGBX is only for images without alpha
+    let file = encode_packed::<4>(
+        &pixels::<4>(),
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+    );
+    let format = BufferFormat {
+        layout: PixelLayout::Rgbx,
+        ..Default::default()
+    };
+    let result = decode_with_format::<4>(&file, &mut out, &format);
+    assert!(matches!(
+        result,
+        Err(KoiDecodeError::UnsupportedLayout { .. })
+    ));
+
+    // the default layout still decodes as before
+    let mut packed = vec![0; WIDTH * HEIGHT * 4];
+    decode::<4>(&file, &mut packed).unwrap();
+    assert_eq!(packed, pixels::<4>());
+}
+
+// the image is placed at (LEFT, TOP) of a larger buffer, PAD pixels wider than the image
+const LEFT: usize = 5;
+const TOP: usize = 2;
+const PAD: usize = 11;
+
+// copies a buffer with tightly packed rows into a sub-rectangle of a buffer filled with `fill`,
+// returns the buffer and the offset of the sub-rectangle
+fn embed(data: &[u8], bytes_per_pixel: usize, fill: u8) -> (Vec<u8>, usize) {
+    let row_size = WIDTH * bytes_per_pixel;
+    let stride = (WIDTH + PAD) * bytes_per_pixel;
+
+    let mut buffer = vec![fill; stride * (HEIGHT + TOP + 3)];
+    let offset = TOP * stride + LEFT * bytes_per_pixel;
+    for (y, row) in data.chunks_exact(row_size).enumerate() {
+        let start = offset + y * stride;
+        buffer[start..start + row_size].copy_from_slice(row);
+    }
+    (buffer, offset)
+}
+
+// reverses the order of tightly packed rows
+fn flip(data: &[u8], bytes_per_pixel: usize) -> Vec<u8> {
+    data.chunks_exact(WIDTH * bytes_per_pixel)
+        .rev()
+        .flatten()
+        .copied()
+        .collect()
+}
+
+fn check_stride<const C: usize>(header: FileHeader, layout: PixelLayout, bottom_up: bool) {
+    let data = pixels::<C>();
+    let packed = encode_packed::<C>(&data, header.clone());
+    let bytes_per_pixel = layout.bytes_per_pixel(C);
+    let format = BufferFormat {
+        layout,
+        row_stride: Some((WIDTH + PAD) * bytes_per_pixel),
+        bottom_up,
+    };
+    let arrange = |padding| {
+        let data = to_layout::<C>(&data, layout, padding);
+        match bottom_up {
+            true => flip(&data, bytes_per_pixel),
+            false => data,
+        }
+    };
+
+    // encoding from a sub-rectangle gives the same file, the bytes around it are ignored
+    let (input, offset) = embed(&arrange(7), bytes_per_pixel, 99);
+    let mut file = vec![0; max_encoded_size::<C>(&header).unwrap()];
+    let len = encode_with_format::<C>(
+        &input[offset..],
+        &mut file,
+        header,
+        CompressionLevel::Lz4Flex,
+        &format,
+    )
+    .unwrap();
+    assert!(file[..len] == packed, "{layout:?}");
+
+    // decoding into a sub-rectangle leaves the bytes around it untouched
+    let (mut out, offset) = embed(&[], bytes_per_pixel, 99);
+    let (len, header) = decode_with_format::<C>(&packed, &mut out[offset..], &format).unwrap();
+    assert_eq!(Some(len), format.buffer_size(&header));
+
+    let (expected, _) = embed(&arrange(255), bytes_per_pixel, 99);
+    assert!(out == expected, "{layout:?}");
+}
+
+#[test]
+fn strides_roundtrip() {
+    check_stride::<4>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        PixelLayout::Rgba,
+        false,
+    );
+    check_stride::<4>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        PixelLayout::Bgra,
+        false,
+    );
+    check_stride::<3>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        PixelLayout::Rgbx,
+        false,
+    );
+    check_stride::<3>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        PixelLayout::Rgba,
+        false,
+    );
+
+    let mut plane = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba);
+    plane.alpha_mode = AlphaMode::Plane;
+    check_stride::<4>(plane.clone(), PixelLayout::Rgba, false);
+    check_stride::<4>(plane, PixelLayout::Abgr, false);
+
+    let mut chunked = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb);
+    chunked.rows_per_chunk = Some(3);
+    check_stride::<3>(chunked, PixelLayout::Rgbx, false);
+}
+
+#[test]
+fn bottom_up_rows() {
+    check_stride::<4>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        PixelLayout::Rgba,
+        true,
+    );
+    check_stride::<3>(
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        PixelLayout::Rgbx,
+        true,
+    );
+
+    let mut chunked = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba);
+    chunked.rows_per_chunk = Some(4);
+    chunked.alpha_mode = AlphaMode::Plane;
+    check_stride::<4>(chunked, PixelLayout::Argb, true);
+
+    // without a stride the rows are tightly packed
+    let data = pixels::<3>();
+    let format = BufferFormat {
+        bottom_up: true,
+        ..Default::default()
+    };
+    let mut file = vec![
+        0;
+        max_encoded_size::<3>(&header(
+            VERSION_BLOCK,
+            WIDTH as u64,
+            HEIGHT as u64,
+            Channels::Rgb
+        ))
+        .unwrap()
+    ];
+    let len = encode_with_format::<3>(
+        &flip(&data, 3),
+        &mut file,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        CompressionLevel::Lz4Flex,
+        &format,
+    )
+    .unwrap();
+    assert!(
+        file[..len]
+            == encode_packed::<3>(
+                &data,
+                header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb)
+            )
+    );
+
+    let mut out = vec![0; data.len()];
+    let (len, _) = decode_with_format::<3>(&file[..len], &mut out, &format).unwrap();
+    assert_eq!(len, out.len());
+    assert_eq!(out, flip(&data, 3));
+}
+
+#[test]
+fn strides_with_cfa_data() {
+    let data: Vec<u8> = (0..WIDTH * HEIGHT * 2).map(|i| (i * 7 / 3) as u8).collect();
+    let format = BufferFormat {
+        row_stride: Some((WIDTH + PAD) * 2),
+        ..Default::default()
+    };
+
+    // predictions reach back two rows, so single rows per chunk need the previous chunks
+    for rows_per_chunk in [None, Some(1), Some(4)] {
+        let mut header = header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Gray);
+        header.cfa_pattern = Some(CfaPattern::Rggb);
+        header.bit_depth = 16;
+        header.rows_per_chunk = rows_per_chunk;
+        let packed = encode_packed::<1>(&data, header.clone());
+
+        let (input, offset) = embed(&data, 2, 99);
+        let mut file = vec![0; max_encoded_size::<1>(&header).unwrap()];
+        let len = encode_with_format::<1>(
+            &input[offset..],
+            &mut file,
+            header,
+            CompressionLevel::Lz4Flex,
+            &format,
+        )
+        .unwrap();
+        assert!(file[..len] == packed, "{rows_per_chunk:?}");
+
+        let (mut out, offset) = embed(&[], 2, 99);
+        decode_with_format::<1>(&packed, &mut out[offset..], &format).unwrap();
+        assert!(out == input, "{rows_per_chunk:?}");
+    }
+}
+
+#[test]
+fn strides_are_checked() {
+    let data = pixels::<4>();
+    let file = encode_packed::<4>(
+        &data,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+    );
+    let mut out = vec![0; 1 << 16];
+
+    // rows can't overlap
+    let format = BufferFormat {
+        row_stride: Some(WIDTH * 4 - 1),
+        ..Default::default()
+    };
+    let result = encode_with_format::<4>(
+        &data,
+        &mut out,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        CompressionLevel::Lz4Flex,
+        &format,
+    );
+    assert!(matches!(
+        result,
+        Err(KoiEncodeError::InvalidStride { row_size, .. }) if row_size == WIDTH * 4
+    ));
+    let result = decode_with_format::<4>(&file, &mut out, &format);
+    assert!(matches!(result, Err(KoiDecodeError::InvalidStride { .. })));
+
+    // the last row doesn't need padding, but has to be complete
+    let stride = WIDTH * 4 + 8;
+    let format = BufferFormat {
+        row_stride: Some(stride),
+        ..Default::default()
+    };
+    let required = (HEIGHT - 1) * stride + WIDTH * 4;
+
+    let mut decoded = vec![0; required - 1];
+    let result = decode_with_format::<4>(&file, &mut decoded, &format);
+    assert!(matches!(
+        result,
+        Err(KoiDecodeError::OutputTooSmall { required: r, .. }) if r == required
+    ));
+
+    let mut decoded = vec![0; required];
+    let (len, _) = decode_with_format::<4>(&file, &mut decoded, &format).unwrap();
+    assert_eq!(len, required);
+
+    let result = encode_with_format::<4>(
+        &decoded[..required - 1],
+        &mut out,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        CompressionLevel::Lz4Flex,
+        &format,
+    );
+    assert!(matches!(result, Err(KoiEncodeError::InvalidLength)));
+
+    let len = encode_with_format::<4>(
+        &decoded,
+        &mut out,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgba),
+        CompressionLevel::Lz4Flex,
+        &format,
+    )
+    .unwrap();
+    assert!(out[..len] == file);
+}
@@ -0,0 +1,443 @@
+// Golden test vectors: every file in tests/vectors has to decode to its expected pixels and
+// encoding these pixels has to reproduce the file byte for byte.
+//
+// Run with KOI_BLESS=1 to regenerate the files after an intentional format change.
+//
+// The files in tests/vectors/legacy were written by the encoders of versions 0 and 1 and can't be
+// regenerated, they only have to keep decoding to their expected pixels.
+#![cfg(feature = "std")]
+
+use std::{collections::BTreeSet, io, path::PathBuf};
+
+use koi::{
+    decoder::block,
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, Layout, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels, Compression},
+    KoiEncodeError,
+};
+
+#[derive(Clone, Copy, Debug)]
+enum Mode {
+    Block(CompressionLevel),
+    Stream(Compression),
+}
+
+impl Mode {
+    const ALL: [Mode; 6] = [
+        Mode::Block(CompressionLevel::None),
+        Mode::Block(CompressionLevel::Lz4Flex),
+        Mode::Block(CompressionLevel::Lz4(1)),
+        Mode::Block(CompressionLevel::Lz4Hc(4)),
+        Mode::Stream(Compression::None),
+        Mode::Stream(Compression::Lz4),
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Block(CompressionLevel::None) => "block_none",
+            Mode::Block(CompressionLevel::Lz4Flex) => "block_lz4flex",
+            Mode::Block(CompressionLevel::Lz4(_)) => "block_lz4",
+            Mode::Block(CompressionLevel::Lz4Hc(_)) => "block_lz4hc",
+            Mode::Stream(Compression::None) => "stream_none",
+            Mode::Stream(Compression::Lz4) => "stream_lz4",
+        }
+    }
+}
+
+struct Vector {
+    name: String,
+    raw: String,
+    channels: Channels,
+    width: usize,
+    height: usize,
+    rows_per_chunk: Option<u32>,
+    alpha_mode: AlphaMode,
+    cfa: Option<(CfaPattern, u8)>,
+    mode: Mode,
+}
+
+fn vectors() -> Vec<Vector> {
+    let mut vectors = Vec::new();
+
+    for channels in 1..=4u8 {
+        for mode in Mode::ALL {
+            vectors.push(Vector {
+                name: format!("ops_c{channels}_{}", mode.name()),
+                raw: format!("ops_c{channels}"),
+                channels: channels.try_into().unwrap(),
+                width: 8,
+                height: 4,
+                rows_per_chunk: None,
+                alpha_mode: AlphaMode::Interleaved,
+                cfa: None,
+                mode,
+            });
+        }
+    }
+
+    let edge_cases = [
+        ("1x1", 4, 1, 1, None, Mode::Block(CompressionLevel::None)),
+        ("1x1", 3, 1, 1, None, Mode::Stream(Compression::None)),
+        ("0x5", 3, 0, 5, None, Mode::Block(CompressionLevel::Lz4Flex)),
+        ("5x0", 1, 5, 0, None, Mode::Stream(Compression::Lz4)),
+        ("ops", 5, 8, 4, None, Mode::Block(CompressionLevel::Lz4Flex)),
+        ("ops", 16, 8, 4, None, Mode::Stream(Compression::None)),
+        (
+            "16x16_rows4",
+            4,
+            16,
+            16,
+            Some(4),
+            Mode::Block(CompressionLevel::Lz4Flex),
+        ),
+    ];
+
+    for (name, channels, width, height, rows_per_chunk, mode) in edge_cases {
+        vectors.push(Vector {
+            name: format!("{name}_c{channels}_{}", mode.name()),
+            raw: format!("{name}_c{channels}"),
+            channels: match channels {
+                5 => Channels::Cmyka,
+                _ => (channels as u8).try_into().unwrap(),
+            },
+            width,
+            height,
+            rows_per_chunk,
+            alpha_mode: AlphaMode::Interleaved,
+            cfa: None,
+            mode,
+        });
+    }
+
+    let alpha_planes = [
+        ("ops", 2, Mode::Block(CompressionLevel::None)),
+        ("ops", 4, Mode::Block(CompressionLevel::Lz4Flex)),
+        ("alpha", 4, Mode::Block(CompressionLevel::None)),
+    ];
+
+    for (name, channels, mode) in alpha_planes {
+        vectors.push(Vector {
+            name: format!("{name}_c{channels}_plane_{}", mode.name()),
+            raw: format!("{name}_c{channels}"),
+            channels: (channels as u8).try_into().unwrap(),
+            width: 8,
+            height: 4,
+            rows_per_chunk: None,
+            alpha_mode: AlphaMode::Plane,
+            cfa: None,
+            mode,
+        });
+    }
+
+    let cfa = [
+        (CfaPattern::Rggb, 8, Mode::Block(CompressionLevel::None)),
+        (CfaPattern::Gbrg, 16, Mode::Block(CompressionLevel::Lz4Flex)),
+    ];
+
+    for (pattern, bit_depth, mode) in cfa {
+        vectors.push(Vector {
+            name: format!("cfa{bit_depth}_{}_{}", pattern.name(), mode.name()),
+            raw: format!("cfa{bit_depth}"),
+            channels: Channels::Gray,
+            width: 8,
+            height: 6,
+            rows_per_chunk: None,
+            alpha_mode: AlphaMode::Interleaved,
+            cfa: Some((pattern, bit_depth)),
+            mode,
+        });
+    }
+
+    vectors
+}
+
+// RGBA pixels that trigger every op (for the channels that support it), followed by a gradient
+fn pixels(name: &str, channels: usize, width: usize, height: usize) -> Vec<u8> {
+    if name.starts_with("alpha") {
+        return binary_alpha_pixels(channels, width, height);
+    }
+    if let Some(bit_depth) = name.strip_prefix("cfa") {
+        return mosaic_samples(bit_depth.parse().unwrap(), width, height);
+    }
+
+    let ops: [[u8; 4]; 13] = [
+        [255, 255, 255, 255], // OP_SAME
+        [254, 254, 255, 255], // OP_DIFF
+        [230, 234, 236, 255], // OP_LUMA
+        [100, 100, 100, 255], // OP_GRAY
+        [100, 100, 100, 200], // OP_GRAY_ALPHA
+        [100, 100, 100, 210], // OP_DIFF_ALPHA
+        [10, 200, 30, 210],   // OP_RGB
+        [40, 50, 60, 0],      // OP_RGBA
+        [40, 50, 60, 0],      // OP_SAME
+        [41, 51, 61, 0],      // OP_DIFF, keeps alpha
+        [90, 90, 90, 0],      // OP_GRAY, keeps alpha
+        [90, 90, 90, 255],    // OP_DIFF_ALPHA (wrapping)
+        [0, 128, 255, 255],   // OP_RGB
+    ];
+
+    (0..width * height)
+        .flat_map(|i| {
+            let px = match ops.get(i) {
+                Some(px) => *px,
+                None => [(i * 7) as u8, (i * 5) as u8, (i * 3) as u8, (i * 11) as u8],
+            };
+
+            match channels {
+                1 => vec![px[0]],
+                2 => vec![px[0], px[3]],
+                3 => px[..3].to_vec(),
+                _ => (0..channels)
+                    .map(|c| match c {
+                        0..=3 => px[c],
+                        _ => (i * c) as u8 ^ px[c % 4],
+                    })
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+// RGBA gradient with runs of fully transparent and fully opaque pixels
+fn binary_alpha_pixels(channels: usize, width: usize, height: usize) -> Vec<u8> {
+    assert_eq!(channels, 4);
+    (0..width * height)
+        .flat_map(|i| {
+            let a = if i % 11 < 4 { 0 } else { 255 };
+            [(i * 7) as u8, (i * 5) as u8, (i * 3) as u8, a]
+        })
+        .collect()
+}
+
+// smooth mosaic with sensor noise, 16-bit samples are little endian
+fn mosaic_samples(bit_depth: u8, width: usize, height: usize) -> Vec<u8> {
+    (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            let value = ((x * 40 + y * 30 + (x % 2) * 50 + (i * 7) % 5) << (bit_depth - 8)) as u16;
+            match bit_depth {
+                16 => value.to_le_bytes().to_vec(),
+                _ => vec![value as u8],
+            }
+        })
+        .collect()
+}
+
+fn vector_path(name: &str, ext: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/vectors")
+        .join(format!("{name}.{ext}"))
+}
+
+fn bless() -> bool {
+    std::env::var_os("KOI_BLESS").is_some()
+}
+
+fn encode<const C: usize>(vector: &Vector, data: &[u8]) -> Vec<u8> {
+    let version = match vector.mode {
+        Mode::Block(_) => VERSION_BLOCK,
+        Mode::Stream(_) => VERSION_STREAM,
+    };
+    let compression = match vector.mode {
+        Mode::Block(CompressionLevel::None) | Mode::Stream(Compression::None) => Compression::None,
+        _ => Compression::Lz4,
+    };
+
+    let mut header = FileHeader::new(
+        version,
+        None,
+        vector.width as u64,
+        vector.height as u64,
+        vector.channels,
+        compression,
+        None,
+        None,
+    );
+    header.rows_per_chunk = vector.rows_per_chunk;
+    header.alpha_mode = vector.alpha_mode;
+    if let Some((pattern, bit_depth)) = vector.cfa {
+        header.cfa_pattern = Some(pattern);
+        header.bit_depth = bit_depth;
+    }
+
+    match vector.mode {
+        Mode::Block(level) => encode_to_vec::<C>(data, header, level).unwrap(),
+        Mode::Stream(_) => {
+            let mut out = Vec::new();
+            koi::encode::<_, _, C>(header, data, &mut out).unwrap();
+            out
+        }
+    }
+}
+
+fn decode<const C: usize>(vector: &Vector, file: &[u8]) -> Vec<u8> {
+    let image = koi::decode_to_vec::<C>(file).unwrap();
+    assert_eq!(image.header.width, vector.width as u64, "{}", vector.name);
+    assert_eq!(image.header.height, vector.height as u64, "{}", vector.name);
+    assert_eq!(image.header.channels, vector.channels, "{}", vector.name);
+
+    if let Mode::Block(_) = vector.mode {
+        let block = block::decode_to_vec::<C>(file).unwrap();
+        assert_eq!(block.data, image.data, "{}", vector.name);
+    }
+
+    image.data
+}
+
+fn check<const C: usize>(vector: &Vector) {
+    if bless() {
+        let raw = pixels(&vector.raw, C, vector.width, vector.height);
+        std::fs::write(vector_path(&vector.raw, "raw"), &raw).unwrap();
+        std::fs::write(vector_path(&vector.name, "koi"), encode::<C>(vector, &raw)).unwrap();
+    }
+
+    let raw = std::fs::read(vector_path(&vector.raw, "raw")).unwrap();
+    let file = std::fs::read(vector_path(&vector.name, "koi")).unwrap();
+
+    assert!(
+        decode::<C>(vector, &file) == raw,
+        "{}: decoding differs",
+        vector.name
+    );
+
+    // without the C library these levels are compressed with lz4_flex, which compresses differently
+    let c_level = matches!(
+        vector.mode,
+        Mode::Block(CompressionLevel::Lz4(_) | CompressionLevel::Lz4Hc(_))
+    );
+    if c_level && !cfg!(feature = "lzzzz") {
+        let file = encode::<C>(vector, &raw);
+        assert!(decode::<C>(vector, &file) == raw, "{}", vector.name);
+        return;
+    }
+
+    assert!(
+        encode::<C>(vector, &raw) == file,
+        "{}: encoding differs",
+        vector.name
+    );
+}
+
+#[test]
+fn golden_vectors() {
+    for vector in vectors() {
+        match vector.channels.count() {
+            1 => check::<1>(&vector),
+            2 => check::<2>(&vector),
+            3 => check::<3>(&vector),
+            4 => check::<4>(&vector),
+            5 => check::<5>(&vector),
+            16 => check::<16>(&vector),
+            n => panic!("no test vectors with {n} channels"),
+        }
+    }
+}
+
+fn check_legacy<const C: usize>(mode: &str) {
+    let name = format!("legacy/ops_c{C}_{mode}");
+    let raw = std::fs::read(vector_path(&format!("legacy/ops_c{C}"), "raw")).unwrap();
+    let file = std::fs::read(vector_path(&name, "koi")).unwrap();
+
+    let image = koi::decode_to_vec::<C>(&file).unwrap();
+    assert!(image.data == raw, "{name}: decoding differs");
+
+    let mut streamed = Vec::new();
+    koi::decode::<_, _, C>(&file[..], &mut streamed).unwrap();
+    assert!(streamed == raw, "{name}: streamed decoding differs");
+
+    let (layout, data) = match mode.starts_with("block") {
+        true => (
+            Layout::Block,
+            block::decode_to_vec::<C>(&file).unwrap().data,
+        ),
+        false => (Layout::Stream, image.data),
+    };
+    assert_eq!(image.header.layout().unwrap(), layout, "{name}");
+    assert!(data == raw, "{name}: block decoding differs");
+
+    // the old versions can't be written anymore
+    let header = image.header;
+    let result = match layout {
+        Layout::Block => encode_to_vec::<C>(&raw, header, CompressionLevel::None).map(|_| ()),
+        Layout::Stream => koi::encode::<_, _, C>(header, &raw[..], io::sink()),
+    };
+    assert!(
+        matches!(result, Err(KoiEncodeError::UnsupportedVersion(0 | 1))),
+        "{name}"
+    );
+}
+
+// files of versions 0 and 1 set alpha to 255 for OP_GRAY, OP_RGB and OP_DIFF
+#[test]
+fn legacy_vectors() {
+    for mode in ["block_none", "block_lz4flex", "stream_none", "stream_lz4"] {
+        check_legacy::<1>(mode);
+        check_legacy::<3>(mode);
+        check_legacy::<4>(mode);
+    }
+
+    // the block encoder of version 1 stored an alpha of 0 for every gray+alpha pixel, so there
+    // are no block vectors with 2 channels
+    for mode in ["stream_none", "stream_lz4"] {
+        check_legacy::<2>(mode);
+    }
+
+    // OP_DIFF, OP_GRAY and OP_RGB with an alpha of 0 before them decode differently
+    let raw = std::fs::read(vector_path("legacy/ops_c4", "raw")).unwrap();
+    assert!(raw != pixels("ops", 4, 8, 4));
+    assert!(raw.chunks_exact(4).skip(9).take(2).all(|px| px[3] == 255));
+}
+
+// makes sure the `ops` vectors keep exercising every op that is valid for their channel count
+#[test]
+fn golden_vectors_cover_all_ops() {
+    let expected: [&[&str]; 4] = [
+        &["same", "diff", "gray"],
+        &["same", "diff", "gray", "gray_alpha", "diff_alpha"],
+        &["same", "diff", "luma", "gray", "rgb"],
+        &[
+            "same",
+            "diff",
+            "luma",
+            "gray",
+            "gray_alpha",
+            "diff_alpha",
+            "rgb",
+            "rgba",
+        ],
+    ];
+
+    for channels in 1..=4 {
+        let file =
+            std::fs::read(vector_path(&format!("ops_c{channels}_block_none"), "koi")).unwrap();
+        let (header_len, _) = FileHeader::read_bytes(&file).unwrap();
+
+        let mut data = &file[header_len..];
+        let mut ops = BTreeSet::new();
+        while !data.is_empty() {
+            let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+            let mut chunk = &data[8..8 + len];
+            data = &data[8 + len..];
+
+            while let Some(&opcode) = chunk.first() {
+                let (name, len) = match opcode {
+                    0x00..=0x3f => ("diff", 1),
+                    0x40..=0x7f => ("luma", 2),
+                    0x80 => ("same", 1),
+                    0xc0..=0xfb => ("diff_alpha", 1),
+                    0xfc => ("gray", 2),
+                    0xfd => ("gray_alpha", 3),
+                    0xfe => ("rgb", 4),
+                    0xff => ("rgba", 5),
+                    _ => panic!("invalid opcode {opcode}"),
+                };
+                ops.insert(name);
+                chunk = &chunk[len..];
+            }
+        }
+
+        let expected: BTreeSet<_> = expected[channels - 1].iter().copied().collect();
+        assert_eq!(ops, expected, "ops_c{channels}");
+    }
+}
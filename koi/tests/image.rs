@@ -0,0 +1,153 @@
+// Integration with the `image` crate: koi files load into and save from DynamicImage like PNG.
+#![cfg(feature = "image")]
+
+use image::{
+    metadata::Orientation as ImageOrientation, DynamicImage, GenericImageView, ImageBuffer,
+    ImageDecoder, ImageError, Luma, LumaA, Rgb, Rgba,
+};
+use koi::{
+    decoder::DecodeLimits,
+    encoder::{block::CompressionLevel, Encoder},
+    file::Layout,
+    image::{decode_image, encode_image, register, KoiDecoder, KoiEncoder},
+    types::{CfaPattern, Channels, Orientation},
+};
+
+const WIDTH: u32 = 23;
+const HEIGHT: u32 = 14;
+
+fn images() -> Vec<DynamicImage> {
+    let value = |x: u32, y: u32, c: u32| (x * 11 + y * 5 + c * 70) as u8;
+    vec![
+        ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| Luma([value(x, y, 0)])).into(),
+        ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+            LumaA([value(x, y, 0), value(x, y, 1)])
+        })
+        .into(),
+        ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+            Rgb([value(x, y, 0), value(x, y, 1), value(x, y, 2)])
+        })
+        .into(),
+        ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+            Rgba([
+                value(x, y, 0),
+                value(x, y, 1),
+                value(x, y, 2),
+                (x * 20) as u8,
+            ])
+        })
+        .into(),
+    ]
+}
+
+#[test]
+fn dynamic_images_roundtrip() {
+    for image in images() {
+        let file = encode_image(&image).unwrap();
+        let decoded = decode_image(&file).unwrap();
+        assert_eq!(decoded.color(), image.color());
+        assert_eq!(decoded.dimensions(), (WIDTH, HEIGHT));
+        assert_eq!(decoded.as_bytes(), image.as_bytes(), "{:?}", image.color());
+    }
+}
+
+#[test]
+fn encoder_settings_are_kept() {
+    let image = &images()[3];
+    let settings = Encoder::new(0, 0, Channels::Gray)
+        .layout(Layout::Stream)
+        .level(CompressionLevel::None)
+        .orientation(Orientation::Rotate90);
+
+    let mut file = Vec::new();
+    let mut encoder = KoiEncoder::with_encoder(&mut file, settings);
+    image::ImageEncoder::set_exif_metadata(&mut encoder, b"Exif\0\0MM".to_vec()).unwrap();
+    image.write_with_encoder(encoder).unwrap();
+
+    let mut decoder = KoiDecoder::new(&file[..]).unwrap();
+    assert_eq!(decoder.header().layout().unwrap(), Layout::Stream);
+    assert_eq!(decoder.header().channels, Channels::Rgba);
+    assert_eq!(
+        decoder.exif_metadata().unwrap().as_deref(),
+        Some(&b"Exif\0\0MM"[..])
+    );
+    assert_eq!(decoder.orientation().unwrap(), ImageOrientation::Rotate90);
+
+    let decoded = DynamicImage::from_decoder(decoder).unwrap();
+    assert_eq!(decoded.as_bytes(), image.as_bytes());
+}
+
+#[test]
+fn registered_files_load_like_other_formats() {
+    register();
+    assert!(!register());
+
+    let image = &images()[2];
+    let file = encode_image(image).unwrap();
+    let loaded = image::load_from_memory(&file).unwrap();
+    assert_eq!(loaded.as_bytes(), image.as_bytes());
+
+    let path = std::env::temp_dir().join(format!("koi-image-{}.koi", std::process::id()));
+    std::fs::write(&path, &file).unwrap();
+    let opened = image::open(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(opened.unwrap().as_bytes(), image.as_bytes());
+}
+
+#[test]
+fn cfa_data_decodes_to_16_bit_gray() {
+    let samples: Vec<u16> = (0..WIDTH * HEIGHT).map(|i| (i * 97) as u16).collect();
+    let data: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let file = Encoder::new(WIDTH as u64, HEIGHT as u64, Channels::Gray)
+        .cfa(CfaPattern::Rggb, 16)
+        .encode_to_vec::<1>(&data)
+        .unwrap();
+
+    let decoded = decode_image(&file).unwrap();
+    assert_eq!(decoded.as_luma16().unwrap().as_raw(), &samples);
+}
+
+#[test]
+fn unsupported_images_are_rejected() {
+    let image = DynamicImage::new_rgb16(4, 4);
+    assert!(matches!(
+        encode_image(&image),
+        Err(ImageError::Unsupported(_))
+    ));
+
+    let cmyk = Encoder::new(2, 2, Channels::Cmyk)
+        .encode_to_vec::<4>(&[0; 16])
+        .unwrap();
+    assert!(matches!(
+        decode_image(&cmyk),
+        Err(ImageError::Unsupported(_))
+    ));
+
+    assert!(matches!(
+        decode_image(b"not an image"),
+        Err(ImageError::Decoding(_))
+    ));
+}
+
+#[test]
+fn decoder_limits_can_be_relaxed() {
+    let file = Encoder::new(WIDTH as u64, HEIGHT as u64, Channels::Gray)
+        .encode_to_vec::<1>(&[0; (WIDTH * HEIGHT) as usize])
+        .unwrap();
+
+    let limits = DecodeLimits {
+        max_pixels: (WIDTH * HEIGHT - 1) as u64,
+        ..Default::default()
+    };
+    assert!(matches!(
+        KoiDecoder::with_limits(&file[..], &limits),
+        Err(ImageError::Limits(_))
+    ));
+
+    let limits = DecodeLimits {
+        max_pixels: (WIDTH * HEIGHT) as u64,
+        ..limits
+    };
+    let decoder = KoiDecoder::with_limits(&file[..], &limits).unwrap();
+    assert_eq!(decoder.dimensions(), (WIDTH, HEIGHT));
+}
@@ -0,0 +1,177 @@
+// Images that don't fit into memory are encoded and decoded chunk by chunk with `BlockEncoder` and
+// `BlockDecoder`, the pixels are generated and checked on the fly.
+#![cfg(feature = "std")]
+
+use std::io::{self, Read, Write};
+
+use koi::{
+    decoder::block::BlockDecoder,
+    encoder::block::{encode_to_vec, max_encoded_size, BlockEncoder, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels},
+};
+
+mod common;
+use common::header;
+
+// procedurally generated image, every byte only depends on its position
+struct Generated {
+    width: u64,
+    channels: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl Generated {
+    fn new(header: &FileHeader) -> Self {
+        let channels = (header.channels.count() * header.bytes_per_sample()) as u64;
+        Self {
+            width: header.width,
+            channels,
+            len: header.width * header.height * channels,
+            pos: 0,
+        }
+    }
+
+    fn byte(&self, pos: u64) -> u8 {
+        let (px, c) = (pos / self.channels, pos % self.channels);
+        let (x, y) = (px % self.width, px / self.width);
+        match c {
+            0 => (x / 3) as u8,
+            1 => (y / 5) as u8,
+            2 => ((x ^ y) / 64) as u8,
+            _ => [0, 255, (x + y) as u8][((x / 97 + y / 89) % 3) as usize],
+        }
+    }
+}
+
+impl Read for Generated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.len - self.pos) as usize;
+        for (i, b) in buf[..len].iter_mut().enumerate() {
+            *b = self.byte(self.pos + i as u64);
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+// compares everything written to it with a generated image
+impl Write for Generated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for (i, b) in buf.iter().enumerate() {
+            let pos = self.pos + i as u64;
+            assert!(pos < self.len, "too many bytes");
+            assert_eq!(*b, self.byte(pos), "byte {pos} differs");
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn encode_streaming<const C: usize>(header: &FileHeader, level: CompressionLevel) -> Vec<u8> {
+    let mut encoder = BlockEncoder::<_, C>::new(Vec::new(), header.clone(), level).unwrap();
+    io::copy(&mut Generated::new(header), &mut encoder).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn decode_streaming<const C: usize>(file: &[u8], header: &FileHeader) {
+    let mut decoder = BlockDecoder::<_, C>::new(file).unwrap();
+    assert_eq!(decoder.header().width, header.width);
+
+    let mut expected = Generated::new(header);
+    io::copy(&mut decoder, &mut expected).unwrap();
+    assert_eq!(expected.pos, expected.len, "missing bytes");
+}
+
+fn roundtrip<const C: usize>(header: FileHeader) {
+    let file = encode_streaming::<C>(&header, CompressionLevel::Lz4Flex);
+    decode_streaming::<C>(&file, &header);
+
+    // the streaming encoder writes the same file as the one working on a whole image
+    let mut data = Vec::new();
+    Generated::new(&header).read_to_end(&mut data).unwrap();
+    assert!(encode_to_vec::<C>(&data, header.clone(), CompressionLevel::Lz4Flex).unwrap() == file);
+    assert!(koi::decode_to_vec::<C>(&file).unwrap().data == data);
+
+    let mut decoded = Vec::new();
+    koi::decode::<_, _, C>(&file[..], &mut decoded).unwrap();
+    assert!(decoded == data);
+}
+
+#[test]
+fn streaming_roundtrip() {
+    roundtrip::<4>(header(VERSION_BLOCK, 1500, 1100, Channels::Rgba));
+    roundtrip::<3>(header(VERSION_BLOCK, 1, 300_001, Channels::Rgb));
+    roundtrip::<1>(header(VERSION_BLOCK, 200_001, 2, Channels::Gray));
+
+    let mut rows = header(VERSION_BLOCK, 1013, 517, Channels::Rgba);
+    rows.rows_per_chunk = Some(7);
+    roundtrip::<4>(rows);
+
+    let mut plane = header(VERSION_BLOCK, 999, 301, Channels::GrayAlpha);
+    plane.alpha_mode = AlphaMode::Plane;
+    roundtrip::<2>(plane);
+
+    // CFA predictions reach back two rows, also across chunks
+    for (width, height) in [(1001, 400), (150_000, 3)] {
+        let mut cfa = header(VERSION_BLOCK, width, height, Channels::Gray);
+        cfa.cfa_pattern = Some(CfaPattern::Grbg);
+        cfa.bit_depth = 16;
+        roundtrip::<1>(cfa);
+    }
+}
+
+#[test]
+fn streaming_encoder_requires_all_pixels() {
+    let header = header(VERSION_BLOCK, 100, 100, Channels::Rgb);
+    let mut encoder =
+        BlockEncoder::<_, 3>::new(Vec::new(), header.clone(), CompressionLevel::Lz4Flex).unwrap();
+    encoder.write_all(&[0; 100 * 99 * 3]).unwrap();
+    assert!(encoder.finish().is_err());
+
+    let mut encoder =
+        BlockEncoder::<_, 3>::new(Vec::new(), header, CompressionLevel::Lz4Flex).unwrap();
+    assert!(encoder.write_all(&[0; 100 * 100 * 3 + 1]).is_err());
+}
+
+#[test]
+fn sizes_dont_overflow() {
+    // 2^32 x 2^32 pixels don't fit into usize
+    for (width, height) in [(1 << 32, 1 << 32), (u64::MAX, 2)] {
+        let header = header(VERSION_BLOCK, width, height, Channels::Rgba);
+        assert!(max_encoded_size::<4>(&header).is_err());
+        assert!(encode_to_vec::<4>(&[], header.clone(), CompressionLevel::Lz4Flex).is_err());
+        assert!(
+            BlockEncoder::<_, 4>::new(Vec::new(), header.clone(), CompressionLevel::None).is_err()
+        );
+
+        let file = header.write_to_vec().unwrap();
+        assert!(koi::decode_to_vec::<4>(&file).is_err());
+        assert!(BlockDecoder::<_, 4>::new(&file[..]).is_err());
+
+        let mut stream = header.clone();
+        stream.version = VERSION_STREAM;
+        assert!(koi::encode::<_, _, 4>(stream.clone(), io::empty(), Vec::new()).is_err());
+        assert!(koi::decode_to_vec::<4>(&stream.write_to_vec().unwrap()).is_err());
+    }
+
+    let mut rows = header(VERSION_BLOCK, u64::MAX, 1, Channels::Rgba);
+    rows.rows_per_chunk = Some(u32::MAX);
+    assert!(BlockEncoder::<_, 4>::new(Vec::new(), rows, CompressionLevel::None).is_err());
+}
+
+// 36000 x 30000 RGBA pixels (more than 4 GiB), run with `cargo test --release -- --ignored`
+#[test]
+#[ignore]
+fn more_than_4_gib() {
+    let header = header(VERSION_BLOCK, 36_000, 30_000, Channels::Rgba);
+    assert!(header.data_size().unwrap() > u32::MAX as usize);
+
+    let file = encode_streaming::<4>(&header, CompressionLevel::Lz4Flex);
+    decode_streaming::<4>(&file, &header);
+}
@@ -0,0 +1,184 @@
+#![cfg(feature = "std")]
+use koi::{
+    decoder::{block::BlockDecoder, DecodeLimits, DecodeOptions, Limit},
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::Channels,
+    KoiDecodeError,
+};
+
+mod common;
+use common::header;
+
+// a header with a large EXIF block, which counts towards the limits
+fn header_with_exif(version: u32, width: u64, height: u64) -> FileHeader {
+    FileHeader {
+        exif: Some(vec![7; 1000]),
+        ..header(version, width, height, Channels::Rgba)
+    }
+}
+
+fn image() -> Vec<u8> {
+    let data: Vec<u8> = (0..64 * 32 * 4).map(|i| (i / 5) as u8).collect();
+    encode_to_vec::<4>(
+        &data,
+        header_with_exif(VERSION_BLOCK, 64, 32),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap()
+}
+
+fn exceeded(result: Result<impl Sized, KoiDecodeError>) -> Limit {
+    match result {
+        Err(KoiDecodeError::LimitExceeded { limit, .. }) => limit,
+        Err(e) => panic!("unexpected error: {e}"),
+        Ok(_) => panic!("limit not enforced"),
+    }
+}
+
+fn decode(file: &[u8], limits: DecodeLimits) -> Result<koi::decoder::block::Image, KoiDecodeError> {
+    let options = DecodeOptions {
+        limits,
+        ..Default::default()
+    };
+    koi::decode_to_vec_with_options::<4>(file, &options)
+}
+
+fn set_limit(limits: &mut DecodeLimits, limit: Limit, value: u64) {
+    match limit {
+        Limit::Width => limits.max_width = value,
+        Limit::Height => limits.max_height = value,
+        Limit::Pixels => limits.max_pixels = value,
+        Limit::HeaderSize => limits.max_header_size = value as usize,
+        Limit::MetadataSize => limits.max_metadata_size = value as usize,
+        Limit::Alloc => limits.max_alloc = value as usize,
+    }
+}
+
+#[test]
+fn every_limit_is_enforced() {
+    let file = image();
+    let (header_len, _) = FileHeader::read_bytes(&file).unwrap();
+    let header_size = header_len - 4;
+
+    let limits = [
+        Limit::Width,
+        Limit::Height,
+        Limit::Pixels,
+        Limit::HeaderSize,
+        Limit::MetadataSize,
+        Limit::Alloc,
+    ];
+
+    for limit in limits {
+        // the smallest value that still allows the image
+        let value = match limit {
+            Limit::Width => 64,
+            Limit::Height => 32,
+            Limit::Pixels => 64 * 32,
+            Limit::HeaderSize => header_size as u64,
+            Limit::MetadataSize => 1000,
+            Limit::Alloc => koi::decode_to_vec::<4>(&file)
+                .unwrap()
+                .header
+                .min_output_size() as u64,
+        };
+
+        let mut limits = DecodeLimits::default();
+        set_limit(&mut limits, limit, value);
+        assert!(decode(&file, limits.clone()).is_ok(), "{limit}");
+
+        set_limit(&mut limits, limit, value - 1);
+        assert_eq!(exceeded(decode(&file, limits.clone())), limit);
+
+        // the streaming decoder doesn't allocate the whole image
+        if limit != Limit::Alloc {
+            assert_eq!(
+                exceeded(BlockDecoder::<_, 4>::with_limits(&file[..], &limits)),
+                limit
+            );
+
+            let options = DecodeOptions {
+                limits,
+                ..Default::default()
+            };
+            let result = koi::decode_with_options::<_, _, 4>(&file[..], Vec::new(), &options);
+            assert_eq!(exceeded(result), limit);
+        }
+    }
+}
+
+#[test]
+fn huge_dimensions_are_rejected_before_allocating() {
+    for version in [VERSION_STREAM, VERSION_BLOCK] {
+        let file = header_with_exif(version, 1_000_000, 1_000_000)
+            .write_to_vec()
+            .unwrap();
+        assert_eq!(exceeded(koi::decode_to_vec::<4>(&file)), Limit::Pixels);
+        assert_eq!(
+            exceeded(koi::decode::<_, _, 4>(&file[..], Vec::new())),
+            Limit::Pixels
+        );
+
+        let file = header_with_exif(version, 1 << 40, 1)
+            .write_to_vec()
+            .unwrap();
+        assert_eq!(exceeded(koi::decode_to_vec::<4>(&file)), Limit::Width);
+    }
+
+    let file = header_with_exif(VERSION_BLOCK, 1_000_000, 1_000_000)
+        .write_to_vec()
+        .unwrap();
+    assert_eq!(
+        exceeded(koi::decoder::block::decode_to_vec::<4>(&file)),
+        Limit::Pixels
+    );
+}
+
+#[test]
+fn block_decoder_limits_can_be_relaxed() {
+    let width = DecodeLimits::default().max_width + 1;
+    let data = vec![9; width as usize];
+    let file = encode_to_vec::<1>(
+        &data,
+        header(VERSION_BLOCK, width, 1, Channels::Gray),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap();
+
+    assert_eq!(
+        exceeded(koi::decoder::block::decode_to_vec::<1>(&file)),
+        Limit::Width
+    );
+
+    let limits = DecodeLimits {
+        max_width: width,
+        ..Default::default()
+    };
+    let image = koi::decoder::block::decode_to_vec_with_limits::<1>(&file, &limits).unwrap();
+    assert_eq!(image.data, data);
+
+    let limits = DecodeLimits {
+        max_alloc: width as usize - 1,
+        ..limits
+    };
+    assert_eq!(
+        exceeded(koi::decoder::block::decode_to_vec_with_limits::<1>(
+            &file, &limits
+        )),
+        Limit::Alloc
+    );
+}
+
+#[test]
+fn huge_header_is_rejected_before_reading() {
+    let mut file = b"KOI ".to_vec();
+    file.extend_from_slice(&i32::MAX.to_le_bytes());
+    file.extend_from_slice(&[0; 16]);
+
+    assert_eq!(exceeded(koi::decode_to_vec::<4>(&file)), Limit::HeaderSize);
+    assert_eq!(exceeded(FileHeader::read_bytes(&file)), Limit::HeaderSize);
+
+    // without limits the header is simply truncated
+    assert!(FileHeader::read_bytes_with_limits(&file, &DecodeLimits::none()).is_err());
+}
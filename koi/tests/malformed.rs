@@ -0,0 +1,267 @@
+// Malformed input has to be rejected with an error, never with a panic: every golden vector is
+// decoded truncated at every length and with every byte corrupted.
+#![cfg(feature = "std")]
+
+use std::{fs, io, path::Path};
+
+use koi::{
+    decoder::{
+        block::{self, BlockDecoder},
+        DecodeLimits, DecodeOptions,
+    },
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{Channels, Compression},
+    KoiDecodeError,
+};
+
+// keeps corrupted dimensions from allocating too much memory
+fn options() -> DecodeOptions {
+    DecodeOptions {
+        limits: DecodeLimits {
+            max_alloc: 1 << 24,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// runs every decoder over the file, only the absence of panics matters
+fn decode_all<const C: usize>(file: &[u8]) {
+    let options = options();
+    let _ = koi::decode_to_vec_with_options::<C>(file, &options);
+    let _ = koi::decode_with_options::<_, _, C>(file, io::sink(), &options);
+    let _ = block::min_output_size::<C>(file);
+
+    if let Ok(mut decoder) = BlockDecoder::<_, C>::with_limits(file, &options.limits) {
+        let _ = io::copy(&mut decoder, &mut io::sink());
+    }
+    if block::min_output_size::<C>(file).is_ok_and(|size| size <= options.limits.max_alloc) {
+        let _ = block::decode_to_vec::<C>(file);
+    }
+}
+
+fn decode_any(file: &[u8], channels: usize) {
+    match channels {
+        1 => decode_all::<1>(file),
+        2 => decode_all::<2>(file),
+        3 => decode_all::<3>(file),
+        4 => decode_all::<4>(file),
+        5 => decode_all::<5>(file),
+        16 => decode_all::<16>(file),
+        n => panic!("no test vectors with {n} channels"),
+    }
+}
+
+// including the vectors of versions 0 and 1 in tests/vectors/legacy
+fn golden_vectors() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vectors");
+    let mut files: Vec<_> = [fs::read_dir(&dir), fs::read_dir(dir.join("legacy"))]
+        .into_iter()
+        .flat_map(|entries| entries.unwrap())
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "koi"))
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).unwrap())
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+// the channel count is part of the name of the vectors, e.g. `ops_c4_block_none`, except for
+// CFA data, which always has a single channel
+fn channels(name: &str) -> usize {
+    if name.starts_with("cfa") {
+        return 1;
+    }
+
+    name.split('_')
+        .find_map(|part| part.strip_prefix('c')?.parse().ok())
+        .unwrap_or_else(|| panic!("no channel count in {name}"))
+}
+
+#[test]
+fn truncated_files_dont_panic() {
+    for (name, file) in golden_vectors() {
+        for len in 0..file.len() {
+            decode_any(&file[..len], channels(&name));
+        }
+    }
+}
+
+#[test]
+fn wrong_channel_counts_dont_panic() {
+    for (_, file) in golden_vectors() {
+        for channels in [1, 2, 3, 4, 5, 16] {
+            decode_any(&file, channels);
+        }
+    }
+}
+
+#[test]
+fn corrupted_files_dont_panic() {
+    for (name, file) in golden_vectors() {
+        let mut file = file;
+        for i in 0..file.len() {
+            for mask in [0x01, 0x10, 0x80, 0xff] {
+                file[i] ^= mask;
+                decode_any(&file, channels(&name));
+                file[i] ^= mask;
+            }
+        }
+    }
+}
+
+// an image whose ops are OP_GRAY followed by OP_SAME for every other pixel
+fn gray_image(version: u32, compression: Compression) -> (Vec<u8>, usize) {
+    let header = FileHeader::new(version, None, 8, 2, Channels::Rgb, compression, None, None);
+    let data = vec![10; 8 * 2 * 3];
+
+    let file = if version == VERSION_BLOCK {
+        encode_to_vec::<3>(&data, header, CompressionLevel::None).unwrap()
+    } else {
+        let mut file = Vec::new();
+        koi::encode::<_, _, 3>(header, &data[..], &mut file).unwrap();
+        file
+    };
+
+    let (header_len, _) = FileHeader::read_bytes(&file).unwrap();
+    (file, header_len)
+}
+
+#[test]
+fn invalid_opcodes_are_reported_with_their_offset() {
+    // the block layout has a chunk header in front of the ops
+    for (version, ops_start) in [(VERSION_BLOCK, 8), (VERSION_STREAM, 0)] {
+        let (mut file, header_len) = gray_image(version, Compression::None);
+        file[header_len + ops_start + 3] = 0x81;
+
+        match koi::decode_to_vec::<3>(&file).map(|_| ()) {
+            Err(KoiDecodeError::InvalidOpcode { opcode, offset }) => {
+                assert_eq!((opcode, offset), (0x81, 3));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+
+        match koi::decode::<_, _, 3>(&file[..], io::sink()) {
+            Err(KoiDecodeError::InvalidOpcode { opcode, offset }) => {
+                assert_eq!((opcode, offset), (0x81, 3));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn truncated_chunks_are_detected() {
+    let (file, header_len) = gray_image(VERSION_BLOCK, Compression::None);
+
+    for len in [header_len + 4, header_len + 10, file.len() - 1] {
+        let file = &file[..len];
+        assert!(
+            matches!(
+                koi::decode_to_vec::<3>(file),
+                Err(KoiDecodeError::TruncatedChunk)
+            ),
+            "{len}"
+        );
+        assert!(
+            matches!(
+                koi::decode::<_, _, 3>(file, io::sink()),
+                Err(KoiDecodeError::TruncatedChunk)
+            ),
+            "{len}"
+        );
+    }
+}
+
+// like the block layout, unlike the header it's read with
+#[test]
+fn truncated_streams_are_detected() {
+    for compression in [Compression::None, Compression::Lz4] {
+        let (file, header_len) = gray_image(VERSION_STREAM, compression);
+
+        // within the ops (or the lz4 frame) and without any ops
+        let mut lens = vec![file.len() - 12, header_len];
+        if compression == Compression::None {
+            lens.push(file.len() - 1); // within the end marker
+        }
+        for len in lens {
+            let file = &file[..len];
+            for result in [
+                koi::decode_to_vec::<3>(file).map(|_| ()),
+                koi::decode::<_, _, 3>(file, io::sink()).map(|_| ()),
+            ] {
+                assert!(
+                    matches!(result, Err(KoiDecodeError::TruncatedChunk)),
+                    "{compression:?} {len}: {result:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn stream_end_marker_is_checked() {
+    let (mut file, _) = gray_image(VERSION_STREAM, Compression::None);
+    *file.last_mut().unwrap() ^= 1;
+
+    for result in [
+        koi::decode_to_vec::<3>(&file).map(|_| ()),
+        koi::decode::<_, _, 3>(&file[..], io::sink()).map(|_| ()),
+    ] {
+        assert!(
+            matches!(result, Err(KoiDecodeError::InvalidEndOfImage)),
+            "{result:?}"
+        );
+    }
+}
+
+#[test]
+fn chunks_have_to_contain_every_pixel() {
+    let (file, header_len) = gray_image(VERSION_BLOCK, Compression::None);
+    let count = header_len + 4..header_len + 8;
+
+    for actual in [15u32, 17] {
+        let mut file = file.clone();
+        file[count.clone()].copy_from_slice(&actual.to_le_bytes());
+
+        for result in [
+            koi::decode_to_vec::<3>(&file).map(|_| ()),
+            koi::decode::<_, _, 3>(&file[..], io::sink()).map(|_| ()),
+        ] {
+            match result {
+                Err(KoiDecodeError::PixelCountMismatch {
+                    expected,
+                    actual: a,
+                }) => {
+                    assert_eq!((expected, a), (16, actual as u64));
+                }
+                other => panic!("unexpected result: {other:?}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn small_output_buffers_arent_corrupt_data() {
+    let (file, _) = gray_image(VERSION_BLOCK, Compression::None);
+
+    for size in [0, 10, 8 * 2 * 3 - 1] {
+        match block::decode::<3>(&file, &mut vec![0; size]) {
+            Err(KoiDecodeError::OutputTooSmall { size: s, required }) => {
+                assert_eq!((s, required), (size, 8 * 2 * 3));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+    assert!(block::decode::<3>(&file, &mut [0; 8 * 2 * 3]).is_ok());
+}
+
+#[test]
+fn min_output_size_rejects_garbage() {
+    assert!(block::min_output_size::<3>(b"not an image").is_err());
+    assert!(block::min_output_size::<3>(b"").is_err());
+}
@@ -0,0 +1,179 @@
+#![cfg(feature = "std")]
+use koi::{
+    decoder::DecodeOptions,
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{CfaPattern, Channels, Orientation},
+};
+
+mod common;
+use common::header;
+
+#[test]
+fn header_fields_roundtrip() {
+    let default = header(VERSION_BLOCK, 3, 2, Channels::GrayAlpha);
+    let mut header = default.clone();
+    header.orientation = Orientation::Rotate90;
+    header.pixels_per_meter = Some((3780, 2835));
+    header.pixel_aspect_ratio = Some((4, 3));
+
+    let (_, read) = FileHeader::read_bytes(&header.write_to_vec().unwrap()).unwrap();
+    assert_eq!(read.orientation, Orientation::Rotate90);
+    assert_eq!(read.pixels_per_meter, Some((3780, 2835)));
+    assert_eq!(read.pixel_aspect_ratio, Some((4, 3)));
+
+    let (_, read) = FileHeader::read_bytes(&default.write_to_vec().unwrap()).unwrap();
+    assert_eq!(read.orientation, Orientation::Normal);
+    assert_eq!(read.pixels_per_meter, None);
+    assert_eq!(read.pixel_aspect_ratio, None);
+}
+
+#[test]
+fn dpi() {
+    let mut header = header(VERSION_BLOCK, 3, 2, Channels::GrayAlpha);
+    assert_eq!(header.dpi(), None);
+
+    header.set_dpi(300.0, 72.0);
+    assert_eq!(header.pixels_per_meter, Some((11811, 2835)));
+
+    let (x, y) = header.dpi().unwrap();
+    assert!(
+        (x - 300.0).abs() < 0.01 && (y - 72.0).abs() < 0.01,
+        "{x} {y}"
+    );
+}
+
+#[test]
+fn apply_orientation() {
+    // 3x2 image, displayed as:
+    // 0 1 2
+    // 3 4 5
+    let stored: Vec<u8> = (0..6).flat_map(|v| [v, 255 - v]).collect();
+
+    let expected: [(Orientation, &[u8]); 8] = [
+        (Orientation::Normal, &[0, 1, 2, 3, 4, 5]),
+        (Orientation::FlipHorizontal, &[2, 1, 0, 5, 4, 3]),
+        (Orientation::Rotate180, &[5, 4, 3, 2, 1, 0]),
+        (Orientation::FlipVertical, &[3, 4, 5, 0, 1, 2]),
+        (Orientation::Transpose, &[0, 3, 1, 4, 2, 5]),
+        (Orientation::Rotate90, &[3, 0, 4, 1, 5, 2]),
+        (Orientation::Transverse, &[5, 2, 4, 1, 3, 0]),
+        (Orientation::Rotate270, &[2, 5, 1, 4, 0, 3]),
+    ];
+
+    for (orientation, pixels) in expected {
+        let expected: Vec<u8> = pixels.iter().flat_map(|&v| [v, 255 - v]).collect();
+
+        for version in [VERSION_STREAM, VERSION_BLOCK] {
+            let mut header = header(version, 3, 2, Channels::GrayAlpha);
+            header.orientation = orientation;
+            header.pixels_per_meter = Some((100, 200));
+            header.pixel_aspect_ratio = Some((1, 2));
+
+            let file = match version {
+                VERSION_BLOCK => {
+                    encode_to_vec::<2>(&stored, header, CompressionLevel::Lz4Flex).unwrap()
+                }
+                _ => {
+                    let mut out = Vec::new();
+                    koi::encode::<_, _, 2>(header, &stored[..], &mut out).unwrap();
+                    out
+                }
+            };
+
+            // the stored pixels are returned unchanged by default
+            let image = koi::decode_to_vec::<2>(&file).unwrap();
+            assert_eq!(image.data, stored);
+            assert_eq!(image.header.orientation, orientation);
+
+            let options = DecodeOptions {
+                apply_orientation: true,
+                ..Default::default()
+            };
+            let image = koi::decode_to_vec_with_options::<2>(&file, &options).unwrap();
+            assert_eq!(image.data, expected, "{orientation:?}");
+            assert_eq!(image.header.orientation, Orientation::Normal);
+
+            if orientation.swaps_dimensions() {
+                assert_eq!((image.header.width, image.header.height), (2, 3));
+                assert_eq!(image.header.pixels_per_meter, Some((200, 100)));
+                assert_eq!(image.header.pixel_aspect_ratio, Some((2, 1)));
+            } else {
+                assert_eq!((image.header.width, image.header.height), (3, 2));
+                assert_eq!(image.header.pixels_per_meter, Some((100, 200)));
+                assert_eq!(image.header.pixel_aspect_ratio, Some((1, 2)));
+            }
+        }
+    }
+}
+
+// the color of a sample at (x, y) of a mosaic, as the letter in the pattern's name
+fn cfa_color(pattern: CfaPattern, x: usize, y: usize) -> u8 {
+    pattern.name().as_bytes()[y % 2 * 2 + x % 2]
+}
+
+fn encode_cfa(
+    mosaic: &[u8],
+    width: usize,
+    height: usize,
+    pattern: CfaPattern,
+    orientation: Orientation,
+) -> Vec<u8> {
+    let mut header = header(VERSION_BLOCK, width as u64, height as u64, Channels::Gray);
+    header.cfa_pattern = Some(pattern);
+    header.orientation = orientation;
+    encode_to_vec::<1>(mosaic, header, CompressionLevel::Lz4Flex).unwrap()
+}
+
+#[test]
+fn apply_orientation_to_cfa_data() {
+    let orientations = [
+        Orientation::Normal,
+        Orientation::FlipHorizontal,
+        Orientation::Rotate180,
+        Orientation::FlipVertical,
+        Orientation::Transpose,
+        Orientation::Rotate90,
+        Orientation::Transverse,
+        Orientation::Rotate270,
+    ];
+
+    // odd dimensions keep the color of the first sample for some transforms, even ones don't
+    for (width, height) in [(4, 2), (5, 3), (1, 6)] {
+        for pattern in [CfaPattern::Rggb, CfaPattern::Gbrg] {
+            let mosaic: Vec<u8> = (0..width * height)
+                .map(|i| cfa_color(pattern, i % width, i / width))
+                .collect();
+
+            for orientation in orientations {
+                let file = encode_cfa(&mosaic, width, height, pattern, orientation);
+
+                let options = DecodeOptions {
+                    apply_orientation: true,
+                    ..Default::default()
+                };
+                let image = koi::decode_to_vec_with_options::<1>(&file, &options).unwrap();
+
+                // every sample has the color the returned pattern gives its position
+                let pattern = image.header.cfa_pattern.unwrap();
+                let out_width = image.header.width as usize;
+                for (i, &color) in image.data.iter().enumerate() {
+                    assert_eq!(
+                        color,
+                        cfa_color(pattern, i % out_width, i / out_width),
+                        "{width}x{height} {orientation:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    // e.g. RGGB turned upside down starts with the last blue sample
+    let file = encode_cfa(&[0; 8], 4, 2, CfaPattern::Rggb, Orientation::Rotate180);
+    let options = DecodeOptions {
+        apply_orientation: true,
+        ..Default::default()
+    };
+    let image = koi::decode_to_vec_with_options::<1>(&file, &options).unwrap();
+    assert_eq!(image.header.cfa_pattern, Some(CfaPattern::Bggr));
+}
@@ -0,0 +1,107 @@
+// Probing: the header alone tells the size of the decoded pixels, so the output buffer can be
+// allocated exactly once.
+#![cfg(feature = "std")]
+
+use koi::{
+    decoder::block::{decode, decode_with_format, min_output_size},
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, Layout, VERSION_BLOCK, VERSION_STREAM},
+    types::{BufferFormat, Channels, Compression, PixelLayout},
+};
+
+mod common;
+use common::header;
+
+const WIDTH: usize = 29;
+const HEIGHT: usize = 13;
+
+fn pixels(channels: usize) -> Vec<u8> {
+    (0..WIDTH * HEIGHT * channels)
+        .map(|i| (i * 11 / 5) as u8)
+        .collect()
+}
+
+#[test]
+fn probe_reads_the_header() {
+    let exif = b"Exif\0\0MM".to_vec();
+    let data = pixels(3);
+    let header = FileHeader {
+        exif: Some(exif),
+        ..header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb)
+    };
+    let file = encode_to_vec::<3>(&data, header, CompressionLevel::Lz4Flex).unwrap();
+
+    let info = koi::probe(&file).unwrap();
+    assert_eq!((info.width, info.height), (WIDTH as u64, HEIGHT as u64));
+    assert_eq!(info.channels, Channels::Rgb);
+    assert_eq!(info.bit_depth, 8);
+    assert_eq!(info.compression, Compression::Lz4);
+    assert_eq!(info.layout, Layout::Block);
+    assert!(info.has_exif);
+    assert_eq!(info.header_size, FileHeader::read_bytes(&file).unwrap().0);
+
+    // only the header is needed
+    let info = koi::probe(&file[..info.header_size]).unwrap();
+    assert_eq!(info.data_size(), Some(data.len()));
+
+    let mut stream = Vec::new();
+    koi::encode::<_, _, 3>(
+        self::header(VERSION_STREAM, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        &data[..],
+        &mut stream,
+    )
+    .unwrap();
+    let info = koi::probe(&stream).unwrap();
+    assert_eq!(info.layout, Layout::Stream);
+    assert!(!info.has_exif);
+
+    assert!(koi::probe(b"not an image").is_err());
+    assert!(koi::probe(&file[..info.header_size - 1]).is_err());
+}
+
+#[test]
+fn output_sizes_are_exact() {
+    let data = pixels(3);
+    let file = encode_to_vec::<3>(
+        &data,
+        header(VERSION_BLOCK, WIDTH as u64, HEIGHT as u64, Channels::Rgb),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap();
+    let info = koi::probe(&file).unwrap();
+
+    // the decoded pixels fill the buffer completely
+    let mut out = vec![0; info.data_size().unwrap()];
+    assert_eq!(decode::<3>(&file, &mut out).unwrap().0, out.len());
+    assert_eq!(out, data);
+    assert_eq!(min_output_size::<3>(&file).unwrap(), data.len());
+    assert_eq!(
+        koi::decode_to_vec::<3>(&file).unwrap().data.len(),
+        data.len()
+    );
+
+    let padded = BufferFormat {
+        layout: PixelLayout::Rgbx,
+        row_stride: Some(WIDTH * 4 + 12),
+        ..Default::default()
+    };
+    for format in [BufferFormat::default(), padded] {
+        let size = info.output_size(&format).unwrap();
+        let mut out = vec![0; size];
+        assert_eq!(
+            decode_with_format::<3>(&file, &mut out, &format).unwrap().0,
+            size
+        );
+    }
+    assert_eq!(
+        info.output_size(&padded),
+        Some((HEIGHT - 1) * (WIDTH * 4 + 12) + WIDTH * 4)
+    );
+
+    // layouts the image can't be decoded to have no size
+    let bgra = BufferFormat {
+        layout: PixelLayout::Bgra,
+        ..Default::default()
+    };
+    assert_eq!(info.output_size(&bgra), None);
+}
@@ -0,0 +1,177 @@
+// Property based round trips: random images in every layout and compression level have to decode
+// to exactly the pixels they were encoded from.
+#![cfg(feature = "std")]
+
+use std::io;
+
+use koi::{
+    decoder::block::{self, BlockDecoder},
+    encoder::block::{encode_to_vec, CompressionLevel},
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{Channels, Compression},
+};
+use proptest::prelude::*;
+
+#[derive(Clone, Debug)]
+enum Distribution {
+    Noise(Vec<u8>),
+    // every channel increases by its own step per pixel in x and y
+    Gradient { dx: [u8; 4], dy: [u8; 4] },
+    // runs of the same pixel, with a new random color after every run
+    Runs { len: usize, colors: Vec<[u8; 4]> },
+    // mostly opaque or transparent pixels with a few partially transparent ones at the edges
+    AlphaEdges { period: usize, color: [u8; 4] },
+}
+
+impl Distribution {
+    fn pixels(&self, width: usize, height: usize, channels: usize) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width.max(1), i / width.max(1));
+                let px: [u8; 4] = match self {
+                    Distribution::Noise(noise) => {
+                        let start = i * channels;
+                        return (start..start + channels)
+                            .map(|j| noise[j % noise.len()])
+                            .collect();
+                    }
+                    Distribution::Gradient { dx, dy } => std::array::from_fn(|c| {
+                        (x as u8)
+                            .wrapping_mul(dx[c])
+                            .wrapping_add((y as u8).wrapping_mul(dy[c]))
+                    }),
+                    Distribution::Runs { len, colors } => colors[i / len % colors.len()],
+                    Distribution::AlphaEdges { period, color } => {
+                        let a = match (x + y) % period {
+                            0 => (x * 37) as u8,
+                            p if p < period / 2 => 0,
+                            _ => 255,
+                        };
+                        [color[0], color[1], color[2], a]
+                    }
+                };
+
+                // the last channel is the alpha channel for images with two channels
+                match channels {
+                    2 => vec![px[0], px[3]],
+                    _ => px[..channels].to_vec(),
+                }
+            })
+            .collect()
+    }
+}
+
+fn distribution() -> impl Strategy<Value = Distribution> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 1..256).prop_map(Distribution::Noise),
+        (any::<[u8; 4]>(), any::<[u8; 4]>()).prop_map(|(dx, dy)| Distribution::Gradient { dx, dy }),
+        (1..64usize, prop::collection::vec(any::<[u8; 4]>(), 1..8))
+            .prop_map(|(len, colors)| Distribution::Runs { len, colors }),
+        (2..24usize, any::<[u8; 4]>())
+            .prop_map(|(period, color)| Distribution::AlphaEdges { period, color }),
+    ]
+}
+
+#[derive(Clone, Debug)]
+enum Mode {
+    Stream(Compression),
+    Block(CompressionLevel, Option<u32>), // compression level and rows per chunk
+}
+
+fn mode() -> impl Strategy<Value = Mode> {
+    let level = prop_oneof![
+        Just(CompressionLevel::None),
+        Just(CompressionLevel::Lz4Flex),
+        (1..=12i32).prop_map(CompressionLevel::Lz4),
+        (1..=12i32).prop_map(CompressionLevel::Lz4Hc),
+    ];
+
+    prop_oneof![
+        Just(Mode::Stream(Compression::None)),
+        Just(Mode::Stream(Compression::Lz4)),
+        (level, prop::option::of(1..8u32)).prop_map(|(level, rows)| Mode::Block(level, rows)),
+    ]
+}
+
+fn encode<const C: usize>(data: &[u8], width: usize, height: usize, mode: &Mode) -> Vec<u8> {
+    let (version, compression) = match mode {
+        Mode::Stream(compression) => (VERSION_STREAM, *compression),
+        Mode::Block(CompressionLevel::None, _) => (VERSION_BLOCK, Compression::None),
+        Mode::Block(_, _) => (VERSION_BLOCK, Compression::Lz4),
+    };
+
+    let mut header = FileHeader::new(
+        version,
+        None,
+        width as u64,
+        height as u64,
+        Channels::try_from(C as u8).unwrap(),
+        compression,
+        None,
+        None,
+    );
+
+    match mode {
+        Mode::Stream(_) => {
+            let mut file = Vec::new();
+            koi::encode::<_, _, C>(header, data, &mut file).unwrap();
+            file
+        }
+        Mode::Block(level, rows_per_chunk) => {
+            header.rows_per_chunk = *rows_per_chunk;
+            encode_to_vec::<C>(data, header, *level).unwrap()
+        }
+    }
+}
+
+fn roundtrip<const C: usize>(
+    width: usize,
+    height: usize,
+    distribution: &Distribution,
+    mode: &Mode,
+) -> Result<(), TestCaseError> {
+    let data = distribution.pixels(width, height, C);
+    let file = encode::<C>(&data, width, height, mode);
+
+    let image = koi::decode_to_vec::<C>(&file).unwrap();
+    prop_assert_eq!(&image.data, &data, "decode_to_vec");
+
+    let mut streamed = Vec::new();
+    koi::decode::<_, _, C>(&file[..], &mut streamed).unwrap();
+    prop_assert_eq!(&streamed, &data, "decode");
+
+    if let Mode::Block(..) = mode {
+        prop_assert_eq!(
+            &block::decode_to_vec::<C>(&file).unwrap().data,
+            &data,
+            "block"
+        );
+
+        let mut chunked = Vec::new();
+        let mut decoder = BlockDecoder::<_, C>::new(&file[..]).unwrap();
+        io::copy(&mut decoder, &mut chunked).unwrap();
+        prop_assert_eq!(&chunked, &data, "BlockDecoder");
+    }
+
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn decode_encode_is_lossless(
+        width in 0..48usize,
+        height in 0..48usize,
+        channels in 1..=4usize,
+        distribution in distribution(),
+        mode in mode(),
+    ) {
+        match channels {
+            1 => roundtrip::<1>(width, height, &distribution, &mode)?,
+            2 => roundtrip::<2>(width, height, &distribution, &mode)?,
+            3 => roundtrip::<3>(width, height, &distribution, &mode)?,
+            _ => roundtrip::<4>(width, height, &distribution, &mode)?,
+        }
+    }
+}
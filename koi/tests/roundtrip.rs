@@ -0,0 +1,470 @@
+#![cfg(feature = "std")]
+
+use koi::{
+    decoder::{block, PixelDecoder},
+    encoder::{
+        block::{encode, encode_to_vec, max_encoded_size, CompressionLevel},
+        PixelEncoder,
+    },
+    file::{FileHeader, VERSION_BLOCK, VERSION_STREAM},
+    types::{AlphaMode, CfaPattern, Channels, Compression},
+    KoiEncodeError,
+};
+
+const WIDTH: usize = 67;
+const HEIGHT: usize = 45;
+
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u8 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as u8
+    }
+}
+
+// test images covering all ops: noise, smooth gradients, runs, gray areas and changing alpha
+fn images<const C: usize>() -> Vec<(&'static str, Vec<u8>)> {
+    let mut rng = Rng(0x1234_5678);
+    let pixels = WIDTH * HEIGHT;
+
+    let noise = (0..pixels * C).map(|_| rng.next()).collect();
+    let gradient = (0..pixels)
+        .flat_map(|i| {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            [(x * 3) as u8, (y * 5) as u8, (x + y) as u8, 255 - x as u8][..C].to_vec()
+        })
+        .collect();
+    let small_diffs = (0..pixels * C)
+        .map(|i| ((i / 7) as u8).wrapping_add(rng.next() % 3))
+        .collect();
+    let runs = (0..pixels)
+        .flat_map(|i| {
+            let v = (i / 13 * 41) as u8;
+            [v, v, v / 2, 255][..C].to_vec()
+        })
+        .collect();
+    let alpha_edges = (0..pixels)
+        .flat_map(|i| {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            let a = match (x + y) % 20 {
+                0..=7 => 0,
+                8..=9 => (x * 20) as u8,
+                10..=11 => 128,
+                _ => 255,
+            };
+            let gray = (y * 3) as u8;
+            let px = [gray, if x % 9 == 0 { gray } else { x as u8 }, gray, a];
+            match C {
+                2 => vec![px[0], px[3]],
+                _ => px[..C].to_vec(),
+            }
+        })
+        .collect();
+
+    vec![
+        ("noise", noise),
+        ("gradient", gradient),
+        ("small_diffs", small_diffs),
+        ("runs", runs),
+        ("alpha_edges", alpha_edges),
+    ]
+}
+
+fn header<const C: usize>(version: u32, compression: Compression) -> FileHeader {
+    FileHeader::new(
+        version,
+        None,
+        WIDTH as u64,
+        HEIGHT as u64,
+        (C as u8).try_into().unwrap(),
+        compression,
+        None,
+        None,
+    )
+}
+
+fn encode_stream<const C: usize>(data: &[u8], compression: Compression) -> Vec<u8> {
+    let mut out = Vec::new();
+    koi::encode::<_, _, C>(header::<C>(VERSION_STREAM, compression), data, &mut out).unwrap();
+    out
+}
+
+fn encode_block<const C: usize>(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let compression = match level {
+        CompressionLevel::None => Compression::None,
+        _ => Compression::Lz4,
+    };
+    encode_to_vec::<C>(data, header::<C>(VERSION_BLOCK, compression), level).unwrap()
+}
+
+// raw pixel ops of an uncompressed file, without the header and chunk headers
+fn ops(file: &[u8]) -> Vec<u8> {
+    let (header_len, header) = FileHeader::read_bytes(file).unwrap();
+    let mut data = &file[header_len..];
+
+    if header.version == VERSION_STREAM {
+        return data[..data.len() - 4].to_vec();
+    }
+
+    let mut ops = Vec::new();
+    while !data.is_empty() {
+        let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+        ops.extend_from_slice(&data[8..8 + len]);
+        data = &data[8 + len..];
+    }
+    ops
+}
+
+fn roundtrip<const C: usize>() {
+    for (name, image) in images::<C>() {
+        for level in [
+            CompressionLevel::None,
+            CompressionLevel::Lz4Flex,
+            CompressionLevel::Lz4(1),
+            CompressionLevel::Lz4Hc(4),
+        ] {
+            let encoded = encode_block::<C>(&image, level);
+            let decoded = block::decode_to_vec::<C>(&encoded).unwrap();
+            assert_eq!(decoded.data, image, "block {name} {level:?} C={C}");
+        }
+
+        for compression in [Compression::None, Compression::Lz4] {
+            let encoded = encode_stream::<C>(&image, compression);
+            let mut decoded = Vec::new();
+            koi::decode::<_, _, C>(&encoded[..], &mut decoded).unwrap();
+            assert_eq!(decoded, image, "stream {name} {compression:?} C={C}");
+        }
+    }
+}
+
+fn cross_api<const C: usize>() {
+    for (name, image) in images::<C>() {
+        // ops written by the block encoder, read by the stream decoder
+        let block_ops = ops(&encode_block::<C>(&image, CompressionLevel::None));
+        let mut stream = block_ops.clone();
+        stream.extend_from_slice(&[0; 4]);
+
+        let mut decoded = Vec::new();
+        PixelDecoder::<_, C>::new_uncompressed(&stream[..], WIDTH * HEIGHT)
+            .decode(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, image, "block -> stream {name} C={C}");
+
+        // ops written by the stream encoder, read by the block decoder
+        let stream_ops = ops(&encode_stream::<C>(&image, Compression::None));
+        assert_eq!(stream_ops, block_ops, "ops differ {name} C={C}");
+
+        let mut file = header::<C>(VERSION_BLOCK, Compression::None)
+            .write_to_vec()
+            .unwrap();
+        file.extend_from_slice(&(stream_ops.len() as u32).to_le_bytes());
+        file.extend_from_slice(&((WIDTH * HEIGHT) as u32).to_le_bytes());
+        file.extend_from_slice(&stream_ops);
+
+        let decoded = block::decode_to_vec::<C>(&file).unwrap();
+        assert_eq!(decoded.data, image, "stream -> block {name} C={C}");
+    }
+}
+
+#[test]
+fn roundtrip_all_channels() {
+    roundtrip::<1>();
+    roundtrip::<2>();
+    roundtrip::<3>();
+    roundtrip::<4>();
+}
+
+#[test]
+fn cross_api_all_channels() {
+    cross_api::<1>();
+    cross_api::<2>();
+    cross_api::<3>();
+    cross_api::<4>();
+}
+
+#[test]
+fn stream_encoder_in_small_writes() {
+    let (_, image) = images::<3>().remove(0);
+    let header = header::<3>(VERSION_STREAM, Compression::Lz4);
+
+    let mut out = Vec::new();
+    header.write(&mut out).unwrap();
+    let mut encoder = PixelEncoder::<_, 3>::new_lz4(&mut out, WIDTH * HEIGHT);
+    for chunk in image.chunks(7) {
+        std::io::Write::write_all(&mut encoder, chunk).unwrap();
+    }
+    std::io::Write::flush(&mut encoder).unwrap();
+    drop(encoder);
+
+    let decoded = koi::decode_to_vec::<3>(&out).unwrap();
+    assert_eq!(decoded.data, image);
+}
+
+#[test]
+fn small_output_buffers_are_rejected() {
+    let (_, image) = images::<4>().remove(0);
+    for level in [CompressionLevel::None, CompressionLevel::Lz4Flex] {
+        let file = encode_block::<4>(&image, level);
+        let header = FileHeader::read_bytes(&file).unwrap().1;
+
+        // too small for the header, a chunk header and the chunk itself
+        for len in [0, 10, 100, file.len() / 2, file.len() - 1] {
+            let mut out = vec![0; len];
+            assert!(
+                matches!(
+                    encode::<4>(&image, &mut out, header.clone(), level),
+                    Err(KoiEncodeError::InvalidLength)
+                ),
+                "{level:?} {len}"
+            );
+        }
+
+        let mut out = vec![0; max_encoded_size::<4>(&header).unwrap()];
+        let len = encode::<4>(&image, &mut out, header, level).unwrap();
+        assert!(out[..len] == file);
+    }
+}
+
+#[test]
+fn pixel_data_has_to_match_the_header() {
+    let (_, image) = images::<3>().remove(0);
+    let block = header::<3>(VERSION_BLOCK, Compression::Lz4);
+
+    for data in [&image[..image.len() - 3], &image[..image.len() - 1], &[]] {
+        assert!(matches!(
+            encode_to_vec::<3>(data, block.clone(), CompressionLevel::Lz4Flex),
+            Err(KoiEncodeError::InvalidLength)
+        ));
+    }
+
+    // the stream layout checks the length as well instead of writing an undecodable file
+    let mut long = image.clone();
+    long.extend_from_slice(&[7; 30]);
+    for data in [&image[..image.len() - 3], &image[..9], &[], &long] {
+        for compression in [Compression::None, Compression::Lz4] {
+            let stream = header::<3>(VERSION_STREAM, compression);
+            assert!(matches!(
+                koi::encode::<_, _, 3>(stream, data, Vec::new()),
+                Err(KoiEncodeError::InvalidLength)
+            ));
+        }
+    }
+}
+
+#[test]
+fn stream_encoder_rejects_extra_pixels() {
+    let mut out = Vec::new();
+    let mut encoder = PixelEncoder::<_, 3>::new_uncompressed(&mut out, 2);
+    std::io::Write::write_all(&mut encoder, &[1, 2, 3, 4]).unwrap();
+
+    let error = std::io::Write::write(&mut encoder, &[5, 6, 7]).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    std::io::Write::write_all(&mut encoder, &[5, 6]).unwrap();
+    assert!(std::io::Write::write(&mut encoder, &[8]).is_err());
+    drop(encoder);
+
+    let mut decoded = Vec::new();
+    let mut decoder = PixelDecoder::<_, 3>::new_uncompressed(&out[..], 2);
+    std::io::Read::read_to_end(&mut decoder, &mut decoded).unwrap();
+    assert_eq!(decoded, [1, 2, 3, 4, 5, 6]);
+}
+
+#[test]
+fn stream_encoder_reports_incomplete_pixels() {
+    let mut encoder = PixelEncoder::<_, 3>::new_uncompressed(Vec::new(), 2);
+    std::io::Write::write_all(&mut encoder, &[1, 2, 3, 4, 5]).unwrap();
+
+    let error = std::io::Write::flush(&mut encoder).unwrap_err();
+    assert!(error.to_string().contains("2 bytes"), "{error}");
+}
+
+fn roundtrip_bands<const C: usize>() {
+    let mut rng = Rng(0x9e37_79b9);
+    let image: Vec<u8> = (0..WIDTH * HEIGHT * C)
+        .map(|i| match (i / C) % 3 {
+            0 => rng.next(),
+            1 => (i / C / 50) as u8,
+            _ => (i % C * 16) as u8 ^ (rng.next() % 4),
+        })
+        .collect();
+
+    let encoded = encode_block::<C>(&image, CompressionLevel::Lz4Flex);
+    let decoded = block::decode_to_vec::<C>(&encoded).unwrap();
+    assert_eq!(decoded.data, image, "block C={C}");
+
+    let encoded = encode_stream::<C>(&image, Compression::Lz4);
+    let decoded = koi::decode_to_vec::<C>(&encoded).unwrap();
+    assert_eq!(decoded.data, image, "stream C={C}");
+}
+
+#[test]
+fn roundtrip_additional_channels() {
+    roundtrip_bands::<5>();
+    roundtrip_bands::<7>();
+    roundtrip_bands::<16>();
+}
+
+fn roundtrip_alpha_plane<const C: usize>(channels: Channels, image: &[u8], name: &str) {
+    for level in [CompressionLevel::None, CompressionLevel::Lz4Flex] {
+        let compression = match level {
+            CompressionLevel::None => Compression::None,
+            _ => Compression::Lz4,
+        };
+        let mut header = header::<C>(VERSION_BLOCK, compression);
+        header.channels = channels;
+        header.alpha_mode = AlphaMode::Plane;
+
+        let encoded = encode_to_vec::<C>(image, header, level).unwrap();
+        let decoded = koi::decode_to_vec::<C>(&encoded).unwrap();
+        assert_eq!(decoded.header.alpha_mode, AlphaMode::Plane);
+        assert_eq!(decoded.data, image, "alpha plane {name} {level:?} C={C}");
+    }
+}
+
+#[test]
+fn roundtrip_alpha_planes() {
+    for (name, image) in images::<2>() {
+        roundtrip_alpha_plane::<2>(Channels::GrayAlpha, &image, name);
+    }
+    for (name, image) in images::<4>() {
+        roundtrip_alpha_plane::<4>(Channels::Rgba, &image, name);
+    }
+
+    let mut rng = Rng(0x0bad_cafe);
+    let cmyka: Vec<u8> = (0..WIDTH * HEIGHT * 5)
+        .map(|i| match i % 5 {
+            4 => [0, 255][(i / 5 / 17) % 2],
+            _ => rng.next(),
+        })
+        .collect();
+    roundtrip_alpha_plane::<5>(Channels::Cmyka, &cmyka, "cmyka");
+}
+
+#[test]
+fn alpha_plane_requires_alpha_channel() {
+    let (_, image) = images::<3>().remove(0);
+    let mut rgb = header::<3>(VERSION_BLOCK, Compression::None);
+    rgb.alpha_mode = AlphaMode::Plane;
+    assert!(encode_to_vec::<3>(&image, rgb, CompressionLevel::None).is_err());
+
+    let (_, image) = images::<4>().remove(0);
+    let mut stream = header::<4>(VERSION_STREAM, Compression::None);
+    stream.alpha_mode = AlphaMode::Plane;
+    assert!(koi::encode::<_, _, 4>(stream, &image[..], Vec::new()).is_err());
+}
+
+// smooth scene sampled through a Bayer filter with a bit of sensor noise
+fn mosaic(pattern: CfaPattern, bit_depth: u8, width: usize, height: usize) -> Vec<u8> {
+    let mut rng = Rng(0x5eed_1234);
+    let max = (1u32 << bit_depth) - 1;
+
+    (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            // red and blue alternate between rows, red is in the first row for RGGB and GRBG
+            let red_row = (y % 2 == 0) == matches!(pattern, CfaPattern::Rggb | CfaPattern::Grbg);
+            let scene = match (pattern.is_green(x, y), red_row) {
+                (true, _) => x + y * 2,
+                (false, true) => x * 3 + y,
+                (false, false) => x * y / 8,
+            } as u32;
+            let value = (scene * (max / 255 + 1) + rng.next() as u32 % 4).min(max);
+
+            match bit_depth {
+                16 => (value as u16).to_le_bytes().to_vec(),
+                _ => vec![value as u8],
+            }
+        })
+        .collect()
+}
+
+fn cfa_header(pattern: CfaPattern, bit_depth: u8, width: usize, height: usize) -> FileHeader {
+    let mut header = FileHeader::new(
+        VERSION_BLOCK,
+        None,
+        width as u64,
+        height as u64,
+        Channels::Gray,
+        Compression::Lz4,
+        None,
+        None,
+    );
+    header.cfa_pattern = Some(pattern);
+    header.bit_depth = bit_depth;
+    header
+}
+
+#[test]
+fn roundtrip_cfa() {
+    let patterns = [
+        CfaPattern::Rggb,
+        CfaPattern::Bggr,
+        CfaPattern::Grbg,
+        CfaPattern::Gbrg,
+    ];
+
+    for pattern in patterns {
+        for bit_depth in [8, 16] {
+            // multiple chunks, odd sizes, and single rows or columns
+            for (width, height, rows_per_chunk) in [
+                (WIDTH, HEIGHT, None),
+                (WIDTH, HEIGHT, Some(3)),
+                (701, 301, None),
+                (1, 9, None),
+                (9, 1, None),
+            ] {
+                let image = mosaic(pattern, bit_depth, width, height);
+                let mut header = cfa_header(pattern, bit_depth, width, height);
+                header.rows_per_chunk = rows_per_chunk;
+
+                let encoded =
+                    encode_to_vec::<1>(&image, header, CompressionLevel::Lz4Flex).unwrap();
+                let decoded = koi::decode_to_vec::<1>(&encoded).unwrap();
+                assert_eq!(decoded.header.cfa_pattern, Some(pattern));
+                assert_eq!(decoded.header.bit_depth, bit_depth);
+                assert!(
+                    decoded.data == image,
+                    "cfa {pattern:?} {bit_depth} bits {width}x{height} {rows_per_chunk:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn cfa_predicts_from_same_color_neighbors() {
+    let image = mosaic(CfaPattern::Rggb, 8, 256, 256);
+
+    let cfa = encode_to_vec::<1>(
+        &image,
+        cfa_header(CfaPattern::Rggb, 8, 256, 256),
+        CompressionLevel::Lz4Flex,
+    )
+    .unwrap();
+
+    let mut header = cfa_header(CfaPattern::Rggb, 8, 256, 256);
+    header.cfa_pattern = None;
+    let gray = encode_to_vec::<1>(&image, header, CompressionLevel::Lz4Flex).unwrap();
+
+    assert!(cfa.len() < gray.len(), "{} >= {}", cfa.len(), gray.len());
+}
+
+#[test]
+fn cfa_requires_block_layout_and_single_channel() {
+    let image = mosaic(CfaPattern::Bggr, 16, WIDTH, HEIGHT);
+
+    let mut stream = cfa_header(CfaPattern::Bggr, 16, WIDTH, HEIGHT);
+    stream.version = VERSION_STREAM;
+    assert!(koi::encode::<_, _, 1>(stream, &image[..], Vec::new()).is_err());
+
+    let mut rgb = cfa_header(CfaPattern::Bggr, 8, WIDTH / 3, HEIGHT);
+    rgb.channels = Channels::Rgb;
+    assert!(encode_to_vec::<3>(&image, rgb, CompressionLevel::None).is_err());
+
+    let mut gray16 = cfa_header(CfaPattern::Bggr, 16, WIDTH, HEIGHT);
+    gray16.cfa_pattern = None;
+    assert!(encode_to_vec::<1>(&image, gray16, CompressionLevel::Lz4Flex).is_err());
+}
@@ -1,10 +1,23 @@
-use crate::util::cold;
+use crate::{file::FileHeader, layout::Frame, util::cold};
 
 // magic number to identify koi files
 pub(crate) const MAGIC: [u8; 4] = *b"KOI ";
+#[cfg(feature = "std")]
 pub(crate) const END_OF_IMAGE: [u8; 4] = 0u32.to_le_bytes();
 pub(crate) const MAX_CHUNK_SIZE: usize = 199992; // about 200kb
 
+// every op is at most twice as large as the pixel it encodes (e.g. OP_GRAY for a single channel)
+pub(crate) const MAX_OPS_CHUNK_SIZE: usize = MAX_CHUNK_SIZE * 2;
+// worst case size of a lz4 compressed chunk
+pub(crate) const MAX_COMPRESSED_CHUNK_SIZE: usize = compress_bound(MAX_OPS_CHUNK_SIZE);
+
+// lz4 itself needs `len + len / 255 + 16` bytes, but lz4_flex requires an output buffer of 110% of
+// the input (plus 20 bytes) before it starts compressing
+#[inline]
+pub(crate) const fn compress_bound(len: usize) -> usize {
+    len + len / 10 + 24
+}
+
 // pub const OP_INDEX: u8 = 0x00;
 // pub const OP_INDEX_END: u8 = 0x3F;
 // pub const OP_DIFF: u8 = 0x40;
@@ -32,9 +45,6 @@ pub(crate) const OP_RGBA: u8 = 0xff;
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub(crate) enum Op {
-    Diff = OP_DIFF,
-    Luma = OP_LUMA,
-    DiffAlpha = OP_DIFF_ALPHA,
     Gray = OP_GRAY,
     GrayAlpha = OP_GRAY_ALPHA,
     Rgb = OP_RGB,
@@ -48,25 +58,6 @@ impl From<Op> for u8 {
     }
 }
 
-impl From<u8> for Op {
-    fn from(op: u8) -> Self {
-        match op {
-            // OP_INDEX..=OP_INDEX_END => Op::Index,
-            OP_DIFF..=OP_DIFF_END => Op::Diff,
-            OP_LUMA..=OP_LUMA_END => Op::Luma,
-            OP_DIFF_ALPHA..=OP_DIFF_ALPHA_END => Op::DiffAlpha,
-            OP_GRAY => Op::Gray,
-            OP_GRAY_ALPHA => Op::GrayAlpha,
-            OP_RGB => Op::Rgb,
-            OP_RGBA => Op::Rgba,
-            _ => {
-                cold();
-                panic!("Invalid opcode {}", op)
-            }
-        }
-    }
-}
-
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Pixel<const C: usize> {
     pub data: [u8; C],
@@ -84,19 +75,9 @@ impl<const C: usize> Default for Pixel<C> {
     }
 }
 
-impl<const C: usize, const C2: usize> From<[u8; C2]> for Pixel<C> {
-    fn from(data: [u8; C2]) -> Self {
-        let (r, g, b, a) = match C2 {
-            1 => (data[0], 0, 0, 255),
-            2 => (data[0], 0, 0, data[1]),
-            3 => (data[0], data[1], data[2], 255),
-            4 => (data[0], data[1], data[2], data[3]),
-            _ => unreachable!(),
-        };
-
-        Pixel {
-            data: [r, g, b, a][..C].try_into().unwrap(),
-        }
+impl<const C: usize> From<[u8; C]> for Pixel<C> {
+    fn from(data: [u8; C]) -> Self {
+        Pixel { data }
     }
 }
 
@@ -108,7 +89,28 @@ impl<const C: usize> From<&[u8]> for Pixel<C> {
     }
 }
 
+// Pixels are always handled as RGBA internally:
+// - gray images use the same value for r, g and b
+// - images without an alpha channel are always fully opaque
+// - channels after the fourth one are not part of the RGBA value
 impl<const C: usize> Pixel<C> {
+    #[inline]
+    pub fn from_rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        let mut px = Pixel::default();
+        match C {
+            3 => px.data[..3].copy_from_slice(&[r, g, b]),
+            2 => px.data[..2].copy_from_slice(&[r, a]),
+            1 => px.data[0] = r,
+            _ => px.data[..4].copy_from_slice(&[r, g, b, a]),
+        }
+        px
+    }
+
+    #[inline]
+    pub fn from_gray_alpha(gray: u8, a: u8) -> Self {
+        Self::from_rgba(gray, gray, gray, a)
+    }
+
     #[inline]
     pub fn rgb(&self) -> [u8; 3] {
         [self.r(), self.g(), self.b()]
@@ -122,48 +124,33 @@ impl<const C: usize> Pixel<C> {
     #[inline]
     pub fn g(&self) -> u8 {
         match C {
-            3 | 4 => self.data[1],
             1 | 2 => self.data[0],
-            _ => unreachable!(),
+            _ => self.data[1],
         }
     }
 
     #[inline]
     pub fn b(&self) -> u8 {
         match C {
-            3 | 4 => self.data[2],
             1 | 2 => self.data[0],
-            _ => unreachable!(),
+            _ => self.data[2],
         }
     }
 
     #[inline]
     pub fn a(&self) -> u8 {
         match C {
-            4 => self.data[3],
-            2 => self.data[1],
             1 | 3 => 0xff,
-            _ => unreachable!(),
-        }
-    }
-
-    #[inline]
-    pub fn from_grayscale(gray: u8) -> Self {
-        match C {
-            3 => [gray, gray, gray, 0xff].into(),
-            4 => [gray, gray, gray, 0xff].into(),
-            2 => [gray, 0xff].into(),
-            1 => [gray].into(),
-            _ => unreachable!(),
+            2 => self.data[1],
+            _ => self.data[3],
         }
     }
 
     #[inline]
     pub fn is_gray(&self) -> bool {
         match C {
-            4 | 3 => self.data[0] == self.data[1] && self.data[1] == self.data[2],
             2 | 1 => true,
-            _ => unreachable!(),
+            _ => self.data[0] == self.data[1] && self.data[1] == self.data[2],
         }
     }
 
@@ -191,16 +178,17 @@ impl<const C: usize> Pixel<C> {
     pub fn apply_alpha_diff(&self, b1: u8) -> Self {
         let diff = (b1 & !(OP_DIFF_ALPHA)).wrapping_sub(0x1e);
         let new_alpha = self.a().wrapping_add(diff);
-        [self.r(), self.g(), self.b(), new_alpha].into()
+        Self::from_rgba(self.r(), self.g(), self.b(), new_alpha)
     }
 
+    // `a` is the alpha value of the new pixel, OP_DIFF doesn't store it
     #[inline]
-    pub fn apply_diff(&self, b1: u8) -> Self {
+    pub fn apply_diff(&self, b1: u8, a: u8) -> Self {
         let r = self.r().wrapping_add(b1 >> 4 & 0x03).wrapping_sub(2);
         let g = self.g().wrapping_add(b1 >> 2 & 0x03).wrapping_sub(2);
         let b = self.b().wrapping_add(b1 & 0x03).wrapping_sub(2);
 
-        [r, g, b].into()
+        Self::from_rgba(r, g, b, a)
     }
 
     #[inline]
@@ -213,7 +201,7 @@ impl<const C: usize> Pixel<C> {
         let g = self.g().wrapping_add(vg);
         let b = self.b().wrapping_add(vb);
 
-        [r, g, b, self.a()].into()
+        Self::from_rgba(r, g, b, self.a())
     }
 }
 
@@ -261,13 +249,67 @@ impl TryFrom<u8> for Compression {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-#[repr(u8)]
+pub const MAX_CHANNELS: usize = 16;
+
+// The number of channels and their meaning. The first four channels are encoded using the regular
+// ops (as if they were RGBA), all further channels are stored as per-channel differences.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Channels {
-    Gray = 1,
-    GrayAlpha = 2,
-    Rgb = 3,
-    Rgba = 4,
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba,
+    Cmyk,
+    Cmyka,
+    // channels without a specific meaning, e.g. multispectral images (1..=MAX_CHANNELS)
+    Bands(u8),
+}
+
+impl Channels {
+    #[inline]
+    pub fn count(&self) -> usize {
+        match self {
+            Channels::Gray => 1,
+            Channels::GrayAlpha => 2,
+            Channels::Rgb => 3,
+            Channels::Rgba | Channels::Cmyk => 4,
+            Channels::Cmyka => 5,
+            Channels::Bands(n) => *n as usize,
+        }
+    }
+
+    // name of the channel semantics, stored in the file header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channels::Gray => "gray",
+            Channels::GrayAlpha => "gray_alpha",
+            Channels::Rgb => "rgb",
+            Channels::Rgba => "rgba",
+            Channels::Cmyk => "cmyk",
+            Channels::Cmyka => "cmyka",
+            Channels::Bands(_) => "bands",
+        }
+    }
+
+    // whether the last channel is an alpha channel
+    pub fn has_alpha(&self) -> bool {
+        matches!(self, Channels::GrayAlpha | Channels::Rgba | Channels::Cmyka)
+    }
+
+    pub fn from_name(name: &str, count: u8) -> Option<Self> {
+        let channels = match name {
+            "gray" => Channels::Gray,
+            "gray_alpha" => Channels::GrayAlpha,
+            "rgb" => Channels::Rgb,
+            "rgba" => Channels::Rgba,
+            "cmyk" => Channels::Cmyk,
+            "cmyka" => Channels::Cmyka,
+            "bands" if (1..=MAX_CHANNELS).contains(&(count as usize)) => Channels::Bands(count),
+            _ => return None,
+        };
+
+        (channels.count() == count as usize).then_some(channels)
+    }
 }
 
 impl TryFrom<u8> for Channels {
@@ -279,6 +321,7 @@ impl TryFrom<u8> for Channels {
             2 => Ok(Channels::GrayAlpha),
             3 => Ok(Channels::Rgb),
             4 => Ok(Channels::Rgba),
+            5..=16 => Ok(Channels::Bands(value)),
             _ => {
                 cold();
                 Err(())
@@ -287,8 +330,216 @@ impl TryFrom<u8> for Channels {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum AlphaMode {
+    // alpha is encoded together with the color channels
+    #[default]
+    Interleaved = 0,
+    // alpha is encoded as a separate plane after the color channels of every chunk (block layout only)
+    Plane = 1,
+}
+
+impl TryFrom<u8> for AlphaMode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AlphaMode::Interleaved),
+            1 => Ok(AlphaMode::Plane),
+            _ => Err(()),
+        }
+    }
+}
+
+// color filter array layout of raw sensor data, named after the colors of the top-left 2x2 block
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CfaPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+impl CfaPattern {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CfaPattern::Rggb => "rggb",
+            CfaPattern::Bggr => "bggr",
+            CfaPattern::Grbg => "grbg",
+            CfaPattern::Gbrg => "gbrg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rggb" => Some(CfaPattern::Rggb),
+            "bggr" => Some(CfaPattern::Bggr),
+            "grbg" => Some(CfaPattern::Grbg),
+            "gbrg" => Some(CfaPattern::Gbrg),
+            _ => None,
+        }
+    }
+
+    // whether the sample at (x, y) is green, the other samples alternate between red and blue
+    #[inline]
+    pub fn is_green(&self, x: usize, y: usize) -> bool {
+        let odd = (x + y) % 2 == 1;
+        match self {
+            CfaPattern::Rggb | CfaPattern::Bggr => odd,
+            CfaPattern::Grbg | CfaPattern::Gbrg => !odd,
+        }
+    }
+}
+
+// EXIF orientation, describes how the stored pixels have to be transformed for display
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Orientation {
+    #[default]
+    Normal = 1,
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    FlipVertical = 4,
+    // mirrored along the top-left to bottom-right diagonal
+    Transpose = 5,
+    // rotated 90 degrees clockwise for display
+    Rotate90 = 6,
+    // mirrored along the top-right to bottom-left diagonal
+    Transverse = 7,
+    // rotated 270 degrees clockwise for display
+    Rotate270 = 8,
+}
+
+impl Orientation {
+    // whether width and height are swapped for display
+    pub fn swaps_dimensions(&self) -> bool {
+        matches!(
+            self,
+            Orientation::Transpose
+                | Orientation::Rotate90
+                | Orientation::Transverse
+                | Orientation::Rotate270
+        )
+    }
+
+    // position of the stored pixel that is displayed at (x, y), width and height are the stored dimensions
+    #[inline]
+    pub fn source(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Orientation::Normal => (x, y),
+            Orientation::FlipHorizontal => (width - 1 - x, y),
+            Orientation::Rotate180 => (width - 1 - x, height - 1 - y),
+            Orientation::FlipVertical => (x, height - 1 - y),
+            Orientation::Transpose => (y, x),
+            Orientation::Rotate90 => (y, height - 1 - x),
+            Orientation::Transverse => (width - 1 - y, height - 1 - x),
+            Orientation::Rotate270 => (width - 1 - y, x),
+        }
+    }
+}
+
+impl TryFrom<u8> for Orientation {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Orientation::Normal),
+            2 => Ok(Orientation::FlipHorizontal),
+            3 => Ok(Orientation::Rotate180),
+            4 => Ok(Orientation::FlipVertical),
+            5 => Ok(Orientation::Transpose),
+            6 => Ok(Orientation::Rotate90),
+            7 => Ok(Orientation::Transverse),
+            8 => Ok(Orientation::Rotate270),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum Colorspace {
     Srgb,
 }
+
+// byte order of the pixels in the buffers the block encoder reads from and the block decoder
+// writes to (see BufferFormat)
+// - Rgba: the image's own channels in their own order, for any number of channels
+// - Bgra, Argb, Abgr: images with four channels, reordered
+// - Rgbx: images with three channels, padded to four bytes per pixel (the padding is ignored when
+//   encoding and set to 255 when decoding)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum PixelLayout {
+    #[default]
+    Rgba,
+    Bgra,
+    Argb,
+    Abgr,
+    Rgbx,
+}
+
+impl PixelLayout {
+    // whether images with `channels` channels can be stored in this layout
+    pub fn supports(&self, channels: usize) -> bool {
+        match self {
+            PixelLayout::Rgba => true,
+            PixelLayout::Bgra | PixelLayout::Argb | PixelLayout::Abgr => channels == 4,
+            PixelLayout::Rgbx => channels == 3,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self, channels: usize) -> usize {
+        match self {
+            PixelLayout::Rgba => channels,
+            _ => 4,
+        }
+    }
+
+    // position of every channel of the image within a pixel of the buffer
+    #[inline]
+    pub(crate) fn order(&self) -> [usize; 4] {
+        match self {
+            PixelLayout::Rgba | PixelLayout::Rgbx => [0, 1, 2, 3],
+            PixelLayout::Bgra => [2, 1, 0, 3],
+            PixelLayout::Argb => [1, 2, 3, 0],
+            PixelLayout::Abgr => [3, 2, 1, 0],
+        }
+    }
+}
+
+// how the pixels are arranged in the buffers passed to `encoder::block::encode_with_format` and
+// `decoder::block::decode_with_format`, the default is tightly packed pixels in the image's own
+// channel order
+// - alpha planes and CFA data are copied through a buffer of one chunk (plus two rows of history
+//   for CFA data) in other formats, interleaved pixels are read and written in place
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct BufferFormat {
+    pub layout: PixelLayout,
+    // bytes from the start of one row to the start of the next, rows are tightly packed if None
+    // - the bytes between rows are neither read nor written, so images can be encoded from and
+    //   decoded into a sub-rectangle of a larger buffer
+    pub row_stride: Option<usize>,
+    // rows are stored last row first (e.g. for OpenGL textures or BMP files)
+    // - with `FileHeader::rows_per_chunk` every chunk covers whole rows, which are then contiguous
+    //   in the buffer as well
+    pub bottom_up: bool,
+}
+
+impl BufferFormat {
+    // whether the buffer can be used as it is, without converting every pixel, `row_size` is the
+    // size of a tightly packed row
+    #[inline]
+    pub(crate) fn is_packed(&self, row_size: usize) -> bool {
+        self.layout == PixelLayout::Rgba
+            && self.row_stride.unwrap_or(row_size) == row_size
+            && !self.bottom_up
+    }
+
+    // size of the buffer holding an image in this format, None if it doesn't fit into usize
+    // - the last row ends after its last pixel, it doesn't need to be padded to the stride
+    pub fn buffer_size(&self, header: &FileHeader) -> Option<usize> {
+        let channels = header.channels.count();
+        Frame::new(self, header, channels).buffer_size()
+    }
+}
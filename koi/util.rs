@@ -1,4 +1,4 @@
-use std::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut};
 
 #[inline]
 #[cold]
@@ -27,22 +27,31 @@ impl<'a> Buffer<'a> {
         Self(buf)
     }
 
-    pub fn read_one(self) -> (u8, Self) {
-        (self.0[0], Self(&self.0[1..]))
+    // the read functions return None if there isn't enough data left
+
+    pub fn read_one(self) -> Option<(u8, Self)> {
+        let (first, rest) = self.0.split_first()?;
+        Some((*first, Self(rest)))
     }
 
-    pub fn read_many(self, n: usize) -> (&'a [u8], Self) {
-        (&self.0[..n], Self(&self.0[n..]))
+    pub fn read_many(self, n: usize) -> Option<(&'a [u8], Self)> {
+        if n > self.0.len() {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(n);
+        Some((head, Self(tail)))
     }
 
-    pub fn read_u32_le(self) -> (u32, Self) {
-        let (bytes, buf) = self.read_many(4);
-        let n = u32::from_le_bytes(bytes.try_into().expect("4 bytes"));
-        (n, buf)
+    pub fn read_u32_le(self) -> Option<(u32, Self)> {
+        let (bytes, buf) = self.read_many(4)?;
+        let n = u32::from_le_bytes(bytes.try_into().ok()?);
+        Some((n, buf))
     }
 
+    // skips at most n bytes
     pub fn advance(self, n: usize) -> Self {
-        Self(&self.0[n..])
+        Self(&self.0[n.min(self.0.len())..])
     }
 }
 
@@ -54,6 +63,8 @@ impl<'a> Deref for Buffer<'a> {
     }
 }
 
+// writing past the end is a bug: ops are written into buffers sized for the worst case, and
+// buffers passed in by users are checked before anything is written into them
 pub struct BufferMut<'a>(&'a mut [u8]);
 
 pub trait Writer: Sized {
@@ -110,6 +121,16 @@ impl<'a> BufferMut<'a> {
             unreachable!()
         }
     }
+
+    // splits off the next n bytes to be written directly
+    pub fn take(self, n: usize) -> (&'a mut [u8], Self) {
+        if n <= self.0.len() {
+            let (head, tail) = self.0.split_at_mut(n);
+            (head, Self(tail))
+        } else {
+            unreachable!()
+        }
+    }
 }
 
 impl<'a> Writer for BufferMut<'a> {
@@ -129,6 +150,28 @@ impl<'a> Writer for BufferMut<'a> {
     }
 }
 
+// writes an unsigned LEB128 varint
+pub(crate) fn write_varint<W: Writer>(mut value: usize, mut buf: W) -> W {
+    while value >= 0x80 {
+        buf = buf.write_one(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.write_one(value as u8)
+}
+
+// reads an unsigned LEB128 varint of at most 4 bytes, returns the value and the remaining data
+pub(crate) fn read_varint(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0;
+    for (i, byte) in data.iter().enumerate().take(4) {
+        value |= ((byte & 0x7f) as usize) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+
+    None
+}
+
 // impl<'a> Write for Buffer<'a> {
 //     #[inline]
 //     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
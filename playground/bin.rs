@@ -1,5 +1,6 @@
 mod fft;
 mod plot;
+#[allow(dead_code)]
 mod util;
 
 fn main() {
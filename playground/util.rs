@@ -49,9 +49,7 @@ pub fn ifft_channel(fft_output: &Array2<Complex<f32>>) -> Array2<u8> {
 
         for x in 0..width {
             // Normalize to the range 0..255
-            let pixel_value = ((input[x].re - min) / (max - min) * 255.0)
-                .max(0.0)
-                .min(255.0) as u8;
+            let pixel_value = ((input[x].re - min) / (max - min) * 255.0).clamp(0.0, 255.0) as u8;
             output[[y, x]] = pixel_value;
         }
     }